@@ -0,0 +1,101 @@
+//
+//  zip.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Compares writing a ZIP archive of 500 source files on a single thread with compressing them in parallel.
+//!
+//! ZIP entries are only compressed in parallel when rayon has more than one thread, so each case runs inside a thread
+//! pool of the right size. Run with `cargo bench --bench zip`.
+
+use bathpack::config::Config;
+use bathpack::file_map::{ExecuteOptions, FileMap, FileMapBuilder};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rayon::ThreadPoolBuilder;
+
+use std::fs;
+use std::path::Path;
+
+/// How many source files the archive is made of.
+const FILES: usize = 500;
+
+/// Write `FILES` Java-like source files of a few kilobytes each into `root`, and build a map that archives them.
+fn corpus(root: &Path) -> FileMap {
+    for i in 0..FILES {
+        let dir = root.join("src").join(format!("package{}", i % 10));
+        fs::create_dir_all(&dir).unwrap();
+
+        let body: String = (0..100)
+            .map(|line| format!("    int field{} = {} * {};\n", line, i, line))
+            .collect();
+        fs::write(
+            dir.join(format!("Class{}.java", i)),
+            format!("class Class{} {{\n{}}}\n", i, body),
+        )
+        .unwrap();
+    }
+
+    let config = Config::parse(
+        r#"
+        username = "bench"
+
+        [sources]
+        src = { path = "src", pattern = "**/*.java" }
+
+        [destination]
+        name = "bench-{username}"
+        archive = true
+
+        [destination.locations]
+        src = "."
+    "#,
+    )
+    .unwrap();
+
+    FileMapBuilder::from(config, root.to_path_buf())
+        .build()
+        .unwrap()
+}
+
+fn write_zip(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let file_map = corpus(dir.path());
+    let options = ExecuteOptions::default();
+
+    let mut group = c.benchmark_group(format!("zip_{}_files", FILES));
+    group.sample_size(20);
+
+    for &threads in &[1, 4] {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let name = if threads == 1 {
+            "sequential"
+        } else {
+            "parallel"
+        };
+
+        group.bench_function(name, |b| {
+            b.iter(|| pool.install(|| file_map.execute_into_archive(&options).unwrap()))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, write_zip);
+criterion_main!(benches);
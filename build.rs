@@ -0,0 +1,42 @@
+//
+//  build.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Records the git commit and the time of the build, for `bathpack version`.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    // Building from a source archive without a git checkout, or without git installed, is fine.
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    println!("cargo:rustc-env=BATHPACK_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BATHPACK_BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,443 @@
+//
+//  checksum.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Computing checksums of files, for manifests and integrity checks.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A hash algorithm used to compute checksums of files.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    /// SHA-256, the default.
+    #[default]
+    Sha256,
+    /// SHA-512.
+    Sha512,
+    /// BLAKE3.
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// The lowercase name of the algorithm, as used in configuration files and manifests.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Sha512 => "sha512",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Compute the checksum of the contents of the file at `path`, as a lowercase hexadecimal string.
+    pub fn hash_file<P>(self, path: P) -> io::Result<String>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = File::open(path)?;
+
+        let digest = match self {
+            ChecksumAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().to_vec()
+            }
+            ChecksumAlgorithm::Sha512 => {
+                let mut hasher = Sha512::new();
+                io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().to_vec()
+            }
+            ChecksumAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().as_bytes().to_vec()
+            }
+        };
+
+        Ok(to_hex(&digest))
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha512" => Ok(ChecksumAlgorithm::Sha512),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            _ => Err(format!(
+                "unknown checksum algorithm `{}` (expected sha256, sha512 or blake3)",
+                s
+            )),
+        }
+    }
+}
+
+/// A list of files and their checksums, as written to `MANIFEST.<algorithm>` by
+/// [`FileMap::write_manifest`][write_manifest].
+///
+/// A manifest starts with a comment naming the algorithm, `# bathpack manifest (sha256)`, followed by one line per file
+/// in the same format as `sha256sum`: the checksum, two spaces, and the file's path relative to the folder the manifest
+/// describes, with `/` as the separator.
+///
+/// [write_manifest]: ../file_map/struct.FileMap.html#method.write_manifest
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Manifest {
+    /// The algorithm the checksums were computed with.
+    pub algorithm: ChecksumAlgorithm,
+    /// Each file's checksum and path, in order.
+    pub entries: Vec<(String, String)>,
+}
+
+impl Manifest {
+    /// Read the manifest at `path`. If it has no comment naming the algorithm, the algorithm is taken from the file's
+    /// extension, e.g. `MANIFEST.sha512`, and is SHA-256 if that isn't the name of one either.
+    pub fn read<P>(path: P) -> Result<Manifest, ManifestError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        let mut manifest: Manifest = contents.parse()?;
+
+        if !contents.starts_with(MANIFEST_HEADER_START) {
+            if let Some(algorithm) = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| ext.parse().ok())
+            {
+                manifest.algorithm = algorithm;
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
+/// How the comment naming the algorithm at the top of a manifest starts.
+const MANIFEST_HEADER_START: &str = "# bathpack manifest (";
+
+impl FromStr for Manifest {
+    type Err = ManifestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut manifest = Manifest::default();
+
+        for (i, line) in s.lines().enumerate() {
+            if let Some(rest) = line.strip_prefix(MANIFEST_HEADER_START) {
+                let name = rest.trim_end_matches(')');
+                manifest.algorithm = name
+                    .parse()
+                    .map_err(|_| ManifestError::UnknownAlgorithm(name.to_string()))?;
+                continue;
+            }
+
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once("  ") {
+                Some((checksum, path)) if !checksum.is_empty() && !path.is_empty() => manifest
+                    .entries
+                    .push((checksum.to_string(), path.to_string())),
+                _ => return Err(ManifestError::InvalidLine { line: i + 1 }),
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}{})", MANIFEST_HEADER_START, self.algorithm)?;
+
+        for (checksum, path) in &self.entries {
+            writeln!(f, "{}  {}", checksum, path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Errors to do with reading a [`Manifest`][manifest].
+///
+/// [manifest]: ./struct.Manifest.html
+#[derive(Debug)]
+pub enum ManifestError {
+    /// Wraps a [`std::io::Error`][ioerr].
+    ///
+    /// [ioerr]: https://doc.rust-lang.org/std/io/struct.Error.html
+    Io(io::Error),
+    /// The comment at the top of the manifest names an algorithm that isn't supported.
+    UnknownAlgorithm(String),
+    /// This line, counting from 1, isn't a checksum and a path separated by two spaces.
+    InvalidLine { line: usize },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManifestError::Io(ref io_err) => write!(f, "{}", io_err),
+            ManifestError::UnknownAlgorithm(ref name) => {
+                write!(f, "Unknown checksum algorithm `{}` in manifest", name)
+            }
+            ManifestError::InvalidLine { line } => write!(
+                f,
+                "Line {} of the manifest is not a checksum and a path separated by two spaces",
+                line
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ManifestError::Io(ref io_err) => Some(io_err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ManifestError {
+    fn from(io_error: io::Error) -> Self {
+        ManifestError::Io(io_error)
+    }
+}
+
+/// Checks the files in a folder against a [`Manifest`][manifest] of their checksums.
+///
+/// [manifest]: ./struct.Manifest.html
+#[derive(Clone, Debug)]
+pub struct ManifestVerifier {
+    manifest: Manifest,
+    dest_dir: PathBuf,
+}
+
+impl ManifestVerifier {
+    /// Create a `ManifestVerifier` that checks the files listed in `manifest`, relative to `dest_dir`.
+    pub fn new(manifest: Manifest, dest_dir: &Path) -> ManifestVerifier {
+        ManifestVerifier {
+            manifest,
+            dest_dir: dest_dir.to_path_buf(),
+        }
+    }
+
+    /// Re-hash every file in the manifest, returning the result for each one in the manifest's order.
+    pub fn verify(&self) -> Vec<VerifyResult> {
+        self.manifest
+            .entries
+            .iter()
+            .map(|(expected, path)| {
+                let status = match self.manifest.algorithm.hash_file(self.dest_dir.join(path)) {
+                    Ok(ref actual) if actual.eq_ignore_ascii_case(expected) => VerifyStatus::Ok,
+                    Ok(_) => VerifyStatus::Corrupted,
+                    Err(_) => VerifyStatus::Missing,
+                };
+
+                VerifyResult {
+                    path: path.clone(),
+                    status,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The result of checking one file with a [`ManifestVerifier`][verifier].
+///
+/// [verifier]: ./struct.ManifestVerifier.html
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct VerifyResult {
+    /// The file's path, as written in the manifest.
+    pub path: String,
+    /// Whether the file matched its checksum.
+    pub status: VerifyStatus,
+}
+
+/// Whether a file matched its checksum in a manifest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum VerifyStatus {
+    /// The file's checksum matches.
+    Ok,
+    /// The file's checksum doesn't match.
+    Corrupted,
+    /// The file doesn't exist, or couldn't be read.
+    Missing,
+}
+
+impl fmt::Display for VerifyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            VerifyStatus::Ok => "OK",
+            VerifyStatus::Corrupted => "CORRUPTED",
+            VerifyStatus::Missing => "MISSING",
+        })
+    }
+}
+
+/// A reader that computes the SHA-256 digest of everything read through it, so that a file can be hashed while it is
+/// being copied rather than read twice.
+#[derive(Debug)]
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashingReader<R>
+where
+    R: Read,
+{
+    /// Wrap `inner`, hashing everything read from it.
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The SHA-256 digest of everything read so far.
+    pub fn finalize(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R> Read for HashingReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Format `bytes` as a lowercase hexadecimal string.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that each algorithm produces the well-known digest of "abc".
+    #[test]
+    fn hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        std::fs::write(&path, "abc").unwrap();
+
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.hash_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(ChecksumAlgorithm::Sha512
+            .hash_file(&path)
+            .unwrap()
+            .starts_with("ddaf35a193617abacc417349ae204131"));
+        assert_eq!(
+            ChecksumAlgorithm::Blake3.hash_file(&path).unwrap(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    /// Test that a `HashingReader` passes its input through unchanged and produces its SHA-256 digest.
+    #[test]
+    fn hashing_reader() {
+        let mut reader = HashingReader::new("abc".as_bytes());
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+
+        assert_eq!(contents, "abc");
+        assert_eq!(
+            to_hex(&reader.finalize()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    /// Test that a manifest round-trips through `FromStr` and `Display`, and that the verifier reports each file as OK,
+    /// corrupted or missing.
+    #[test]
+    fn verify_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/a.txt"), "abc").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "changed").unwrap();
+
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let text = format!(
+            "# bathpack manifest (sha256)\n{}  src/a.txt\n{}  b.txt\n{}  c.txt\n",
+            abc, abc, abc
+        );
+        let manifest: Manifest = text.parse().unwrap();
+        assert_eq!(manifest.algorithm, ChecksumAlgorithm::Sha256);
+        assert_eq!(manifest.to_string(), text);
+
+        let statuses: Vec<VerifyStatus> = ManifestVerifier::new(manifest, dir.path())
+            .verify()
+            .into_iter()
+            .map(|result| result.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                VerifyStatus::Ok,
+                VerifyStatus::Corrupted,
+                VerifyStatus::Missing
+            ]
+        );
+
+        assert!(matches!(
+            "not a manifest line".parse::<Manifest>(),
+            Err(ManifestError::InvalidLine { line: 1 })
+        ));
+    }
+
+    /// Test that algorithm names round-trip through `FromStr` and `Display`.
+    #[test]
+    fn parse_name() {
+        for algorithm in &[
+            ChecksumAlgorithm::Sha256,
+            ChecksumAlgorithm::Sha512,
+            ChecksumAlgorithm::Blake3,
+        ] {
+            assert_eq!(
+                algorithm.to_string().parse::<ChecksumAlgorithm>(),
+                Ok(*algorithm)
+            );
+        }
+
+        assert!("md5".parse::<ChecksumAlgorithm>().is_err());
+    }
+}
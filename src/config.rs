@@ -18,55 +18,644 @@
 
 //! Parsing and structure of `bathpack.toml` configuration file.
 
+pub mod validate;
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::file_map::{format_file_template, format_template, FileMapError};
+use crate::join_paths;
+
 use serde::{Deserialize, Serialize};
 
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
 
-/// Read and return the user's configuration file from the default location, printing an error and exiting on failure.
-pub fn read_config() -> Config {
-    let mut config_file = match std::env::current_dir() {
-        Ok(mut path) => {
-            path.push("bathpack.toml");
-            path
-        }
-        Err(e) => {
-            eprintln!("Could not access current directory: {}", e);
-            exit(1);
-        }
+/// The name of the configuration file.
+pub const CONFIG_FILE_NAME: &str = "bathpack.toml";
+
+/// Read and return the user's configuration file, along with the directory it is in, printing an error and exiting on
+/// failure. If `discover` is `true`, the configuration file is searched for in the current directory and then each of
+/// its parents in turn, like Git does; otherwise, it must be in the current directory.
+///
+/// Settings in `BATHPACK_*` environment variables take precedence over the file (see
+/// [`Config::with_env`][with_env]). If there is no configuration file, but `BATHPACK_DEST_NAME` is set, the config is
+/// read from the environment variables alone, rooted at the current directory.
+///
+/// [with_env]: ./struct.Config.html#method.with_env
+pub fn read_config(discover: bool) -> (Config, PathBuf) {
+    try_read_config(discover).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    })
+}
+
+/// Like [`read_config`][read_config], but returning an error instead of printing it and exiting: a
+/// [`FileMapError::Config`][config] if the configuration file can't be read, or a
+/// [`FileMapError::EnvConfig`][env] if the environment variables can't be used.
+///
+/// [read_config]: ./fn.read_config.html
+/// [config]: ../file_map/enum.FileMapError.html#variant.Config
+/// [env]: ../file_map/enum.FileMapError.html#variant.EnvConfig
+pub fn try_read_config(discover: bool) -> crate::file_map::Result<(Config, PathBuf)> {
+    let current_dir = std::env::current_dir().map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Could not access current directory: {}", e),
+        )
+    })?;
+
+    let config_file = if discover {
+        find_config(&current_dir).unwrap_or_else(|| join_paths(&current_dir, [CONFIG_FILE_NAME]))
+    } else {
+        join_paths(&current_dir, [CONFIG_FILE_NAME])
     };
 
-    match Config::parse_file(config_file) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Could not read bathpack.toml: {}", e);
-            exit(1);
-        }
+    if !config_file.is_file() && std::env::var_os("BATHPACK_DEST_NAME").is_some() {
+        let config = Config::from_env().map_err(|err| FileMapError::EnvConfig { err })?;
+        return Ok((config, current_dir));
+    }
+
+    let root_dir = config_file
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or(current_dir);
+
+    let config = Config::load_with_includes(&config_file).map_err(|err| FileMapError::Config {
+        path: config_file.clone(),
+        err,
+    })?;
+    let config = config
+        .with_env()
+        .map_err(|err| FileMapError::EnvConfig { err })?;
+
+    Ok((config, root_dir))
+}
+
+/// The places [`Config::from_default_locations`][from_default_locations] looks for a configuration file, in order.
+///
+/// [from_default_locations]: ./struct.Config.html#method.from_default_locations
+fn default_locations(current_dir: &Path, home_dir: Option<&Path>) -> Vec<PathBuf> {
+    let mut locations: Vec<PathBuf> = current_dir
+        .ancestors()
+        .map(|dir| join_paths(dir, [CONFIG_FILE_NAME]))
+        .collect();
+
+    if let Some(home_dir) = home_dir {
+        locations.push(join_paths(
+            home_dir,
+            [".config", "bathpack", CONFIG_FILE_NAME],
+        ));
     }
+
+    locations.push(join_paths(Path::new("/etc/bathpack"), [CONFIG_FILE_NAME]));
+
+    locations
+}
+
+/// The names of the built-in template variables, which can't be set in `[vars]`.
+const BUILTIN_VARS: &[&str] = &["username", "assignment"];
+
+/// Find the nearest configuration file, starting in `start_dir` and then trying each of its parents in turn.
+pub fn find_config(start_dir: &Path) -> Option<PathBuf> {
+    start_dir
+        .ancestors()
+        .map(|dir| join_paths(dir, [CONFIG_FILE_NAME]))
+        .find(|path| path.is_file())
 }
 
 /// Specifies source & destination locations for files, and user information.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Config {
-    /// The user's University of Bath username.
-    username: String,
+    /// The user's University of Bath username. If it is left out, the login name from the `USER` or `USERNAME`
+    /// environment variable is used instead, with a warning.
+    #[serde(default)]
+    pub(crate) username: String,
+    /// A human-readable name for the assignment being submitted, e.g. "Functional Programming Coursework 1".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) assignment_name: Option<String>,
     /// Key-value pairs, where the key is the name of the source, and the value is the location (file or folder).
-    sources: BTreeMap<String, Source>,
+    pub(crate) sources: BTreeMap<String, Source>,
     /// The destination for all files, including a list of locations.
-    destination: Destination,
+    pub(crate) destination: Destination,
+    /// Extra variables that can be substituted into `destination.name`, in addition to the built-in ones.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) vars: BTreeMap<String, String>,
+    /// Anything adjusted automatically while loading the config that the user should know about.
+    #[serde(skip)]
+    pub(crate) warnings: Vec<ConfigWarning>,
 }
 
 impl Config {
+    /// Create a config with the given username, sources and destination, and no assignment name or extra variables, so
+    /// that a config can be put together without any TOML.
+    pub fn new(
+        username: &str,
+        sources: BTreeMap<String, Source>,
+        destination: Destination,
+    ) -> Config {
+        Config {
+            username: username.to_string(),
+            assignment_name: None,
+            sources,
+            destination,
+            vars: BTreeMap::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// The user's username, which is empty if it hasn't been filled in from the environment yet.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The sources, by key.
+    pub fn sources(&self) -> &BTreeMap<String, Source> {
+        &self.sources
+    }
+
+    /// The sources, by key, to add, change or remove them in place.
+    pub fn sources_mut(&mut self) -> &mut BTreeMap<String, Source> {
+        &mut self.sources
+    }
+
+    /// The destination.
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    /// The destination, to change it in place.
+    pub fn destination_mut(&mut self) -> &mut Destination {
+        &mut self.destination
+    }
+
+    /// The name of the assignment being submitted, if it was given.
+    pub fn assignment_name(&self) -> Option<&str> {
+        self.assignment_name.as_deref()
+    }
+
+    /// The source with the key `key`, if there is one.
+    pub fn source_by_key(&self, key: &str) -> Option<&Source> {
+        self.sources.get(key)
+    }
+
+    /// The destination location with the key `key`, if there is one.
+    pub fn location_by_key(&self, key: &str) -> Option<&DestLoc> {
+        self.destination.locations.get(key)
+    }
+
+    /// Anything adjusted automatically while loading the config that the user should know about, such as a
+    /// [`ConfigWarning::UsernameFromEnv`][from_env] if `username` was left out.
+    ///
+    /// [from_env]: ./enum.ConfigWarning.html#variant.UsernameFromEnv
+    pub fn warnings(&self) -> &[ConfigWarning] {
+        &self.warnings
+    }
+
+    /// Combine two configs, with the values in `overlay` taking precedence over those in `base`:
+    ///
+    /// - `username` is taken from `overlay` unless it is empty, and `assignment_name` is taken from `overlay` if it is
+    ///   set there.
+    /// - `sources`, `destination.locations` and `vars` contain the entries from both, with `overlay`'s entry used when
+    ///   both have the same key.
+    /// - The rest of `destination` (`name`, `archive`, `overwrite`, `manifest` and `checksum_algorithm`) is taken from
+    ///   `overlay` as a whole, unless all of it has the default value, in which case it is taken from `base`.
+    /// - `destination.archive_path`, `destination.max_size_bytes`, `destination.post_copy`,
+    ///   `destination.compression_level`, `destination.archive_comment` and `destination.temp_dir` are taken from
+    ///   `overlay` if they are set there.
+    pub fn merge(base: Config, overlay: Config) -> Config {
+        let mut merged = base;
+
+        if !overlay.username.is_empty() {
+            merged.username = overlay.username;
+        }
+
+        if overlay.assignment_name.is_some() {
+            merged.assignment_name = overlay.assignment_name;
+        }
+
+        merged.sources.extend(overlay.sources);
+        merged.vars.extend(overlay.vars);
+        merged.warnings.extend(overlay.warnings);
+
+        let destination = overlay.destination;
+        let defaults = Destination::default();
+
+        let has_settings = destination.name != defaults.name
+            || destination.archive != defaults.archive
+            || destination.overwrite != defaults.overwrite
+            || destination.manifest != defaults.manifest
+            || destination.checksum_algorithm != defaults.checksum_algorithm;
+
+        if has_settings {
+            merged.destination.name = destination.name;
+            merged.destination.archive = destination.archive;
+            merged.destination.overwrite = destination.overwrite;
+            merged.destination.manifest = destination.manifest;
+            merged.destination.checksum_algorithm = destination.checksum_algorithm;
+        }
+
+        if destination.archive_path.is_some() {
+            merged.destination.archive_path = destination.archive_path;
+        }
+
+        if destination.max_size_bytes.is_some() {
+            merged.destination.max_size_bytes = destination.max_size_bytes;
+        }
+
+        if destination.post_copy.is_some() {
+            merged.destination.post_copy = destination.post_copy;
+        }
+
+        if destination.compression_level.is_some() {
+            merged.destination.compression_level = destination.compression_level;
+        }
+
+        if destination.archive_comment.is_some() {
+            merged.destination.archive_comment = destination.archive_comment;
+        }
+
+        if destination.temp_dir.is_some() {
+            merged.destination.temp_dir = destination.temp_dir;
+        }
+
+        merged.destination.locations.extend(destination.locations);
+
+        merged
+    }
+
+    /// Return a copy of this config with the environment variables and template variables substituted into
+    /// `destination.name`, `destination.archive_path` and `destination.archive_comment`, as they will be when the files
+    /// are packaged.
+    pub fn resolve(&self) -> crate::file_map::Result<Config> {
+        let mut config = self.clone();
+        config.destination.name = format_template(self, &self.destination.name)?;
+
+        if let Some(ref archive_path) = self.destination.archive_path {
+            config.destination.archive_path = Some(format_template(self, archive_path)?);
+        }
+
+        if let Some(ref archive_comment) = self.destination.archive_comment {
+            config.destination.archive_comment = Some(format_template(self, archive_comment)?);
+        }
+
+        Ok(config)
+    }
+
+    /// Return a copy of this config with redundant components removed from every path in `sources` and
+    /// `destination.locations`, so that e.g. `./src/../src//main.rs` becomes `src/main.rs`. This works on the strings
+    /// alone, without looking at the filesystem, so `..` is collapsed even if the folder before it is a symlink.
+    pub fn normalize(&self) -> Config {
+        let mut config = self.clone();
+
+        for source in config.sources.values_mut() {
+            match source {
+                Source::Folder { path, .. }
+                | Source::RecursiveFolder { path, .. }
+                | Source::Glob(path)
+                | Source::TextFile { file: path, .. }
+                | Source::File(path) => {
+                    *path = normalize_path(path);
+                }
+                Source::Url { .. } => {}
+            }
+        }
+
+        for location in config.destination.locations.values_mut() {
+            match location {
+                DestLoc::Folder(path) | DestLoc::File(path) | DestLoc::Template(path) => {
+                    *path = normalize_path(path)
+                }
+                DestLoc::Multiple(paths) => {
+                    for path in paths {
+                        *path = normalize_path(path);
+                    }
+                }
+            }
+        }
+
+        config
+    }
+
+    /// Return a copy of this config in which every relative path in `sources` and `destination.locations` has `base`
+    /// put in front of it, e.g. so that a config from a project folder can be combined with one from the folder above.
+    /// Absolute paths are left as they are. Fails with [`Error::PathTraversal`][traversal] if a path would lead
+    /// outside `base` through `..` components.
+    ///
+    /// [traversal]: ./enum.Error.html#variant.PathTraversal
+    pub fn all_paths_relative_to(&self, base: &Path) -> Result<Config> {
+        let mut config = self.clone();
+
+        for source in config.sources.values_mut() {
+            match source {
+                Source::Folder {
+                    path, mtime_from, ..
+                } => {
+                    *path = rebase_path(base, path)?;
+
+                    if let Some(file) = mtime_from {
+                        *file = rebase_path(base, file)?;
+                    }
+                }
+                Source::RecursiveFolder { path, .. }
+                | Source::TextFile { file: path, .. }
+                | Source::File(path) => {
+                    *path = rebase_path(base, path)?;
+                }
+                Source::Glob(pattern) => {
+                    // The base is taken literally, so any wildcard characters in it need escaping.
+                    let escaped = glob::Pattern::escape(&base.to_string_lossy());
+                    *pattern = rebase_path(Path::new(&escaped), pattern)?;
+                }
+                Source::Url { .. } => {}
+            }
+        }
+
+        for location in config.destination.locations.values_mut() {
+            match location {
+                DestLoc::Folder(path) | DestLoc::File(path) | DestLoc::Template(path) => {
+                    *path = rebase_path(base, path)?
+                }
+                DestLoc::Multiple(paths) => {
+                    for path in paths {
+                        *path = rebase_path(base, path)?;
+                    }
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Return a copy of this config with `username` set to `username`.
+    pub fn with_username(&self, username: &str) -> Config {
+        let mut config = self.clone();
+        config.username = username.to_string();
+        config
+    }
+
+    /// Return a copy of this config with `assignment_name` set to `assignment_name`.
+    pub fn with_assignment_name(&self, assignment_name: &str) -> Config {
+        let mut config = self.clone();
+        config.assignment_name = Some(assignment_name.to_string());
+        config
+    }
+
+    /// Return a copy of this config with the template variable `name` set to `value` in `[vars]`. Fails with
+    /// [`Error::ReservedVarName`][reserved] if `name` is a built-in variable, such as `username`, which `[vars]` can't
+    /// override, or with [`Error::InvalidVarName`][invalid] if it can't be used in a template: names must be non-empty
+    /// and made of letters, digits, `_` and `-`.
+    ///
+    /// [reserved]: ./enum.Error.html#variant.ReservedVarName
+    /// [invalid]: ./enum.Error.html#variant.InvalidVarName
+    pub fn set_var(&self, name: &str, value: &str) -> Result<Config> {
+        if BUILTIN_VARS.contains(&name) {
+            return Err(Error::ReservedVarName {
+                name: name.to_string(),
+            });
+        }
+
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+
+        if !valid {
+            return Err(Error::InvalidVarName {
+                name: name.to_string(),
+            });
+        }
+
+        let mut config = self.clone();
+        config.vars.insert(name.to_string(), value.to_string());
+        Ok(config)
+    }
+
+    /// Return a copy of this config with `destination.name` set to `name`.
+    pub fn with_dest_name(&self, name: &str) -> Config {
+        let mut config = self.clone();
+        config.destination.name = name.to_string();
+        config
+    }
+
+    /// Return a copy of this config with archiving turned on or off. Turning it on keeps the archive format if one is
+    /// already set, and uses ZIP otherwise.
+    pub fn with_archive(&self, enabled: bool) -> Config {
+        let mode = match (enabled, self.destination.archive) {
+            (false, _) => ArchiveMode::None,
+            (true, ArchiveMode::None) => ArchiveMode::Zip,
+            (true, mode) => mode,
+        };
+
+        self.with_archive_format(mode)
+    }
+
+    /// Return a copy of this config with `destination.archive` set to `mode`.
+    pub fn with_archive_format(&self, mode: ArchiveMode) -> Config {
+        let mut config = self.clone();
+        config.destination.archive = mode;
+        config
+    }
+
+    /// The folders that sources are read from, relative to the root directory, without duplicates: each folder
+    /// source's folder, the folder containing each file source, and the folders of each glob source before the first
+    /// wildcard. URL sources aren't read from a folder.
+    pub fn source_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+
+        for source in self.sources.values() {
+            let dir = match source {
+                Source::Folder { path, .. } | Source::RecursiveFolder { path, .. } => {
+                    PathBuf::from(path)
+                }
+                Source::TextFile { file, .. } | Source::File(file) => Path::new(file)
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default(),
+                Source::Glob(pattern) => glob_base(pattern),
+                Source::Url { .. } => continue,
+            };
+            let dir = if dir.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                dir
+            };
+
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+
+        dirs
+    }
+
+    /// Estimate how many files the sources will match without looking at the filesystem, e.g. to show progress before
+    /// the file map is built. File and URL sources are one file each, while folder sources are only counted, since
+    /// they can't be expanded without reading the folder.
+    pub fn estimate_source_file_count(&self) -> SourceCountEstimate {
+        let mut estimate = SourceCountEstimate::default();
+
+        for source in self.sources.values() {
+            match source {
+                Source::File(_) | Source::TextFile { .. } | Source::Url { .. } => {
+                    estimate.exact_files += 1
+                }
+                Source::Folder { .. } | Source::RecursiveFolder { .. } | Source::Glob(_) => {
+                    estimate.folders_needing_expansion += 1
+                }
+            }
+        }
+
+        estimate
+    }
+
+    /// Find pairs of folder sources `(a, b)` where `b`'s folder is inside `a`'s, so that files in `b` may be included
+    /// twice. Paths are compared after [normalizing][normalize] them, without looking at the filesystem. If both
+    /// sources have the same folder, the pair is only returned once, with the keys in order.
+    ///
+    /// [normalize]: #method.normalize
+    pub fn source_path_prefix_conflicts(&self) -> Vec<(String, String)> {
+        let folders: Vec<(&String, PathBuf)> = self
+            .sources
+            .iter()
+            .filter_map(|(key, source)| match source {
+                Source::Folder { path, .. } | Source::RecursiveFolder { path, .. } => {
+                    Some((key, PathBuf::from(normalize_path(path))))
+                }
+                _ => None,
+            })
+            .map(|(key, path)| {
+                // `.` is a prefix of everything, but `Path::starts_with` doesn't know that.
+                (
+                    key,
+                    path.strip_prefix(".")
+                        .map(Path::to_path_buf)
+                        .unwrap_or(path),
+                )
+            })
+            .collect();
+
+        let mut conflicts = Vec::new();
+
+        for (a, a_path) in &folders {
+            for (b, b_path) in &folders {
+                if a == b || !b_path.starts_with(a_path) || (a_path == b_path && a > b) {
+                    continue;
+                }
+
+                conflicts.push((a.to_string(), b.to_string()));
+            }
+        }
+
+        conflicts
+    }
+
+    /// Return a copy of this config in which every source without a destination location is given a folder named after
+    /// its key, e.g. `code/` for the source `code`. A [`ConfigWarning::AutoFilledLocation`][autofilled] is returned
+    /// alongside it for each one, so that the user can be told to add it to the config.
+    ///
+    /// [autofilled]: ./enum.ConfigWarning.html#variant.AutoFilledLocation
+    pub fn auto_fill_locations(&self) -> (Config, Vec<ConfigWarning>) {
+        let mut config = self.clone();
+        let mut warnings = Vec::new();
+
+        for key in self.sources.keys() {
+            if !config.destination.locations.contains_key(key) {
+                let location = format!("{}/", key);
+                warnings.push(ConfigWarning::AutoFilledLocation {
+                    key: key.clone(),
+                    location: location.clone(),
+                });
+
+                config
+                    .destination
+                    .locations
+                    .insert(key.clone(), DestLoc::Folder(location));
+            }
+        }
+
+        (config, warnings)
+    }
+
+    /// Iterate, in order, over the keys that appear in both `sources` and `destination.locations`.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.sources
+            .keys()
+            .filter(move |key| self.destination.locations.contains_key(*key))
+            .map(String::as_str)
+    }
+
+    /// Iterate over every variable available for substitution into templates such as `destination.name`, as
+    /// `(name, value)` pairs. The built-in variables (`username`, and `assignment` if `assignment_name` is set) come
+    /// first, followed by the entries in `[vars]`. Entries in `[vars]` with the same name as a built-in variable are
+    /// skipped, since built-in variables take precedence.
+    pub fn iter_template_vars(&self) -> impl Iterator<Item = (&str, &str)> {
+        let mut builtin = vec![("username", self.username.as_str())];
+
+        if let Some(ref assignment_name) = self.assignment_name {
+            builtin.push(("assignment", assignment_name.as_str()));
+        }
+
+        let builtin_names: Vec<&str> = builtin.iter().map(|(name, _)| *name).collect();
+
+        let vars = self
+            .vars
+            .iter()
+            .filter(move |(name, _)| !builtin_names.contains(&name.as_str()))
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+
+        builtin.into_iter().chain(vars)
+    }
+
     /// Attempt to parse a `Config` from a string containing some TOML data.
     pub fn parse<T>(toml_str: T) -> Result<Config>
     where
         T: AsRef<str>,
     {
-        toml::from_str(toml_str.as_ref()).map_err(|e| e.into())
+        let config: Config = toml::from_str(toml_str.as_ref())?;
+        let config = config.with_username_fallback(|name| std::env::var(name).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// If `username` was left out, fill it in with the first non-empty value `lookup` gives for `USER` (set on Unix)
+    /// or `USERNAME` (set on Windows), adding a [`ConfigWarning::UsernameFromEnv`][from_env] to the
+    /// [`warnings`][warnings]. It is an error if neither has a value.
+    ///
+    /// [from_env]: ./enum.ConfigWarning.html#variant.UsernameFromEnv
+    /// [warnings]: #method.warnings
+    fn with_username_fallback<F>(mut self, lookup: F) -> Result<Config>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if !self.username.is_empty() {
+            return Ok(self);
+        }
+
+        let username = ["USER", "USERNAME"]
+            .iter()
+            .filter_map(|name| lookup(name))
+            .find(|value| !value.is_empty())
+            .ok_or(Error::MissingUsername)?;
+
+        self.warnings.push(ConfigWarning::UsernameFromEnv {
+            username: username.clone(),
+        });
+        self.username = username;
+
+        Ok(self)
+    }
+
+    /// Write this config out as TOML, in the same format it is read in, so that it can be saved as a `bathpack.toml`.
+    pub fn to_toml(&self) -> Result<String> {
+        // Going through a `toml::Value` puts single-file sources before folder sources, since TOML can't have plain
+        // values after tables.
+        let value = toml::Value::try_from(self)?;
+        Ok(toml::to_string_pretty(&value)?)
     }
 
     /// Attempt to parse a `Config` from a file containing TOML data at the location `path`.
@@ -81,262 +670,2564 @@ impl Config {
 
         Config::parse(contents)
     }
+
+    /// Attempt to parse a `Config` from the file at `path`, first resolving any other files it includes.
+    ///
+    /// The top-level `include` key may be a path or an array of paths, relative to the including file. Included files
+    /// are merged in order, each one overriding values from the ones before it, and the including file's own values
+    /// override them all. Included files may include further files, as long as no file ends up including itself.
+    pub fn load_with_includes<P>(path: P) -> Result<Config>
+    where
+        P: AsRef<Path>,
+    {
+        let value = load_value(path.as_ref(), &mut Vec::new())?;
+        let config: Config = value.try_into()?;
+        let config = config.with_username_fallback(|name| std::env::var(name).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Find and load the nearest configuration file, returning it along with its path. Like `cargo` and `rustfmt`, this
+    /// searches the current directory and each of its parents in turn, then `$HOME/.config/bathpack/bathpack.toml`,
+    /// and finally `/etc/bathpack/bathpack.toml`. If none of them exist, the error lists every path that was searched.
+    pub fn from_default_locations() -> Result<(Config, PathBuf)> {
+        let current_dir = std::env::current_dir()?;
+        let home_dir = std::env::var_os("HOME").map(PathBuf::from);
+
+        Config::from_first_of(default_locations(&current_dir, home_dir.as_deref()))
+    }
+
+    /// Load the first of `candidates` that is a file, returning it along with its path.
+    fn from_first_of(candidates: Vec<PathBuf>) -> Result<(Config, PathBuf)> {
+        match candidates.iter().find(|path| path.is_file()) {
+            Some(path) => Ok((Config::load_with_includes(path)?, path.clone())),
+            None => Err(Error::NotFound {
+                searched: candidates,
+            }),
+        }
+    }
+
+    /// Read a config from `BATHPACK_*` environment variables alone, for when writing a config file isn't practical,
+    /// e.g. in CI. See [`with_env`][with_env] for the variables that are read. `BATHPACK_DEST_NAME` must be set, since
+    /// the files would otherwise be copied straight into the root directory.
+    ///
+    /// [with_env]: #method.with_env
+    pub fn from_env() -> Result<Config> {
+        Config::default().with_vars(std::env::vars())
+    }
+
+    /// Return a copy of this config with the settings from these environment variables taking precedence:
+    ///
+    /// - `BATHPACK_USERNAME` sets `username`.
+    /// - `BATHPACK_DEST_NAME` sets `destination.name`.
+    /// - `BATHPACK_DEST_ARCHIVE` sets `destination.archive` to `false`, `true`, `zip` or `tar.gz`.
+    /// - `BATHPACK_SOURCE_<KEY>` adds the source `<key>` (lowercased). The value is either the path of a file, or a
+    ///   source written as a JSON object, e.g. `{"path": "src", "pattern": "*.java"}`.
+    /// - `BATHPACK_LOCATION_<KEY>` adds the destination location for the source `<key>` (lowercased). The value is
+    ///   either a folder, or a location written as JSON, e.g. `{"file": "report.pdf"}` or `["marking", "autograder"]`.
+    pub fn with_env(&self) -> Result<Config> {
+        self.with_vars(std::env::vars())
+    }
+
+    /// Like [`with_env`][with_env], but reading the variables from `vars` instead of the environment.
+    ///
+    /// [with_env]: #method.with_env
+    fn with_vars<I>(&self, vars: I) -> Result<Config>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut config = self.clone();
+
+        for (name, value) in vars {
+            let invalid = |reason: String| Error::InvalidEnvVar {
+                name: name.clone(),
+                reason,
+            };
+
+            if name == "BATHPACK_USERNAME" {
+                config.username = value;
+            } else if name == "BATHPACK_DEST_NAME" {
+                config.destination.name = value;
+            } else if name == "BATHPACK_DEST_ARCHIVE" {
+                let repr = match value.as_str() {
+                    "true" => ArchiveModeRepr::Enabled(true),
+                    "false" => ArchiveModeRepr::Enabled(false),
+                    _ => ArchiveModeRepr::Format(value),
+                };
+                config.destination.archive = ArchiveMode::try_from(repr).map_err(invalid)?;
+            } else if let Some(key) = name.strip_prefix("BATHPACK_SOURCE_") {
+                let source = if value.trim_start().starts_with('{') {
+                    serde_json::from_str(&value).map_err(|e| invalid(e.to_string()))?
+                } else {
+                    Source::File(value)
+                };
+                config.sources.insert(key.to_lowercase(), source);
+            } else if let Some(key) = name.strip_prefix("BATHPACK_LOCATION_") {
+                let location = if value.trim_start().starts_with(['{', '[']) {
+                    serde_json::from_str(&value).map_err(|e| invalid(e.to_string()))?
+                } else {
+                    DestLoc::Folder(value)
+                };
+                config
+                    .destination
+                    .locations
+                    .insert(key.to_lowercase(), location);
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the rules that can't be expressed in the structure of the config. This is done automatically when a
+    /// config is parsed, but should be done again after changing it, e.g. with `extend`.
+    pub fn validate(&self) -> Result<()> {
+        if normalize_path(&self.destination.name) == "." {
+            return Err(Error::DestNameIsRoot {
+                name: self.destination.name.clone(),
+            });
+        }
+
+        for (key, source) in &self.sources {
+            let is_folder = matches!(
+                source,
+                Source::Folder { .. } | Source::RecursiveFolder { .. } | Source::Glob(_)
+            );
+
+            if is_folder && matches!(self.location_by_key(key), Some(DestLoc::File(_))) {
+                return Err(Error::FileLocationForFolder { key: key.clone() });
+            }
+
+            if let Some(encoding) = source.encoding() {
+                if !is_utf8_name(encoding) {
+                    return Err(Error::UnsupportedEncoding {
+                        key: key.clone(),
+                        encoding: encoding.to_string(),
+                    });
+                }
+            }
+        }
+
+        if let Some(level) = self.destination.compression_level {
+            if level > 9 {
+                return Err(Error::InvalidCompressionLevel { value: level });
+            }
+        }
+
+        for (key, location) in &self.destination.locations {
+            if let DestLoc::Template(template) = location {
+                if let Err(err) = format_file_template(template, Path::new("example.txt")) {
+                    return Err(Error::InvalidLocationTemplate {
+                        key: key.clone(),
+                        reason: err.to_string(),
+                    });
+                }
+            }
+
+            if let DestLoc::Multiple(paths) = location {
+                let outside = paths.iter().find(|path| {
+                    Path::new(path).is_absolute()
+                        || Path::new(&normalize_path(path)).starts_with("..")
+                });
+
+                if let Some(path) = outside {
+                    return Err(Error::LocationOutsideDestination {
+                        key: key.clone(),
+                        path: path.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
-/// A source location - either a folder or a file.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum Source {
-    /// A folder, interpreted as all files in that folder matching the given glob pattern. The folder location is
-    /// represented as a relative path to the folder in a string.
-    Folder { path: String, pattern: String },
-    /// A file, stored as a relative path in a string.
-    File(String),
+impl Extend<(String, Source)> for Config {
+    /// Add each `(key, source)` pair to `sources`, replacing any existing source with the same key.
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (String, Source)>,
+    {
+        self.sources.extend(iter);
+    }
+}
+
+impl Extend<(String, DestLoc)> for Config {
+    /// Add each `(key, location)` pair to `destination.locations`, replacing any existing location with the same key.
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (String, DestLoc)>,
+    {
+        self.destination.locations.extend(iter);
+    }
+}
+
+/// Remove `.` components, empty components and `..` components that follow a normal component from `path`. A path
+/// that normalizes to nothing becomes `.`.
+fn normalize_path(path: &str) -> String {
+    let mut normalized = PathBuf::new();
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            _ => normalized.push(component),
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        normalized.to_string_lossy().into_owned()
+    }
+}
+
+/// `path` with `base` in front of it, or `path` itself if it is absolute. Fails if `path` leads outside `base`.
+fn rebase_path(base: &Path, path: &str) -> Result<String> {
+    if Path::new(path).is_absolute() {
+        return Ok(path.to_string());
+    }
+
+    if Path::new(&normalize_path(path)).starts_with("..") {
+        return Err(Error::PathTraversal {
+            path: path.to_string(),
+        });
+    }
+
+    Ok(base.join(path).to_string_lossy().into_owned())
+}
+
+/// Read the TOML file at `path` as a `toml::Value`, merged on top of the files it includes. `stack` contains the
+/// canonical paths of the files currently being loaded, and is used to detect include cycles.
+fn load_value(path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Value> {
+    let canonical = path.canonicalize()?;
+
+    if stack.contains(&canonical) {
+        return Err(Error::IncludeCycle(canonical));
+    }
+
+    let mut contents = String::new();
+    File::open(&canonical)?.read_to_string(&mut contents)?;
+    let mut value: toml::Value = contents.parse()?;
+
+    let includes = match value
+        .as_table_mut()
+        .and_then(|table| table.remove("include"))
+    {
+        None => Vec::new(),
+        Some(toml::Value::String(include)) => vec![include],
+        Some(toml::Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                toml::Value::String(include) => Ok(include),
+                _ => Err(Error::InvalidInclude(canonical.clone())),
+            })
+            .collect::<Result<_>>()?,
+        Some(_) => return Err(Error::InvalidInclude(canonical)),
+    };
+
+    if includes.is_empty() {
+        return Ok(value);
+    }
+
+    let dir = canonical
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    stack.push(canonical);
+
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    for include in includes {
+        merge_values(&mut merged, load_value(&dir.join(include), stack)?, &[]);
+    }
+    merge_values(&mut merged, value, &[]);
+
+    stack.pop();
+
+    Ok(merged)
+}
+
+/// Merge `overlay` into `base`, where `path` is the keys leading to them from the top of the file. Tables are merged
+/// key by key, and any other value in `overlay` replaces the one in `base`. As in [`Config::merge`][merge], entries in
+/// `sources` and `destination.locations` are replaced as a whole, so that a source or location is never made of a mix
+/// of both files' settings.
+///
+/// [merge]: ./struct.Config.html#method.merge
+fn merge_values(base: &mut toml::Value, overlay: toml::Value, path: &[&str]) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            let whole_entries = matches!(path, ["sources"] | ["destination", "locations"]);
+
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) if !whole_entries => {
+                        let mut nested = path.to_vec();
+                        nested.push(&key);
+                        merge_values(existing, value, &nested);
+                    }
+                    _ => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
 }
 
-/// The final destination of a Bathpack run, including the name and a list of destination locations.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct Destination {
-    /// The name of the final folder/archive.
-    name: String,
-    /// Whether to archive the folder.
-    archive: bool,
-    /// Key-value pairs, where each key is the name of a source in a [`Config`][config], and each value is the location
-    /// to move that source to.
-    ///
-    /// [config]: ./struct.Config.html
-    locations: BTreeMap<String, DestLoc>,
-}
+/// The leading folders of the glob `pattern` that don't contain any wildcards, e.g. `src/main` for
+/// `src/main/**/*.java`. A pattern that starts with a wildcard gives `.`.
+fn glob_base(pattern: &str) -> PathBuf {
+    let base: PathBuf = Path::new(pattern)
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(&['*', '?', '['][..])
+        })
+        .collect();
+
+    if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    }
+}
+
+/// (De)serializes the pattern of a [`Source::Glob`][glob] as a table with a single `glob` key, so that it can't be
+/// mistaken for a file source.
+///
+/// [glob]: ./enum.Source.html#variant.Glob
+mod glob_source {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct GlobRef<'a> {
+        glob: &'a str,
+    }
+
+    #[derive(Deserialize)]
+    struct Glob {
+        glob: String,
+    }
+
+    pub fn serialize<S>(pattern: &str, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        GlobRef { glob: pattern }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<String, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Glob::deserialize(deserializer).map(|glob| glob.glob)
+    }
+}
+
+/// The default for boolean settings that are on unless turned off.
+fn default_true() -> bool {
+    true
+}
+
+/// Whether `value` is `true`, so that settings at their default of `true` can be left out when serializing.
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+/// A source location - either a folder, a file, or a file to download. Paths are relative to the root directory, unless
+/// they are absolute, in which case they are used as they are.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Source {
+    /// A folder, interpreted as all files in that folder matching the given glob pattern. The folder location is
+    /// represented as a relative path to the folder in a string. If `max_path_length` is given, matched files whose
+    /// path relative to the root directory is longer than that many characters are skipped, since some ZIP tools reject
+    /// long paths. If `rename_pattern` is given, the first match of that regular expression in each matched file's name
+    /// is replaced with `rename_replacement` (or removed, if there is no replacement), which may refer to capture
+    /// groups as `$1` or `${name}`. Matches inside a subfolder whose name is in `exclude_dirs` are skipped. A pattern
+    /// that matches nothing is an error unless `allow_empty` is set. Matches that are symlinks are skipped if
+    /// `follow_symlinks` is turned off; otherwise the file they point to is copied in their place. If `sort` is given,
+    /// the matches are put in that order, which is the order they are copied and archived in. If `mtime_from` is given,
+    /// every match is archived with the modification time of that file, relative to the root directory, instead of its
+    /// own, so that archives can be reproduced. If `deduplicate` is set, matches with the same contents as a file from
+    /// a source with an earlier key, or an earlier match in this source, are left out. If `encoding` is `"utf-8"`, a
+    /// warning is given for each match that isn't valid UTF-8.
+    Folder {
+        path: String,
+        pattern: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_path_length: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rename_pattern: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rename_replacement: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        exclude_dirs: Vec<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        allow_empty: bool,
+        #[serde(default = "default_true", skip_serializing_if = "is_true")]
+        follow_symlinks: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sort: Option<SortOrder>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime_from: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        deduplicate: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<String>,
+    },
+    /// A folder, interpreted as every file inside it or any of its subfolders. The folders themselves are not included.
+    /// Subfolders whose name is in `exclude_dirs` are skipped without being looked inside.
+    RecursiveFolder {
+        path: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        exclude_dirs: Vec<String>,
+    },
+    /// A file downloaded over HTTP or HTTPS when the file map is built. If `sha256` is given, the downloaded file's
+    /// SHA-256 checksum must match it.
+    Url {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+    },
+    /// All files matching a glob pattern relative to the root directory, written as `{ glob = "src/**/*.rs" }`. The
+    /// matches keep their path relative to the root directory when they are copied.
+    #[serde(with = "glob_source")]
+    Glob(String),
+    /// A file written as a table, `{ file = "report.md", encoding = "utf-8" }`, so that it can be checked: if
+    /// `encoding` is `"utf-8"`, a warning is given if the file isn't valid UTF-8.
+    TextFile {
+        file: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<String>,
+    },
+    /// A file, stored as a relative path in a string.
+    File(String),
+}
+
+impl Source {
+    /// The encoding the source's files are expected to be in, if it was given.
+    pub fn encoding(&self) -> Option<&str> {
+        match self {
+            Source::Folder { encoding, .. } | Source::TextFile { encoding, .. } => {
+                encoding.as_deref()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the source's files should be checked to be valid UTF-8.
+    pub fn requires_utf8(&self) -> bool {
+        self.encoding().is_some_and(is_utf8_name)
+    }
+}
+
+/// Whether `encoding` names UTF-8, the only encoding that source files can be checked against.
+fn is_utf8_name(encoding: &str) -> bool {
+    encoding.eq_ignore_ascii_case("utf-8") || encoding.eq_ignore_ascii_case("utf8")
+}
+
+/// The order to put the files matched by a folder source in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Alphabetically by path.
+    Name,
+    /// By modification time, newest first.
+    Modified,
+    /// By size, largest first.
+    Size,
+}
+
+/// An estimate of the number of files matched by a config's sources, from
+/// [`Config::estimate_source_file_count`][estimate]: exactly `exact_files` files, plus however many are matched by
+/// `folders_needing_expansion` folder sources.
+///
+/// [estimate]: ./struct.Config.html#method.estimate_source_file_count
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SourceCountEstimate {
+    /// The number of sources that are a single file.
+    pub exact_files: usize,
+    /// The number of sources that are a folder, whose files have to be found on the filesystem.
+    pub folders_needing_expansion: usize,
+}
+
+/// The final destination of a Bathpack run, including the name and a list of destination locations.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Destination {
+    /// The name of the final folder/archive.
+    pub(crate) name: String,
+    /// Whether to archive the folder, and in which format.
+    pub(crate) archive: ArchiveMode,
+    /// Where to write the archive, relative to the root directory, instead of alongside the folder. Template variables
+    /// are substituted in the same way as for `name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) archive_path: Option<String>,
+    /// What to do if the folder already exists when the files are about to be copied.
+    #[serde(default)]
+    pub(crate) overwrite: OverwritePolicy,
+    /// Whether to write a manifest of checksums of every file into the folder.
+    #[serde(default)]
+    pub(crate) manifest: bool,
+    /// The hash algorithm used for the checksums in the manifest.
+    #[serde(default)]
+    pub(crate) checksum_algorithm: ChecksumAlgorithm,
+    /// The most that the source files may add up to, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_size_bytes: Option<u64>,
+    /// A shell command to run in the folder once the files have been packaged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) post_copy: Option<String>,
+    /// How hard to compress the archive, from 0 (no compression, fastest) to 9 (maximum compression). Defaults to 6.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compression_level: Option<u32>,
+    /// A comment to store in ZIP archives. Template variables are substituted in the same way as for `name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) archive_comment: Option<String>,
+    /// Where to write temporary files, relative to the root directory, such as an archive being rebuilt, a manifest
+    /// waiting to be archived or a downloaded URL source. Setting it to a folder on the same filesystem as the
+    /// destination means they can be renamed into place rather than copied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) temp_dir: Option<String>,
+    /// Key-value pairs, where each key is the name of a source in a [`Config`][config], and each value is the location
+    /// to move that source to.
+    ///
+    /// [config]: ./struct.Config.html
+    pub(crate) locations: BTreeMap<String, DestLoc>,
+}
+
+impl Destination {
+    /// Create a destination named `name` with the given locations, and every other setting at its default: not
+    /// archived, no manifest, and copying into the folder if it already exists.
+    pub fn new(name: &str, locations: BTreeMap<String, DestLoc>) -> Destination {
+        Destination {
+            name: name.to_string(),
+            locations,
+            ..Destination::default()
+        }
+    }
+
+    /// The name of the final folder/archive, before template variables are substituted.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the folder is archived, and in which format.
+    pub fn archive(&self) -> ArchiveMode {
+        self.archive
+    }
+
+    /// The destination locations, by the key of the source they are for.
+    pub fn locations(&self) -> &BTreeMap<String, DestLoc> {
+        &self.locations
+    }
+
+    /// The destination locations, by the key of the source they are for, to add, change or remove them in place.
+    pub fn locations_mut(&mut self) -> &mut BTreeMap<String, DestLoc> {
+        &mut self.locations
+    }
+}
+
+/// Whether a destination folder is archived, and in which format.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ArchiveModeRepr", into = "ArchiveModeRepr")]
+pub enum ArchiveMode {
+    /// The folder is not archived. Written as `false`.
+    #[default]
+    None,
+    /// A ZIP file. Written as `"zip"`, or as `true` in older config files.
+    Zip,
+    /// A gzipped tarball. Written as `"tar.gz"`.
+    TarGz,
+}
+
+impl ArchiveMode {
+    /// Whether the folder is archived at all.
+    pub fn is_enabled(self) -> bool {
+        self != ArchiveMode::None
+    }
+
+    /// The file extension of archives in this format, without a leading `.`, or `None` if the folder is not archived.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            ArchiveMode::None => None,
+            ArchiveMode::Zip => Some("zip"),
+            ArchiveMode::TarGz => Some("tar.gz"),
+        }
+    }
+}
+
+/// What to do if the destination folder already exists when the files are about to be copied into it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverwritePolicy {
+    /// Ask on the terminal whether to carry on, and stop if the answer isn't yes.
+    Ask,
+    /// Stop with an error.
+    Skip,
+    /// Copy into the existing folder, replacing any files with the same names. This is the default.
+    #[default]
+    Overwrite,
+}
+
+/// How an [`ArchiveMode`][archivemode] is written in the config file. `archive` used to be a boolean, so `true` is
+/// still accepted and means ZIP.
+///
+/// [archivemode]: ./enum.ArchiveMode.html
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum ArchiveModeRepr {
+    Enabled(bool),
+    Format(String),
+}
+
+impl TryFrom<ArchiveModeRepr> for ArchiveMode {
+    type Error = String;
+
+    fn try_from(repr: ArchiveModeRepr) -> std::result::Result<Self, Self::Error> {
+        match repr {
+            ArchiveModeRepr::Enabled(false) => Ok(ArchiveMode::None),
+            ArchiveModeRepr::Enabled(true) => Ok(ArchiveMode::Zip),
+            ArchiveModeRepr::Format(format) => match format.as_str() {
+                "zip" => Ok(ArchiveMode::Zip),
+                "tar.gz" => Ok(ArchiveMode::TarGz),
+                _ => Err(format!(
+                    "unknown archive format `{}`, expected `false`, `\"zip\"` or `\"tar.gz\"`",
+                    format
+                )),
+            },
+        }
+    }
+}
+
+impl From<ArchiveMode> for ArchiveModeRepr {
+    fn from(mode: ArchiveMode) -> Self {
+        match mode.extension() {
+            None => ArchiveModeRepr::Enabled(false),
+            Some(extension) => ArchiveModeRepr::Format(extension.to_string()),
+        }
+    }
+}
+
+/// A destination location.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(from = "DestLocRepr", into = "DestLocRepr")]
+pub enum DestLoc {
+    /// A folder, stored as a relative path in a string.
+    Folder(String),
+    /// The full path of a single file, relative to the destination folder, so that the file can be renamed. Written
+    /// as `{ file = "path" }`, and only allowed for sources that are single files.
+    File(String),
+    /// Several folders, each of which gets its own copy of the source's files. Written as an array of relative paths,
+    /// none of which may lead outside the destination folder.
+    Multiple(Vec<String>),
+    /// A folder whose path depends on the name of each file copied into it, written as `{ template = "path" }`. The
+    /// path may contain `{stem}`, `{ext}` and `{filename}`, which are replaced with the file's name without its
+    /// extension, its extension, and its whole name, e.g. `{ template = "reports/{stem}" }` puts `lab3.pdf` in
+    /// `reports/lab3/`.
+    Template(String),
+}
+
+/// How a [`DestLoc`][destloc] is written in the config file. A bare string can't be used for both variants, so files
+/// are written as a table instead.
+///
+/// [destloc]: ./enum.DestLoc.html
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum DestLocRepr {
+    Folder(String),
+    File { file: String },
+    Template { template: String },
+    Multiple(Vec<String>),
+}
+
+impl From<DestLocRepr> for DestLoc {
+    fn from(repr: DestLocRepr) -> Self {
+        match repr {
+            DestLocRepr::Folder(folder) => DestLoc::Folder(folder),
+            DestLocRepr::File { file } => DestLoc::File(file),
+            DestLocRepr::Template { template } => DestLoc::Template(template),
+            DestLocRepr::Multiple(folders) => DestLoc::Multiple(folders),
+        }
+    }
+}
+
+impl From<DestLoc> for DestLocRepr {
+    fn from(location: DestLoc) -> Self {
+        match location {
+            DestLoc::Folder(folder) => DestLocRepr::Folder(folder),
+            DestLoc::File(file) => DestLocRepr::File { file },
+            DestLoc::Template(template) => DestLocRepr::Template { template },
+            DestLoc::Multiple(folders) => DestLocRepr::Multiple(folders),
+        }
+    }
+}
+
+/// Something about a [`Config`][config] that was adjusted automatically, and that the user should know about.
+///
+/// [config]: ./struct.Config.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConfigWarning {
+    /// The source with the key `key` had no destination location, so it was given `location`.
+    AutoFilledLocation { key: String, location: String },
+    /// There was no `username`, so the login name `username` from the `USER` or `USERNAME` environment variable was
+    /// used.
+    UsernameFromEnv { username: String },
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigWarning::AutoFilledLocation {
+                ref key,
+                ref location,
+            } => write!(
+                f,
+                "Source `{}` has no destination location, so it will be copied to `{}`; add `{} = \"{}\"` to \
+                 [destination.locations] to keep it there",
+                key, location, key, location
+            ),
+            ConfigWarning::UsernameFromEnv { ref username } => write!(
+                f,
+                "No username in the config, so using your login name `{}`; add `username = \"{}\"` if this isn't \
+                 your University of Bath username",
+                username, username
+            ),
+        }
+    }
+}
+
+/// Convenience alias for functions that return [`Error`][error]s.
+///
+/// [error]: ./enum.Error.html
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors to do with [`Config`][config] reading and parsing.
+///
+/// [config]: ./struct.Config.html
+#[derive(Debug)]
+pub enum Error {
+    /// Wraps a [`toml::de::Error`][tomlerr].
+    ///
+    /// [tomlerr]: ../../toml/de/struct.Error.html
+    TomlError(toml::de::Error),
+    /// Wraps a [`toml::ser::Error`][tomlsererr].
+    ///
+    /// [tomlsererr]: ../../toml/ser/enum.Error.html
+    TomlSerError(toml::ser::Error),
+    /// Wraps a [`std::io::Error`][ioerr].
+    ///
+    /// [ioerr]: https://doc.rust-lang.org/std/io/struct.Error.html
+    IoError(std::io::Error),
+    /// A file includes itself, either directly or through other included files.
+    IncludeCycle(PathBuf),
+    /// The `include` key in a file is not a string or an array of strings.
+    InvalidInclude(PathBuf),
+    /// The source with this key is a folder, but its destination location is a single file.
+    FileLocationForFolder { key: String },
+    /// `destination.compression_level` is not between 0 and 9.
+    InvalidCompressionLevel { value: u32 },
+    /// One of the folders in the destination location for the source with this key is outside the destination folder.
+    LocationOutsideDestination { key: String, path: String },
+    /// The environment variable `name` has a value that can't be used, for the given reason.
+    InvalidEnvVar { name: String, reason: String },
+    /// There is no `username`, and no login name to use instead.
+    MissingUsername,
+    /// This path would lead outside the folder it was made relative to.
+    PathTraversal { path: String },
+    /// No configuration file was found in any of these places.
+    NotFound { searched: Vec<PathBuf> },
+    /// The source with this key has an `encoding` other than UTF-8, which is the only one that can be checked.
+    UnsupportedEncoding { key: String, encoding: String },
+    /// A template variable can't be set with this name, because it is the name of a built-in variable.
+    ReservedVarName { name: String },
+    /// A template variable can't be set with this name, because it can't be used in a template.
+    InvalidVarName { name: String },
+    /// The template destination location for the source with this key can't be formatted, for the given reason.
+    InvalidLocationTemplate { key: String, reason: String },
+    /// `destination.name` is empty or `.`, so the destination folder would be the root directory itself.
+    DestNameIsRoot { name: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TomlError(ref toml_err) => write!(f, "{}", toml_err),
+            Error::TomlSerError(ref toml_err) => write!(f, "{}", toml_err),
+            Error::IoError(ref io_err) => write!(f, "{}", io_err),
+            Error::IncludeCycle(ref path) => write!(f, "{} includes itself", path.display()),
+            Error::InvalidInclude(ref path) => {
+                write!(
+                    f,
+                    "`include` in {} must be a string or an array of strings",
+                    path.display()
+                )
+            }
+            Error::InvalidCompressionLevel { value } => write!(
+                f,
+                "Invalid compression level {}: must be between 0 and 9",
+                value
+            ),
+            Error::LocationOutsideDestination { ref key, ref path } => write!(
+                f,
+                "Location {} for source `{}` is outside the destination folder",
+                path, key
+            ),
+            Error::FileLocationForFolder { ref key } => write!(
+                f,
+                "Source `{}` is a folder, so its destination location must be a folder, not a file",
+                key
+            ),
+            Error::InvalidEnvVar {
+                ref name,
+                ref reason,
+            } => write!(f, "Invalid value for {}: {}", name, reason),
+            Error::MissingUsername => write!(
+                f,
+                "No username given, and neither USER nor USERNAME is set to use instead"
+            ),
+            Error::PathTraversal { ref path } => {
+                write!(f, "{} leads outside the folder it is relative to", path)
+            }
+            Error::UnsupportedEncoding {
+                ref key,
+                ref encoding,
+            } => write!(
+                f,
+                "Source `{}` has encoding `{}`, but only `utf-8` is supported",
+                key, encoding
+            ),
+            Error::ReservedVarName { ref name } => write!(
+                f,
+                "`{}` is a built-in template variable, so it can't be set",
+                name
+            ),
+            Error::InvalidVarName { ref name } => write!(
+                f,
+                "Invalid template variable name `{}`: use only letters, digits, `_` and `-`",
+                name
+            ),
+            Error::InvalidLocationTemplate {
+                ref key,
+                ref reason,
+            } => write!(
+                f,
+                "Invalid template location for source `{}`: {}",
+                key, reason
+            ),
+            Error::DestNameIsRoot { ref name } if name.is_empty() => write!(
+                f,
+                "No destination name given (set `destination.name`, or BATHPACK_DEST_NAME)"
+            ),
+            Error::DestNameIsRoot { ref name } => write!(
+                f,
+                "Destination name `{}` is the project folder itself, so the files would be copied into it",
+                name
+            ),
+            Error::NotFound { ref searched } => {
+                write!(f, "No {} found; searched:", CONFIG_FILE_NAME)?;
+                for path in searched {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::TomlError(ref toml_err) => Some(toml_err),
+            Error::TomlSerError(ref toml_err) => Some(toml_err),
+            Error::IoError(ref io_err) => Some(io_err),
+            _ => None,
+        }
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(toml_error: toml::de::Error) -> Self {
+        Error::TomlError(toml_error)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(toml_error: toml::ser::Error) -> Self {
+        Error::TomlSerError(toml_error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(io_error: std::io::Error) -> Self {
+        Error::IoError(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that a correct configuration file string succeeds in being parsed and contains correct
+    /// values.
+    #[test]
+    fn parse_str() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+            
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            test-folder = "."
+            test-file = "test-new-folder/subfolder"
+        "#;
+
+        let decoded: Result<Config> = Config::parse(toml_str);
+        assert!(decoded.is_ok());
+
+        let config = decoded.unwrap();
+        assert_eq!(config.username, "user987".to_string());
+        assert!(!config.destination.manifest);
+        assert_eq!(
+            config.destination.checksum_algorithm,
+            ChecksumAlgorithm::Sha256
+        );
+        assert_eq!(config.destination.post_copy, None);
+    }
+
+    /// Test that the manifest settings in `destination` are parsed, and that an unknown checksum algorithm does not
+    /// successfully parse.
+    #[test]
+    fn manifest_settings() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+            manifest = true
+            checksum_algorithm = "blake3"
+
+            [destination.locations]
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert!(config.destination.manifest);
+        assert_eq!(
+            config.destination.checksum_algorithm,
+            ChecksumAlgorithm::Blake3
+        );
+
+        let decoded = Config::parse(toml_str.replace("blake3", "md5"));
+        assert!(decoded.is_err());
+    }
+
+    /// Test that `archive` accepts `false`, `true`, `"zip"` and `"tar.gz"`, that an unknown format does not
+    /// successfully parse, and that each mode is written back out in its current form.
+    #[test]
+    fn archive_modes() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "test-{username}"
+            archive = MODE
+
+            [destination.locations]
+        "#;
+
+        let cases = [
+            ("false", ArchiveMode::None),
+            ("true", ArchiveMode::Zip),
+            ("\"zip\"", ArchiveMode::Zip),
+            ("\"tar.gz\"", ArchiveMode::TarGz),
+        ];
+
+        for (value, mode) in &cases {
+            let config = Config::parse(toml_str.replace("MODE", value)).unwrap();
+            assert_eq!(config.destination.archive, *mode);
+
+            let written = toml::to_string(&config).unwrap();
+            assert_eq!(Config::parse(written).unwrap().destination.archive, *mode);
+        }
+
+        assert!(Config::parse(toml_str.replace("MODE", "\"rar\"")).is_err());
+    }
+
+    /// Test that file and URL sources are counted exactly, and folder sources separately.
+    #[test]
+    fn estimate_source_file_count() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            code = { path = "src", pattern = "**/*.java" }
+            docs = { path = "docs" }
+            report = "report.pdf"
+            readme = "README.md"
+            data = { url = "https://example.com/data.csv" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+
+        assert_eq!(
+            config.estimate_source_file_count(),
+            SourceCountEstimate {
+                exact_files: 3,
+                folders_needing_expansion: 2,
+            }
+        );
+    }
+
+    /// Test that a config written out with `to_toml` is parsed back into the same config.
+    #[test]
+    fn to_toml() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            code = { path = "src", pattern = "**/*.java" }
+            report = "report.pdf"
+
+            [destination]
+            name = "test-{username}"
+            archive = "tar.gz"
+
+            [destination.locations]
+            code = "src"
+            report = { file = "report.pdf" }
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        let written = config.to_toml().unwrap();
+
+        assert_eq!(Config::parse(&written).unwrap(), config);
+        assert!(written.contains("user987"));
+    }
+
+    /// Test that `overwrite` defaults to `"overwrite"`, and that each policy is parsed.
+    #[test]
+    fn overwrite_policy() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(config.destination.overwrite, OverwritePolicy::Overwrite);
+
+        let cases = [
+            ("ask", OverwritePolicy::Ask),
+            ("skip", OverwritePolicy::Skip),
+            ("overwrite", OverwritePolicy::Overwrite),
+        ];
+
+        for (value, policy) in &cases {
+            let with_policy = toml_str.replace(
+                "archive = false",
+                &format!("archive = false\noverwrite = \"{}\"", value),
+            );
+            let config = Config::parse(with_policy).unwrap();
+            assert_eq!(config.destination.overwrite, *policy);
+        }
+    }
+
+    /// Test that a configuration file with no value for `username` falls back to the login name, and does not
+    /// successfully parse if there isn't one.
+    #[test]
+    fn missing_username() {
+        let toml_str = r#"
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+            
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            test-folder = "."
+            test-file = "test-new-folder/subfolder"
+        "#;
+
+        let decoded: Config = toml::from_str(toml_str).unwrap();
+
+        let config = decoded
+            .clone()
+            .with_username_fallback(|name| match name {
+                "USERNAME" => Some("user987".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.username, "user987");
+        assert_eq!(
+            config.warnings(),
+            &[ConfigWarning::UsernameFromEnv {
+                username: "user987".to_string()
+            }]
+        );
+
+        let config = decoded
+            .clone()
+            .with_username_fallback(|name| Some(name.to_lowercase()))
+            .unwrap();
+        assert_eq!(config.username, "user");
+
+        assert!(matches!(
+            decoded.with_username_fallback(|_| Some(String::new())),
+            Err(Error::MissingUsername)
+        ));
+    }
+
+    /// Test that a configuration file with no `sources` table does not successfully parse.
+    #[test]
+    fn missing_sources() {
+        let toml_str = r#"
+            username = "user987"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            test-folder = "."
+            test-file = "test-new-folder/subfolder"
+        "#;
+
+        let decoded: Result<Config> = Config::parse(toml_str);
+        assert!(decoded.is_err());
+    }
+
+    /// Test that a configuration file with an empty `sources` table successfully parses.
+    #[test]
+    fn empty_sources() {
+        let toml_str = r#"
+            username = "user987"
+            
+            [sources]
+            
+            [destination]
+            name = "test-{username}"
+            archive = true
+            
+            [destination.locations]
+            test-folder = "."
+            test-file = "test-new-folder/subfolder"
+        "#;
+
+        let decoded: Result<Config> = Config::parse(toml_str);
+        assert!(decoded.is_ok());
+
+        let config = decoded.unwrap();
+        assert!(config.sources.is_empty());
+    }
+
+    /// Test that a configuration file with an empty `destination` table does not successfully
+    /// parse.
+    #[test]
+    fn empty_destination() {
+        let toml_str = r#"
+            username = "user987"
+            
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+            
+            [destination]
+        "#;
+
+        let decoded: Result<Config> = Config::parse(toml_str);
+        assert!(decoded.is_err());
+    }
+
+    /// Test that a configuration file with an empty `destination` table, apart from
+    /// `destination.locations`, does not successfully parse.
+    #[test]
+    fn empty_destination_with_locations() {
+        let toml_str = r#"
+            username = "user987"
+            
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+            
+            [destination]
+
+            [destination.locations]
+            test-folder = "."
+            test-file = "test-new-folder/subfolder"
+        "#;
+
+        let decoded: Result<Config> = Config::parse(toml_str);
+        assert!(decoded.is_err());
+    }
+
+    /// Test that a configuration file with no `destination.locations` table does not successfully
+    /// parse.
+    #[test]
+    fn missing_destination_locations() {
+        let toml_str = r#"
+            username = "user987"
+            
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+            
+            [destination]
+            name = "test-{username}"
+            archive = true
+        "#;
+
+        let decoded: Result<Config> = Config::parse(toml_str);
+        assert!(decoded.is_err());
+    }
+
+    /// Test that a configuration file with an empty `destination.locations` table successfully
+    /// parses.
+    #[test]
+    fn empty_destination_locations() {
+        let toml_str = r#"
+            username = "user987"
+            
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+            
+            [destination]
+            name = "test-{username}"
+            archive = true
+            
+            [destination.locations]
+        "#;
+
+        let decoded: Result<Config> = Config::parse(toml_str);
+        assert!(decoded.is_ok());
+
+        let config = decoded.unwrap();
+        assert!(config.destination.locations.is_empty());
+    }
+
+    /// Write each `(name, contents)` pair to a file in a new temporary directory.
+    fn write_files(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        for (name, contents) in files {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        dir
+    }
+
+    /// Test that values from an included file are used as defaults, and overridden by the including file.
+    #[test]
+    fn include_merges_values() {
+        let dir = write_files(&[
+            (
+                "shared/submission.toml",
+                r#"
+                username = "default"
+
+                [sources]
+                report = "report.pdf"
+
+                [destination]
+                name = "cw1-{username}"
+                archive = true
+
+                [destination.locations]
+                report = "."
+                "#,
+            ),
+            (
+                "bathpack.toml",
+                r#"
+                include = "shared/submission.toml"
+                username = "user987"
+
+                [sources]
+                code = { path = "src", pattern = "*.java" }
+
+                [destination.locations]
+                code = "code"
+                "#,
+            ),
+        ]);
+
+        let config = Config::load_with_includes(dir.path().join("bathpack.toml")).unwrap();
+        assert_eq!(config.username, "user987");
+        assert_eq!(config.destination.name, "cw1-{username}");
+        assert_eq!(config.destination.archive, ArchiveMode::Zip);
+        assert_eq!(config.sources.len(), 2);
+        assert_eq!(
+            config.destination.locations["report"],
+            DestLoc::Folder(".".to_string())
+        );
+        assert_eq!(
+            config.destination.locations["code"],
+            DestLoc::Folder("code".to_string())
+        );
+    }
+
+    /// Test that a source or location defined in both files is taken from the including file as a whole, even if it is
+    /// of a different kind.
+    #[test]
+    fn include_replaces_entries() {
+        let dir = write_files(&[
+            (
+                "shared.toml",
+                r#"
+                username = "default"
+
+                [sources]
+                code = { path = "a", pattern = "*.java" }
+
+                [destination]
+                name = "cw1-{username}"
+                archive = false
+
+                [destination.locations]
+                code = { file = "Main.java" }
+                "#,
+            ),
+            (
+                "bathpack.toml",
+                r#"
+                include = "shared.toml"
+
+                [sources]
+                code = { path = "b" }
+
+                [destination.locations]
+                code = "code"
+                "#,
+            ),
+        ]);
+
+        let config = Config::load_with_includes(dir.path().join("bathpack.toml")).unwrap();
+        assert!(matches!(
+            config.source_by_key("code"),
+            Some(Source::RecursiveFolder { path, .. }) if path == "b"
+        ));
+        assert_eq!(
+            config.location_by_key("code"),
+            Some(&DestLoc::Folder("code".to_string()))
+        );
+    }
+
+    /// Test that an array of includes is merged in order, with later files overriding earlier ones.
+    #[test]
+    fn include_array_in_order() {
+        let dir = write_files(&[
+            (
+                "a.toml",
+                "username = \"a\"\n[destination]\nname = \"a\"\narchive = false",
+            ),
+            ("b.toml", "include = \"a.toml\"\nusername = \"b\""),
+            (
+                "bathpack.toml",
+                "include = [\"a.toml\", \"b.toml\"]\n[sources]\n[destination.locations]",
+            ),
+        ]);
+
+        let config = Config::load_with_includes(dir.path().join("bathpack.toml")).unwrap();
+        assert_eq!(config.username, "b");
+        assert_eq!(config.destination.name, "a");
+    }
+
+    /// Test that a file which includes itself through another file does not successfully load.
+    #[test]
+    fn include_cycle() {
+        let dir = write_files(&[
+            ("a.toml", "include = \"b.toml\""),
+            ("b.toml", "include = \"a.toml\""),
+        ]);
+
+        match Config::load_with_includes(dir.path().join("a.toml")) {
+            Err(Error::IncludeCycle(path)) => assert!(path.ends_with("a.toml")),
+            other => panic!("expected IncludeCycle, got {:?}", other),
+        }
+    }
+
+    /// Test that template variables include `username` followed by the `[vars]` entries, and that `[vars]` can't
+    /// override `username`.
+    #[test]
+    fn template_vars() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "{unit}-{username}"
+            archive = true
+
+            [destination.locations]
+
+            [vars]
+            unit = "cm10228"
+            username = "someone-else"
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        let vars: Vec<(&str, &str)> = config.iter_template_vars().collect();
+        assert_eq!(vars, vec![("username", "user987"), ("unit", "cm10228")]);
+
+        let config = Config::parse(toml_str.replace(
+            "username = \"user987\"",
+            "username = \"user987\"\nassignment_name = \"Coursework 1\"",
+        ))
+        .unwrap();
+        assert_eq!(config.assignment_name(), Some("Coursework 1"));
+        let vars: Vec<(&str, &str)> = config.iter_template_vars().collect();
+        assert_eq!(
+            vars,
+            vec![
+                ("username", "user987"),
+                ("assignment", "Coursework 1"),
+                ("unit", "cm10228")
+            ]
+        );
+    }
+
+    /// Test that a folder without a pattern is parsed as a recursive folder.
+    #[test]
+    fn recursive_folder_sources() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            everything = { path = "src" }
+            java = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            everything = "."
+            java = "."
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.source_by_key("everything"),
+            Some(&Source::RecursiveFolder {
+                path: "src".to_string(),
+                exclude_dirs: Vec::new(),
+            })
+        );
+        assert!(matches!(
+            config.source_by_key("java"),
+            Some(Source::Folder { .. })
+        ));
+    }
+
+    /// Test that URL sources are parsed, with and without a checksum.
+    #[test]
+    fn url_sources() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            data = { url = "https://example.com/data.csv" }
+            checked = { url = "https://example.com/checked.csv", sha256 = "abc123" }
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            data = "."
+            checked = "."
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.sources["data"],
+            Source::Url {
+                url: "https://example.com/data.csv".to_string(),
+                sha256: None,
+            }
+        );
+        assert_eq!(
+            config.sources["checked"],
+            Source::Url {
+                url: "https://example.com/checked.csv".to_string(),
+                sha256: Some("abc123".to_string()),
+            }
+        );
+    }
+
+    /// Test that glob sources are told apart from file sources, survive being written out again, and have their
+    /// folders up to the first wildcard in `source_dirs`.
+    #[test]
+    fn glob_sources() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            code = { glob = "src/main/**/*.rs" }
+            docs = { glob = "*.md" }
+            report = "report.pdf"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            code = "."
+            docs = "."
+            report = "."
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.sources["code"],
+            Source::Glob("src/main/**/*.rs".to_string())
+        );
+        assert_eq!(
+            config.sources["report"],
+            Source::File("report.pdf".to_string())
+        );
+
+        assert_eq!(Config::parse(config.to_toml().unwrap()).unwrap(), config);
+        assert_eq!(
+            config.source_dirs(),
+            vec![PathBuf::from("src/main"), PathBuf::from(".")]
+        );
+    }
+
+    /// Test that files can be given an encoding as a table, and that encodings other than UTF-8 are rejected.
+    #[test]
+    fn source_encodings() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            code = { path = "src", pattern = "*.rs", encoding = "utf-8" }
+            report = { file = "report.md", encoding = "UTF8" }
+            notes = { file = "notes.txt" }
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            code = "."
+            report = "."
+            notes = "."
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.sources["report"],
+            Source::TextFile {
+                file: "report.md".to_string(),
+                encoding: Some("UTF8".to_string()),
+            }
+        );
+        assert!(config.sources["code"].requires_utf8());
+        assert!(config.sources["report"].requires_utf8());
+        assert!(!config.sources["notes"].requires_utf8());
+        assert_eq!(Config::parse(config.to_toml().unwrap()).unwrap(), config);
+
+        match Config::parse(toml_str.replace("UTF8", "latin-1")) {
+            Err(Error::UnsupportedEncoding { key, encoding }) => {
+                assert_eq!((key.as_str(), encoding.as_str()), ("report", "latin-1"))
+            }
+            other => panic!("expected UnsupportedEncoding, got {:?}", other),
+        }
+    }
+
+    /// Test that sources and locations can be looked up by key, and that `keys` only includes keys that have both.
+    #[test]
+    fn lookup_by_key() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+            no-location = "other_file_name"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            test-folder = "."
+            test-file = "test-new-folder/subfolder"
+            no-source = "."
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+
+        assert_eq!(
+            config.source_by_key("test-file"),
+            Some(&Source::File("test_file_name".to_string()))
+        );
+        assert_eq!(config.source_by_key("no-source"), None);
+        assert_eq!(
+            config.location_by_key("test-folder"),
+            Some(&DestLoc::Folder(".".to_string()))
+        );
+        assert_eq!(config.location_by_key("no-location"), None);
+
+        let keys: Vec<&str> = config.keys().collect();
+        assert_eq!(keys, vec!["test-file", "test-folder"]);
+    }
+
+    /// Test that the nearest configuration file is found by walking up from the starting directory.
+    #[test]
+    fn find_config_walks_up() {
+        let dir = write_files(&[
+            ("bathpack.toml", ""),
+            ("src/util/Util.java", ""),
+            ("nested/bathpack.toml", ""),
+        ]);
+        let root = dir.path();
+
+        assert_eq!(
+            find_config(&root.join("src/util")),
+            Some(root.join("bathpack.toml"))
+        );
+        assert_eq!(find_config(root), Some(root.join("bathpack.toml")));
+        assert_eq!(
+            find_config(&root.join("nested")),
+            Some(root.join("nested/bathpack.toml"))
+        );
+    }
+
+    /// Test that configs are searched for in the current directory and its parents before the home and system-wide
+    /// locations, and that every searched path is reported if none is found.
+    #[test]
+    fn from_default_locations() {
+        let config = |username: &str| {
+            format!(
+                r#"
+                username = "{}"
+
+                [sources]
+
+                [destination]
+                name = "test"
+                archive = false
+
+                [destination.locations]
+            "#,
+                username
+            )
+        };
+        let dir = write_files(&[
+            ("project/src/Main.java", ""),
+            ("project/bathpack.toml", &config("proj987")),
+            ("home/.config/bathpack/bathpack.toml", &config("home987")),
+        ]);
+        let root = dir.path();
+        let current_dir = root.join("project/src");
+        let home_dir = root.join("home");
+
+        let locations = default_locations(&current_dir, Some(&home_dir));
+        assert_eq!(locations[0], current_dir.join("bathpack.toml"));
+        assert_eq!(locations[1], root.join("project/bathpack.toml"));
+        assert_eq!(
+            locations[locations.len() - 2],
+            home_dir.join(".config/bathpack/bathpack.toml")
+        );
+        assert_eq!(
+            locations[locations.len() - 1],
+            PathBuf::from("/etc/bathpack/bathpack.toml")
+        );
+
+        let (config, path) = Config::from_first_of(locations.clone()).unwrap();
+        assert_eq!(config.username, "proj987");
+        assert_eq!(path, root.join("project/bathpack.toml"));
+
+        std::fs::remove_file(root.join("project/bathpack.toml")).unwrap();
+        let (config, _) = Config::from_first_of(locations.clone()).unwrap();
+        assert_eq!(config.username, "home987");
+
+        let missing = vec![root.join("missing/bathpack.toml")];
+        match Config::from_first_of(missing.clone()) {
+            Err(Error::NotFound { searched }) => assert_eq!(searched, missing),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+
+    /// Test that template locations are parsed, and rejected if they use anything but the parts of a file's name.
+    #[test]
+    fn template_locations() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            report = "lab3.pdf"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            report = { template = "reports/{stem}-{ext}" }
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.location_by_key("report"),
+            Some(&DestLoc::Template("reports/{stem}-{ext}".to_string()))
+        );
+        assert_eq!(Config::parse(config.to_toml().unwrap()).unwrap(), config);
+
+        match Config::parse(toml_str.replace("{ext}", "{username}")) {
+            Err(Error::InvalidLocationTemplate { key, .. }) => assert_eq!(key, "report"),
+            other => panic!("expected InvalidLocationTemplate, got {:?}", other),
+        }
+    }
+
+    /// Test that file destination locations are parsed, and are rejected for folder sources.
+    #[test]
+    fn file_locations() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            test-folder = { path = "test_path", pattern = "test_pattern" }
+            test-file = "test_file_name"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            test-folder = "."
+            test-file = { file = "renamed/file_name" }
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.location_by_key("test-file"),
+            Some(&DestLoc::File("renamed/file_name".to_string()))
+        );
+        assert_eq!(
+            config.location_by_key("test-folder"),
+            Some(&DestLoc::Folder(".".to_string()))
+        );
+
+        let toml_str = toml_str.replace(r#"test-folder = ".""#, r#"test-folder = { file = "x" }"#);
+        match Config::parse(toml_str) {
+            Err(Error::FileLocationForFolder { key }) => assert_eq!(key, "test-folder"),
+            other => panic!("expected FileLocationForFolder, got {:?}", other),
+        }
+    }
+
+    /// Test that compression levels from 0 to 9 are accepted, and others are rejected.
+    #[test]
+    fn compression_level() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "test-{username}"
+            archive = "zip"
+            compression_level = LEVEL
+
+            [destination.locations]
+        "#;
+
+        for level in &[0, 6, 9] {
+            let config = Config::parse(toml_str.replace("LEVEL", &level.to_string())).unwrap();
+            assert_eq!(config.destination.compression_level, Some(*level));
+        }
+
+        match Config::parse(toml_str.replace("LEVEL", "10")) {
+            Err(Error::InvalidCompressionLevel { value }) => assert_eq!(value, 10),
+            other => panic!("expected InvalidCompressionLevel, got {:?}", other),
+        }
+    }
+
+    /// Test that a destination name that is empty or `.` is rejected, both in a config file and when the config is read
+    /// from environment variables without `BATHPACK_DEST_NAME`.
+    #[test]
+    fn dest_name_is_root() {
+        let toml_str = r#"
+            username = "user987"
 
-/// A destination location.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum DestLoc {
-    /// A folder, stored as a relative path in a string.
-    Folder(String),
-}
+            [sources]
 
-/// Convenience alias for functions that return [`Error`][error]s.
-///
-/// [error]: ./enum.Error.html
-pub type Result<T> = std::result::Result<T, Error>;
+            [destination]
+            name = "NAME"
+            archive = false
 
-/// Errors to do with [`Config`][config] reading and parsing.
-///
-/// [config]: ./struct.Config.html
-#[derive(Debug)]
-pub enum Error {
-    /// Wraps a [`toml::de::Error`][tomlerr].
-    ///
-    /// [tomlerr]: ../../toml/de/struct.Error.html
-    TomlError(toml::de::Error),
-    /// Wraps a [`std::io::Error`][ioerr].
-    ///
-    /// [ioerr]: https://doc.rust-lang.org/std/io/struct.Error.html
-    IoError(std::io::Error),
-}
+            [destination.locations]
+        "#;
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Error::TomlError(ref toml_err) => write!(f, "{}", toml_err),
-            Error::IoError(ref io_err) => write!(f, "{}", io_err),
+        for name in &["", ".", "./", "sub/.."] {
+            match Config::parse(toml_str.replace("NAME", name)) {
+                Err(Error::DestNameIsRoot { name: actual }) => assert_eq!(actual, *name),
+                other => panic!("expected DestNameIsRoot for `{}`, got {:?}", name, other),
+            }
         }
-    }
-}
 
-impl std::error::Error for Error {}
+        let vars = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
 
-impl From<toml::de::Error> for Error {
-    fn from(toml_error: toml::de::Error) -> Self {
-        Error::TomlError(toml_error)
+        assert!(matches!(
+            Config::default().with_vars(vars(&[("BATHPACK_USERNAME", "user987")])),
+            Err(Error::DestNameIsRoot { .. })
+        ));
+        let config = Config::default()
+            .with_vars(vars(&[
+                ("BATHPACK_USERNAME", "user987"),
+                ("BATHPACK_DEST_NAME", "cw-{username}"),
+            ]))
+            .unwrap();
+        assert_eq!(config.destination.name, "cw-{username}");
     }
-}
 
-impl From<std::io::Error> for Error {
-    fn from(io_error: std::io::Error) -> Self {
-        Error::IoError(io_error)
-    }
-}
+    /// Test that sources without a location are given a folder named after their key, with a warning for each, and that
+    /// existing locations are left alone.
+    #[test]
+    fn auto_fill_locations() {
+        let (config, warnings) = Config::parse(
+            r#"
+            username = "user987"
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            [sources]
+            code = { path = "src", pattern = "*.java" }
+            report = "report.pdf"
 
-    /// Test that a correct configuration file string succeeds in being parsed and contains correct
-    /// values.
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            report = "."
+        "#,
+        )
+        .unwrap()
+        .auto_fill_locations();
+
+        assert_eq!(
+            warnings,
+            vec![ConfigWarning::AutoFilledLocation {
+                key: "code".to_string(),
+                location: "code/".to_string(),
+            }]
+        );
+
+        assert_eq!(
+            config.location_by_key("code"),
+            Some(&DestLoc::Folder("code/".to_string()))
+        );
+        assert_eq!(
+            config.location_by_key("report"),
+            Some(&DestLoc::Folder(".".to_string()))
+        );
+    }
+
+    /// Test that an array of folders is parsed as a location, and is rejected if any folder is outside the destination
+    /// folder.
     #[test]
-    fn parse_str() {
+    fn multiple_locations() {
         let toml_str = r#"
             username = "user987"
 
             [sources]
-            test-folder = { path = "test_path", pattern = "test_pattern" }
-            test-file = "test_file_name"
-            
+            code = { path = "src", pattern = "*.java" }
+
             [destination]
             name = "test-{username}"
             archive = true
 
             [destination.locations]
-            test-folder = "."
-            test-file = "test-new-folder/subfolder"
+            code = ["marking", "autograder/./src"]
         "#;
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_ok());
+        let config = Config::parse(toml_str).unwrap().normalize();
+        assert_eq!(
+            config.location_by_key("code"),
+            Some(&DestLoc::Multiple(vec![
+                "marking".to_string(),
+                "autograder/src".to_string()
+            ]))
+        );
 
-        let config = decoded.unwrap();
-        assert_eq!(config.username, "user987".to_string());
+        for outside in &["../marking", "/marking", "a/../../marking"] {
+            let toml_str = toml_str.replace("\"marking\"", &format!("\"{}\"", outside));
+            match Config::parse(toml_str) {
+                Err(Error::LocationOutsideDestination { key, path }) => {
+                    assert_eq!(key, "code");
+                    assert_eq!(path, *outside);
+                }
+                other => panic!("expected LocationOutsideDestination, got {:?}", other),
+            }
+        }
     }
 
-    /// Test that a configuration file with no value for `username` does not successfully
-    /// parse.
+    /// Test that sources and locations can be added with `extend`, and that the result can still be validated.
     #[test]
-    fn missing_username() {
+    fn extend() {
         let toml_str = r#"
+            username = "user987"
+
             [sources]
-            test-folder = { path = "test_path", pattern = "test_pattern" }
             test-file = "test_file_name"
-            
+
             [destination]
             name = "test-{username}"
             archive = true
 
             [destination.locations]
-            test-folder = "."
-            test-file = "test-new-folder/subfolder"
+            test-file = "."
         "#;
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_err());
+        let mut config = Config::parse(toml_str).unwrap();
+        config.extend(vec![
+            ("code".to_string(), Source::File("main.rs".to_string())),
+            (
+                "test-file".to_string(),
+                Source::File("other_file_name".to_string()),
+            ),
+        ]);
+        config.extend(vec![(
+            "code".to_string(),
+            DestLoc::Folder("src".to_string()),
+        )]);
+
+        assert_eq!(
+            config.source_by_key("test-file"),
+            Some(&Source::File("other_file_name".to_string()))
+        );
+        assert_eq!(
+            config.location_by_key("code"),
+            Some(&DestLoc::Folder("src".to_string()))
+        );
+        assert!(config.validate().is_ok());
+
+        config.extend(vec![(
+            "folder".to_string(),
+            Source::Folder {
+                path: "src".to_string(),
+                pattern: "*".to_string(),
+                max_path_length: None,
+                rename_pattern: None,
+                rename_replacement: None,
+                exclude_dirs: Vec::new(),
+                allow_empty: false,
+                follow_symlinks: true,
+                sort: None,
+                mtime_from: None,
+                deduplicate: false,
+                encoding: None,
+            },
+        )]);
+        config.extend(vec![("folder".to_string(), DestLoc::File("x".to_string()))]);
+        assert!(config.validate().is_err());
     }
 
-    /// Test that a configuration file with no `sources` table does not successfully parse.
+    /// A config with the given username and destination name, and a single file source and folder location for
+    /// each of `keys`, named after the key with the suffix `suffix`.
+    fn merge_config(username: &str, name: &str, keys: &[&str], suffix: &str) -> Config {
+        let mut config = Config {
+            username: username.to_string(),
+            ..Config::default()
+        };
+        config.destination.name = name.to_string();
+
+        for key in keys {
+            config.extend(vec![(
+                key.to_string(),
+                Source::File(format!("{}{}", key, suffix)),
+            )]);
+            config.extend(vec![(
+                key.to_string(),
+                DestLoc::Folder(format!("{}{}", key, suffix)),
+            )]);
+        }
+
+        config
+    }
+
+    /// Test that the overlay's username is used unless it is empty.
     #[test]
-    fn missing_sources() {
-        let toml_str = r#"
+    fn merge_username() {
+        let base = merge_config("base", "", &[], "");
+
+        let merged = Config::merge(base.clone(), merge_config("overlay", "", &[], ""));
+        assert_eq!(merged.username, "overlay");
+
+        let merged = Config::merge(base, merge_config("", "", &[], ""));
+        assert_eq!(merged.username, "base");
+    }
+
+    /// Test that sources are unioned, with the overlay's source used for keys in both.
+    #[test]
+    fn merge_sources() {
+        let base = merge_config("user987", "", &["a", "b"], "-base");
+        let overlay = merge_config("user987", "", &["b", "c"], "-overlay");
+
+        let merged = Config::merge(base, overlay);
+        let sources: Vec<(&str, &Source)> = merged
+            .sources
+            .iter()
+            .map(|(key, source)| (key.as_str(), source))
+            .collect();
+
+        assert_eq!(
+            sources,
+            vec![
+                ("a", &Source::File("a-base".to_string())),
+                ("b", &Source::File("b-overlay".to_string())),
+                ("c", &Source::File("c-overlay".to_string())),
+            ]
+        );
+    }
+
+    /// Test that destination locations are unioned, with the overlay's location used for keys in both.
+    #[test]
+    fn merge_locations() {
+        let base = merge_config("user987", "", &["a", "b"], "-base");
+        let overlay = merge_config("user987", "", &["b", "c"], "-overlay");
+
+        let merged = Config::merge(base, overlay);
+
+        assert_eq!(
+            merged.location_by_key("a"),
+            Some(&DestLoc::Folder("a-base".to_string()))
+        );
+        assert_eq!(
+            merged.location_by_key("b"),
+            Some(&DestLoc::Folder("b-overlay".to_string()))
+        );
+        assert_eq!(
+            merged.location_by_key("c"),
+            Some(&DestLoc::Folder("c-overlay".to_string()))
+        );
+    }
+
+    /// Test that the overlay's destination settings are used as a whole unless they are all the defaults.
+    #[test]
+    fn merge_destination() {
+        let mut base = merge_config("user987", "base-{username}", &[], "");
+        base.destination.archive = ArchiveMode::Zip;
+        base.destination.manifest = true;
+
+        let merged = Config::merge(base.clone(), merge_config("user987", "", &[], ""));
+        assert_eq!(merged.destination.name, "base-{username}");
+        assert_eq!(merged.destination.archive, ArchiveMode::Zip);
+        assert!(merged.destination.manifest);
+
+        let merged = Config::merge(
+            base.clone(),
+            merge_config("user987", "overlay-{username}", &[], ""),
+        );
+        assert_eq!(merged.destination.name, "overlay-{username}");
+        assert_eq!(merged.destination.archive, ArchiveMode::None);
+        assert!(!merged.destination.manifest);
+
+        let mut overlay = merge_config("user987", "", &[], "");
+        overlay.destination.checksum_algorithm = ChecksumAlgorithm::Blake3;
+        let merged = Config::merge(base, overlay);
+        assert_eq!(merged.destination.name, "");
+        assert_eq!(
+            merged.destination.checksum_algorithm,
+            ChecksumAlgorithm::Blake3
+        );
+    }
+
+    /// Test that the overlay's `post_copy` hook is used only if it is set.
+    #[test]
+    fn merge_post_copy() {
+        let mut base = merge_config("user987", "", &[], "");
+        base.destination.post_copy = Some("make clean".to_string());
+
+        let merged = Config::merge(base.clone(), merge_config("user987", "", &[], ""));
+        assert_eq!(merged.destination.post_copy, Some("make clean".to_string()));
+
+        let mut overlay = merge_config("user987", "", &[], "");
+        overlay.destination.post_copy = Some("make test".to_string());
+        let merged = Config::merge(base, overlay);
+        assert_eq!(merged.destination.post_copy, Some("make test".to_string()));
+    }
+
+    /// Test that the overlay's `archive_path` is used only if it is set.
+    #[test]
+    fn merge_archive_path() {
+        let mut base = merge_config("user987", "", &[], "");
+        base.destination.archive_path = Some("dist/{username}.zip".to_string());
+
+        let merged = Config::merge(base.clone(), merge_config("user987", "", &[], ""));
+        assert_eq!(
+            merged.destination.archive_path,
+            Some("dist/{username}.zip".to_string())
+        );
+
+        let mut overlay = merge_config("user987", "", &[], "");
+        overlay.destination.archive_path = Some("out/{username}.zip".to_string());
+        let merged = Config::merge(base, overlay);
+        assert_eq!(
+            merged.destination.archive_path,
+            Some("out/{username}.zip".to_string())
+        );
+    }
+
+    /// Test that the overlay's `max_size_bytes` is used only if it is set, so that it can tighten or relax the base's.
+    #[test]
+    fn merge_max_size_bytes() {
+        let mut base = merge_config("user987", "", &[], "");
+        base.destination.max_size_bytes = Some(1000);
+
+        let merged = Config::merge(base.clone(), merge_config("user987", "", &[], ""));
+        assert_eq!(merged.destination.max_size_bytes, Some(1000));
+
+        let mut overlay = merge_config("user987", "", &[], "");
+        overlay.destination.max_size_bytes = Some(500);
+        let merged = Config::merge(base, overlay);
+        assert_eq!(merged.destination.max_size_bytes, Some(500));
+    }
+
+    /// Test that `[vars]` are unioned, with the overlay's value used for names in both.
+    #[test]
+    fn merge_vars() {
+        let mut base = merge_config("user987", "", &[], "");
+        base.vars.insert("unit".to_string(), "cm10227".to_string());
+        base.vars.insert("year".to_string(), "2018".to_string());
+
+        let mut overlay = merge_config("user987", "", &[], "");
+        overlay
+            .vars
+            .insert("unit".to_string(), "cm10228".to_string());
+
+        let merged = Config::merge(base, overlay);
+        assert_eq!(merged.vars["unit"], "cm10228");
+        assert_eq!(merged.vars["year"], "2018");
+    }
+
+    /// Test that settings from environment variables take precedence over the config, and that invalid values are
+    /// rejected.
+    #[test]
+    fn with_vars() {
+        let vars = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let base = merge_config("user987", "base-{username}", &["readme"], ".md");
+
+        let config = base
+            .with_vars(vars(&[
+                ("BATHPACK_USERNAME", "abc123"),
+                ("BATHPACK_DEST_ARCHIVE", "tar.gz"),
+                (
+                    "BATHPACK_SOURCE_CODE",
+                    r#"{"path": "src", "pattern": "*.java"}"#,
+                ),
+                ("BATHPACK_LOCATION_CODE", "code"),
+                ("BATHPACK_SOURCE_REPORT", "report.pdf"),
+                ("BATHPACK_LOCATION_REPORT", r#"{"file": "Report.pdf"}"#),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+
+        assert_eq!(config.username, "abc123");
+        assert_eq!(config.destination.name, "base-{username}");
+        assert_eq!(config.destination.archive, ArchiveMode::TarGz);
+        assert_eq!(
+            config.source_by_key("readme"),
+            Some(&Source::File("readme.md".to_string()))
+        );
+        assert!(matches!(
+            config.source_by_key("code"),
+            Some(Source::Folder { .. })
+        ));
+        assert_eq!(
+            config.location_by_key("code"),
+            Some(&DestLoc::Folder("code".to_string()))
+        );
+        assert_eq!(
+            config.source_by_key("report"),
+            Some(&Source::File("report.pdf".to_string()))
+        );
+        assert_eq!(
+            config.location_by_key("report"),
+            Some(&DestLoc::File("Report.pdf".to_string()))
+        );
+
+        assert!(base
+            .with_vars(vars(&[("BATHPACK_DEST_ARCHIVE", "rar")]))
+            .is_err());
+        assert!(base
+            .with_vars(vars(&[("BATHPACK_SOURCE_CODE", "{not json")]))
+            .is_err());
+    }
+
+    /// Test that the `with_*` setters change only their own field, and that `with_archive` keeps the archive format.
+    #[test]
+    fn setters() {
+        let base = merge_config("user987", "base-{username}", &["a"], "");
+
+        let config = base.with_username("abc123").with_dest_name("cw-{username}");
+        assert_eq!(config.username, "abc123");
+        assert_eq!(config.destination.name, "cw-{username}");
+        assert_eq!(config.sources, base.sources);
+        assert_eq!(base.username, "user987");
+
+        let config = base.with_archive(true);
+        assert_eq!(config.destination.archive, ArchiveMode::Zip);
+
+        let config = base
+            .with_archive_format(ArchiveMode::TarGz)
+            .with_archive(true);
+        assert_eq!(config.destination.archive, ArchiveMode::TarGz);
+        assert_eq!(
+            config.with_archive(false).destination.archive,
+            ArchiveMode::None
+        );
+    }
+
+    /// Test that `set_var` adds to `[vars]`, but not with a built-in name or one that can't go in a template.
+    #[test]
+    fn set_var() {
+        let base = merge_config("user987", "{unit}-{username}", &["a"], "");
+
+        let config = base.set_var("unit", "cm10228").unwrap();
+        assert_eq!(config.vars["unit"], "cm10228");
+        assert_eq!(
+            config.resolve().unwrap().destination.name,
+            "cm10228-user987"
+        );
+        assert!(base.vars.is_empty());
+
+        assert!(matches!(
+            base.set_var("username", "someone-else"),
+            Err(Error::ReservedVarName { .. })
+        ));
+        for name in &["", "a:b", "{unit}", "has space"] {
+            assert!(matches!(
+                base.set_var(name, "x"),
+                Err(Error::InvalidVarName { .. })
+            ));
+        }
+    }
+
+    /// Test that a config put together with `Config::new` and changed through its accessors is the same as one parsed
+    /// from the equivalent TOML.
+    #[test]
+    fn new_and_accessors() {
+        let mut sources = BTreeMap::new();
+        sources.insert("report".to_string(), Source::File("report.pdf".to_string()));
+
+        let mut locations = BTreeMap::new();
+        locations.insert("report".to_string(), DestLoc::Folder(".".to_string()));
+
+        let mut config = Config::new(
+            "user987",
+            sources,
+            Destination::new("test-{username}", locations),
+        );
+        config.sources_mut().insert(
+            "src".to_string(),
+            Source::RecursiveFolder {
+                path: "src".to_string(),
+                exclude_dirs: vec![],
+            },
+        );
+        config
+            .destination_mut()
+            .locations_mut()
+            .insert("src".to_string(), DestLoc::Folder("code".to_string()));
+
+        let parsed = Config::parse(
+            r#"
             username = "user987"
 
+            [sources]
+            report = "report.pdf"
+            src = { path = "src" }
+
             [destination]
             name = "test-{username}"
-            archive = true
+            archive = false
 
             [destination.locations]
-            test-folder = "."
-            test-file = "test-new-folder/subfolder"
-        "#;
+            report = "."
+            src = "code"
+        "#,
+        )
+        .unwrap();
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_err());
+        assert_eq!(config, parsed);
+        assert_eq!(config.username(), "user987");
+        assert_eq!(config.sources().len(), 2);
+        assert_eq!(config.destination().name(), "test-{username}");
+        assert_eq!(config.destination().archive(), ArchiveMode::None);
+        assert_eq!(
+            config.destination().locations()["src"],
+            DestLoc::Folder("code".to_string())
+        );
+        config.validate().unwrap();
     }
 
-    /// Test that a configuration file with an empty `sources` table successfully parses.
+    /// Test that `resolve` substitutes variables into the destination name and archive path.
     #[test]
-    fn empty_sources() {
+    fn resolve() {
         let toml_str = r#"
             username = "user987"
-            
+
             [sources]
-            
+
             [destination]
-            name = "test-{username}"
+            name = "{unit}-{username}"
             archive = true
-            
+            archive_path = "dist/{{{username}}}.zip"
+
             [destination.locations]
-            test-folder = "."
-            test-file = "test-new-folder/subfolder"
-        "#;
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_ok());
+            [vars]
+            unit = "cm10228"
+        "#;
 
-        let config = decoded.unwrap();
-        assert!(config.sources.is_empty());
+        let config = Config::parse(toml_str).unwrap().resolve().unwrap();
+        assert_eq!(config.destination.name, "cm10228-user987");
+        assert_eq!(
+            config.destination.archive_path,
+            Some("dist/{user987}.zip".to_string())
+        );
     }
 
-    /// Test that a configuration file with an empty `destination` table does not successfully
-    /// parse.
+    /// Test that folder sources inside other folder sources are found.
     #[test]
-    fn empty_destination() {
+    fn source_path_prefix_conflicts() {
         let toml_str = r#"
             username = "user987"
-            
+
             [sources]
-            test-folder = { path = "test_path", pattern = "test_pattern" }
-            test-file = "test_file_name"
-            
+            main = { path = "src/main", pattern = "*.java" }
+            src = { path = "./src/", pattern = "*.java" }
+            srcs = { path = "srcs" }
+            test = { path = "test" }
+            test-again = { path = "test" }
+            readme = "src/README.md"
+
             [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
         "#;
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_err());
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.source_path_prefix_conflicts(),
+            vec![
+                ("src".to_string(), "main".to_string()),
+                ("test".to_string(), "test-again".to_string()),
+            ]
+        );
+
+        let toml_str = toml_str.replace(r#"path = "test" }"#, r#"path = "." }"#);
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(config.source_path_prefix_conflicts().len(), 8);
     }
 
-    /// Test that a configuration file with an empty `destination` table, apart from
-    /// `destination.locations`, does not successfully parse.
+    /// Test that `source_dirs` lists the folder of every folder and file source once.
     #[test]
-    fn empty_destination_with_locations() {
+    fn source_dirs() {
         let toml_str = r#"
             username = "user987"
-            
+
             [sources]
-            test-folder = { path = "test_path", pattern = "test_pattern" }
-            test-file = "test_file_name"
-            
+            a-folder = { path = "src", pattern = "*.java" }
+            b-everything = { path = "src" }
+            c-readme = "README.md"
+            d-doc = "docs/report.pdf"
+            e-url = { url = "https://example.com/a.txt" }
+
             [destination]
+            name = "test-{username}"
+            archive = true
 
             [destination.locations]
-            test-folder = "."
-            test-file = "test-new-folder/subfolder"
         "#;
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_err());
+        let config = Config::parse(toml_str).unwrap();
+        assert_eq!(
+            config.source_dirs(),
+            vec![
+                PathBuf::from("src"),
+                PathBuf::from("."),
+                PathBuf::from("docs")
+            ]
+        );
     }
 
-    /// Test that a configuration file with no `destination.locations` table does not successfully
-    /// parse.
+    /// Test that `all_paths_relative_to` puts the base in front of relative source and location paths, leaves absolute
+    /// paths alone, and rejects paths that lead outside the base.
     #[test]
-    fn missing_destination_locations() {
+    fn all_paths_relative_to() {
         let toml_str = r#"
             username = "user987"
-            
+
             [sources]
-            test-folder = { path = "test_path", pattern = "test_pattern" }
-            test-file = "test_file_name"
-            
+            code = { glob = "src/**/*.rs" }
+            docs = { path = "docs" }
+            report = "/home/user987/report.pdf"
+
             [destination]
             name = "test-{username}"
             archive = true
+
+            [destination.locations]
+            code = "code"
+            docs = ["docs", "marking"]
+            report = { file = "report.pdf" }
         "#;
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_err());
+        let config = Config::parse(toml_str).unwrap();
+        let rebased = config.all_paths_relative_to(Path::new("part[1]")).unwrap();
+
+        assert_eq!(
+            rebased.source_by_key("code"),
+            Some(&Source::Glob("part[[]1[]]/src/**/*.rs".to_string()))
+        );
+        assert_eq!(
+            rebased.source_by_key("docs"),
+            Some(&Source::RecursiveFolder {
+                path: "part[1]/docs".to_string(),
+                exclude_dirs: Vec::new(),
+            })
+        );
+        assert_eq!(
+            rebased.source_by_key("report"),
+            Some(&Source::File("/home/user987/report.pdf".to_string()))
+        );
+        assert_eq!(
+            rebased.location_by_key("docs"),
+            Some(&DestLoc::Multiple(vec![
+                "part[1]/docs".to_string(),
+                "part[1]/marking".to_string(),
+            ]))
+        );
+        assert_eq!(
+            rebased.location_by_key("report"),
+            Some(&DestLoc::File("part[1]/report.pdf".to_string()))
+        );
+
+        let escaping = Config::parse(toml_str.replace("\"docs\" }", "\"docs/../..\" }")).unwrap();
+        assert!(matches!(
+            escaping.all_paths_relative_to(Path::new("part1")),
+            Err(Error::PathTraversal { .. })
+        ));
     }
 
-    /// Test that a configuration file with an empty `destination.locations` table successfully
-    /// parses.
+    /// Test that `normalize` cleans up the paths of sources and locations, and leaves everything else alone.
     #[test]
-    fn empty_destination_locations() {
+    fn normalize() {
         let toml_str = r#"
             username = "user987"
-            
+
             [sources]
-            test-folder = { path = "test_path", pattern = "test_pattern" }
-            test-file = "test_file_name"
-            
+            folder = { path = "./src/../src//main", pattern = "./**/*.rs" }
+            file = "a/b/../../../README.md"
+            url = { url = "https://example.com/./a/../b" }
+
             [destination]
             name = "test-{username}"
             archive = true
-            
+
             [destination.locations]
+            folder = "./"
+            file = { file = "docs/./x/../README.md" }
+            url = "/abs/../lib"
         "#;
 
-        let decoded: Result<Config> = Config::parse(toml_str);
-        assert!(decoded.is_ok());
+        let config = Config::parse(toml_str).unwrap().normalize();
 
-        let config = decoded.unwrap();
-        assert!(config.destination.locations.is_empty());
+        assert_eq!(
+            config.source_by_key("folder"),
+            Some(&Source::Folder {
+                path: "src/main".to_string(),
+                pattern: "./**/*.rs".to_string(),
+                max_path_length: None,
+                rename_pattern: None,
+                rename_replacement: None,
+                exclude_dirs: Vec::new(),
+                allow_empty: false,
+                follow_symlinks: true,
+                sort: None,
+                mtime_from: None,
+                deduplicate: false,
+                encoding: None,
+            })
+        );
+        assert_eq!(
+            config.source_by_key("file"),
+            Some(&Source::File("../README.md".to_string()))
+        );
+        assert_eq!(
+            config.source_by_key("url"),
+            Some(&Source::Url {
+                url: "https://example.com/./a/../b".to_string(),
+                sha256: None,
+            })
+        );
+        assert_eq!(
+            config.location_by_key("folder"),
+            Some(&DestLoc::Folder(".".to_string()))
+        );
+        assert_eq!(
+            config.location_by_key("file"),
+            Some(&DestLoc::File("docs/README.md".to_string()))
+        );
+        assert_eq!(
+            config.location_by_key("url"),
+            Some(&DestLoc::Folder("/lib".to_string()))
+        );
     }
 }
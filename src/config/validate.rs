@@ -0,0 +1,604 @@
+//
+//  validate.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Checking a [`Config`][config] for likely mistakes that don't stop it from being used.
+//!
+//! [config]: ../struct.Config.html
+
+use super::{Config, DestLoc, Source};
+
+use strfmt::strfmt;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// Checks a [`Config`][config] for likely mistakes.
+///
+/// [config]: ../struct.Config.html
+#[derive(Clone, Copy, Debug)]
+pub struct Validator<'a> {
+    config: &'a Config,
+}
+
+impl<'a> Validator<'a> {
+    /// Create a `Validator` for `config`.
+    pub fn new(config: &'a Config) -> Validator<'a> {
+        Validator { config }
+    }
+
+    /// Check for likely mistakes in how the config describes the project in `root_dir`, returning a warning for each
+    /// one found. Unlike [`Config::source_path_prefix_conflicts`][conflicts], this resolves symlinks, so it also finds
+    /// sources that overlap through a link.
+    ///
+    /// [conflicts]: ../struct.Config.html#method.source_path_prefix_conflicts
+    pub fn validate_with_fs(&self, root_dir: &Path) -> Vec<ValidationWarning> {
+        let mut conflicts = self.config.source_path_prefix_conflicts();
+
+        let folders: Vec<(&String, PathBuf)> = self
+            .config
+            .sources
+            .iter()
+            .filter_map(|(key, source)| match source {
+                Source::Folder { path, .. } | Source::RecursiveFolder { path, .. } => root_dir
+                    .join(path)
+                    .canonicalize()
+                    .ok()
+                    .map(|path| (key, path)),
+                _ => None,
+            })
+            .collect();
+
+        for (a, a_path) in &folders {
+            for (b, b_path) in &folders {
+                let pair = (a.to_string(), b.to_string());
+                let reversed = (pair.1.clone(), pair.0.clone());
+
+                if a != b
+                    && b_path.starts_with(a_path)
+                    && !conflicts.contains(&pair)
+                    && !conflicts.contains(&reversed)
+                {
+                    conflicts.push(pair);
+                }
+            }
+        }
+
+        conflicts
+            .into_iter()
+            .map(|(outer, inner)| ValidationWarning::OverlappingSourcePaths { outer, inner })
+            .collect()
+    }
+
+    /// Check the config in more depth than [`validate_with_fs`][validate], including for things that work but aren't
+    /// recommended for a submission, returning every issue found, most severe first.
+    ///
+    /// [validate]: #method.validate_with_fs
+    pub fn lint(&self, root_dir: &Path) -> Vec<LintIssue> {
+        let mut issues = Vec::new();
+
+        if let Err(err) = self.config.validate() {
+            issues.push(LintIssue::Invalid {
+                reason: err.to_string(),
+            });
+        }
+
+        for (key, source) in &self.config.sources {
+            let path = match source {
+                Source::Folder { path, .. }
+                | Source::RecursiveFolder { path, .. }
+                | Source::Glob(path)
+                | Source::TextFile { file: path, .. }
+                | Source::File(path) => path,
+                Source::Url { .. } => continue,
+            };
+
+            if Path::new(path).is_absolute() {
+                issues.push(LintIssue::AbsoluteSourcePath {
+                    key: key.clone(),
+                    path: path.clone(),
+                });
+            }
+        }
+
+        for (key, location) in &self.config.destination.locations {
+            let paths = match location {
+                DestLoc::Folder(path) | DestLoc::File(path) | DestLoc::Template(path) => {
+                    std::slice::from_ref(path)
+                }
+                DestLoc::Multiple(paths) => paths.as_slice(),
+            };
+
+            for path in paths.iter().filter(|path| Path::new(path).is_absolute()) {
+                issues.push(LintIssue::AbsoluteDestPath {
+                    key: key.clone(),
+                    path: path.clone(),
+                });
+            }
+        }
+
+        if let Some(reason) = self.destination_format_error() {
+            issues.push(LintIssue::InvalidTemplate { reason });
+        }
+
+        issues.extend(
+            self.validate_with_fs(root_dir)
+                .into_iter()
+                .map(LintIssue::Validation),
+        );
+
+        if !self.config.destination.name.contains("{username}") {
+            issues.push(LintIssue::NameWithoutUsername);
+        }
+
+        if !self.config.destination.archive.is_enabled() {
+            issues.push(LintIssue::ArchiveDisabled);
+        }
+
+        issues.sort_by_key(|issue| std::cmp::Reverse(issue.severity()));
+        issues
+    }
+
+    /// Check that every source has a destination location and every location has a source, that no relative source
+    /// path or location path could lead outside the project or destination folder through `..`, and that
+    /// `destination.name` can be filled in, returning every problem found rather than stopping at the first. Absolute
+    /// source paths are allowed, since they are read as they are; [`lint`][lint] warns about them instead.
+    ///
+    /// [lint]: #method.lint
+    pub fn report_all(&self) -> Result<(), Vec<ValidationError>> {
+        let sources = &self.config.sources;
+        let locations = &self.config.destination.locations;
+
+        let mut errors: Vec<ValidationError> = sources
+            .keys()
+            .filter(|key| !locations.contains_key(*key))
+            .map(|key| ValidationError::MissingDestLocation { key: key.clone() })
+            .collect();
+
+        errors.extend(
+            locations
+                .keys()
+                .filter(|key| !sources.contains_key(*key))
+                .map(|key| ValidationError::MissingSourceEntry { key: key.clone() }),
+        );
+
+        for source in sources.values() {
+            match source {
+                Source::Folder { path, .. }
+                | Source::RecursiveFolder { path, .. }
+                | Source::Glob(path)
+                | Source::TextFile { file: path, .. }
+                | Source::File(path) => {
+                    let path = Path::new(path);
+
+                    if !path.is_absolute() && (path.has_root() || has_parent_dir(path)) {
+                        errors.push(ValidationError::PathTraversal {
+                            path: path.to_path_buf(),
+                        });
+                    }
+                }
+                Source::Url { .. } => {}
+            }
+        }
+
+        for location in locations.values() {
+            let paths = match location {
+                DestLoc::Folder(path) | DestLoc::File(path) | DestLoc::Template(path) => {
+                    std::slice::from_ref(path)
+                }
+                DestLoc::Multiple(paths) => paths.as_slice(),
+            };
+
+            errors.extend(
+                paths
+                    .iter()
+                    .map(Path::new)
+                    .filter(|path| has_parent_dir(path))
+                    .map(|path| ValidationError::PathTraversal {
+                        path: path.to_path_buf(),
+                    }),
+            );
+        }
+
+        if let Some(reason) = self.destination_format_error() {
+            errors.push(ValidationError::InvalidDestinationFormat { reason });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Why `destination.name` can't be filled in with the template variables, if it can't.
+    fn destination_format_error(&self) -> Option<String> {
+        let vars: HashMap<String, String> = self
+            .config
+            .iter_template_vars()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        strfmt(&self.config.destination.name, &vars)
+            .err()
+            .map(|err| err.to_string())
+    }
+}
+
+/// A likely mistake in a config, found by a [`Validator`][validator].
+///
+/// [validator]: ./struct.Validator.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValidationWarning {
+    /// The folder of the source `inner` is inside the folder of the source `outer`, so files may be included twice.
+    OverlappingSourcePaths { outer: String, inner: String },
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationWarning::OverlappingSourcePaths {
+                ref outer,
+                ref inner,
+            } => write!(
+                f,
+                "Source `{}` is inside source `{}`, so its files may be included twice",
+                inner, outer
+            ),
+        }
+    }
+}
+
+/// Whether any component of `path` is `..`.
+fn has_parent_dir(path: &Path) -> bool {
+    path.components()
+        .any(|component| component == Component::ParentDir)
+}
+
+/// A problem with a config found by [`Validator::report_all`][report_all].
+///
+/// [report_all]: ./struct.Validator.html#method.report_all
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValidationError {
+    /// The source with this key has no destination location.
+    MissingDestLocation { key: String },
+    /// There is a destination location for this key, but no source.
+    MissingSourceEntry { key: String },
+    /// `destination.name` can't be filled in with the template variables.
+    InvalidDestinationFormat { reason: String },
+    /// A relative source path or a destination location contains `..`, so files could be read from outside the project
+    /// or written outside the destination folder.
+    PathTraversal { path: PathBuf },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::MissingDestLocation { ref key } => {
+                write!(f, "No destination location for source `{}`", key)
+            }
+            ValidationError::MissingSourceEntry { ref key } => {
+                write!(f, "Destination location `{}` has no matching source", key)
+            }
+            ValidationError::InvalidDestinationFormat { ref reason } => {
+                write!(f, "Could not fill in destination.name: {}", reason)
+            }
+            ValidationError::PathTraversal { ref path } => write!(
+                f,
+                "{} leads outside the project or destination folder",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// How serious a [`LintIssue`][lintissue] is.
+///
+/// [lintissue]: ./enum.LintIssue.html
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    /// Worth knowing about, but often intended.
+    Info,
+    /// Probably a mistake.
+    Warning,
+    /// Packaging will fail or do something wrong.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Info => write!(f, "INFO"),
+            Severity::Warning => write!(f, "WARN"),
+            Severity::Error => write!(f, "ERROR"),
+        }
+    }
+}
+
+/// An issue with a config found by [`Validator::lint`][lint].
+///
+/// [lint]: ./struct.Validator.html#method.lint
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LintIssue {
+    /// The config breaks one of the rules checked by [`Config::validate`][validate].
+    ///
+    /// [validate]: ../struct.Config.html#method.validate
+    Invalid { reason: String },
+    /// The source with this key has an absolute path, so the config won't work on anyone else's machine.
+    AbsoluteSourcePath { key: String, path: String },
+    /// The destination location for this key is an absolute path, so the files would be copied outside the
+    /// destination folder.
+    AbsoluteDestPath { key: String, path: String },
+    /// `destination.name` can't be filled in with the template variables.
+    InvalidTemplate { reason: String },
+    /// A likely mistake found by [`Validator::validate_with_fs`][validate].
+    ///
+    /// [validate]: ./struct.Validator.html#method.validate_with_fs
+    Validation(ValidationWarning),
+    /// `destination.name` doesn't include `{username}`, which most submissions are expected to.
+    NameWithoutUsername,
+    /// The destination folder isn't archived, even though submissions are usually a single archive.
+    ArchiveDisabled,
+}
+
+impl LintIssue {
+    /// How serious this issue is.
+    pub fn severity(&self) -> Severity {
+        match *self {
+            LintIssue::Invalid { .. }
+            | LintIssue::AbsoluteDestPath { .. }
+            | LintIssue::InvalidTemplate { .. } => Severity::Error,
+            LintIssue::AbsoluteSourcePath { .. }
+            | LintIssue::Validation(_)
+            | LintIssue::NameWithoutUsername => Severity::Warning,
+            LintIssue::ArchiveDisabled => Severity::Info,
+        }
+    }
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LintIssue::Invalid { ref reason } => write!(f, "{}", reason),
+            LintIssue::AbsoluteSourcePath { ref key, ref path } => write!(
+                f,
+                "Source `{}` has the absolute path {}, which won't exist on other machines",
+                key, path
+            ),
+            LintIssue::AbsoluteDestPath { ref key, ref path } => write!(
+                f,
+                "Location for `{}` is the absolute path {}, which is outside the destination folder",
+                key, path
+            ),
+            LintIssue::InvalidTemplate { ref reason } => {
+                write!(f, "Could not fill in destination.name: {}", reason)
+            }
+            LintIssue::Validation(ref warning) => write!(f, "{}", warning),
+            LintIssue::NameWithoutUsername => write!(
+                f,
+                "destination.name doesn't include {{username}}, so submissions can't be told apart by name"
+            ),
+            LintIssue::ArchiveDisabled => write!(
+                f,
+                "Archiving is turned off, which is unusual for a submission (set `archive = \"zip\"` to turn it on)"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that sources overlapping through a symlink are found, along with the ones that overlap by name.
+    #[cfg(unix)]
+    #[test]
+    fn overlapping_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src/main")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("src/main"), dir.path().join("linked")).unwrap();
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            linked = { path = "linked" }
+            main = { path = "src/main" }
+            src = { path = "src" }
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+        "#,
+        )
+        .unwrap();
+
+        let warnings = Validator::new(&config).validate_with_fs(dir.path());
+        assert_eq!(
+            warnings,
+            vec![
+                ValidationWarning::OverlappingSourcePaths {
+                    outer: "src".to_string(),
+                    inner: "main".to_string(),
+                },
+                ValidationWarning::OverlappingSourcePaths {
+                    outer: "linked".to_string(),
+                    inner: "main".to_string(),
+                },
+                ValidationWarning::OverlappingSourcePaths {
+                    outer: "src".to_string(),
+                    inner: "linked".to_string(),
+                },
+            ]
+        );
+    }
+
+    /// Test that lint finds issues of every severity, most severe first.
+    #[test]
+    fn lint() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src/main")).unwrap();
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            main = { path = "src/main" }
+            src = { path = "src" }
+            lib = "/usr/lib/libfoo.so"
+
+            [destination]
+            name = "coursework-{unit}"
+            archive = false
+
+            [destination.locations]
+            lib = { file = "/tmp/libfoo.so" }
+        "#,
+        )
+        .unwrap();
+
+        let issues = Validator::new(&config).lint(dir.path());
+        let severities: Vec<Severity> = issues.iter().map(LintIssue::severity).collect();
+        assert_eq!(
+            severities,
+            vec![
+                Severity::Error,
+                Severity::Error,
+                Severity::Warning,
+                Severity::Warning,
+                Severity::Warning,
+                Severity::Info,
+            ]
+        );
+        assert_eq!(
+            issues[0],
+            LintIssue::AbsoluteDestPath {
+                key: "lib".to_string(),
+                path: "/tmp/libfoo.so".to_string(),
+            }
+        );
+        assert!(matches!(issues[1], LintIssue::InvalidTemplate { .. }));
+        assert_eq!(
+            issues[2],
+            LintIssue::AbsoluteSourcePath {
+                key: "lib".to_string(),
+                path: "/usr/lib/libfoo.so".to_string(),
+            }
+        );
+        assert_eq!(
+            issues[3],
+            LintIssue::Validation(ValidationWarning::OverlappingSourcePaths {
+                outer: "src".to_string(),
+                inner: "main".to_string(),
+            })
+        );
+        assert_eq!(issues[4], LintIssue::NameWithoutUsername);
+        assert_eq!(issues[5], LintIssue::ArchiveDisabled);
+    }
+
+    /// Test that every problem is reported at once, and that a consistent config passes.
+    #[test]
+    fn report_all() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            code = { path = "src", pattern = "*.java" }
+            report = "report.pdf"
+
+            [destination]
+            name = "coursework-{unit}"
+            archive = "zip"
+
+            [destination.locations]
+            code = "src"
+            notes = "."
+        "#;
+
+        let config = Config::parse(toml_str).unwrap();
+        let errors = Validator::new(&config).report_all().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors[0],
+            ValidationError::MissingDestLocation {
+                key: "report".to_string()
+            }
+        );
+        assert_eq!(
+            errors[1],
+            ValidationError::MissingSourceEntry {
+                key: "notes".to_string()
+            }
+        );
+        assert!(matches!(
+            errors[2],
+            ValidationError::InvalidDestinationFormat { .. }
+        ));
+
+        let config = Config::parse(
+            toml_str
+                .replace("{unit}", "{username}")
+                .replace("notes = \".\"", "report = \".\""),
+        )
+        .unwrap();
+        assert_eq!(Validator::new(&config).report_all(), Ok(()));
+    }
+
+    /// Test that relative source paths and locations that contain `..` are reported, but absolute source paths aren't.
+    #[test]
+    fn path_traversal() {
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            etc = { path = "../../etc", pattern = "*" }
+            passwd = "/etc/passwd"
+            code = { path = "src/./main", pattern = "*.rs" }
+
+            [destination]
+            name = "test-{username}"
+            archive = "zip"
+
+            [destination.locations]
+            etc = "."
+            passwd = { file = "../passwd" }
+            code = "src"
+        "#,
+        )
+        .unwrap();
+
+        let errors = Validator::new(&config).report_all().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::PathTraversal {
+                    path: PathBuf::from("../../etc"),
+                },
+                ValidationError::PathTraversal {
+                    path: PathBuf::from("../passwd"),
+                },
+            ]
+        );
+    }
+}
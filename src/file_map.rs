@@ -0,0 +1,6908 @@
+//
+//  file_map.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Building and executing the mapping of source files to their destinations.
+
+use crate::checksum::{ChecksumAlgorithm, HashingReader, Manifest};
+use crate::config::{self, ArchiveMode, Config, DestLoc, OverwritePolicy, SortOrder, Source};
+use crate::join_paths;
+use crate::logger::{LogEvent, Logger};
+use crate::verbosity::Verbosity;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use glob::{glob_with, MatchOptions, Pattern};
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use strfmt::strfmt;
+use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A list of source files and the destination each one should be copied to.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileMap {
+    /// The directory that source paths were resolved relative to.
+    root_dir: PathBuf,
+    /// The folder that all files will be copied into.
+    dest_dir: PathBuf,
+    /// Whether to archive the destination folder once the files have been copied, and in which format.
+    archive: ArchiveMode,
+    /// Where to write the archive, if not alongside the destination folder.
+    #[serde(default)]
+    archive_path: Option<PathBuf>,
+    /// What to do if the destination folder already exists.
+    #[serde(default)]
+    overwrite: OverwritePolicy,
+    /// Whether to write a manifest of checksums into the destination folder.
+    manifest: bool,
+    /// The hash algorithm used for the manifest.
+    checksum_algorithm: ChecksumAlgorithm,
+    /// A shell command to run in the destination folder once the files have been packaged.
+    post_copy: Option<String>,
+    /// How hard to compress the archive, from 0 to 9, if not the default.
+    #[serde(default)]
+    compression_level: Option<u32>,
+    /// The comment to store in the archive, if it is a ZIP archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archive_comment: Option<String>,
+    /// Where to write temporary files, if not alongside the file they are for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temp_dir: Option<PathBuf>,
+    /// Where to record the operations performed by `execute`, if anywhere.
+    #[serde(skip)]
+    logger: Option<Logger>,
+    /// The modification time to give the archive entries of source files from folders with `mtime_from`, instead of
+    /// their own.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    mtimes: BTreeMap<PathBuf, SystemTime>,
+    /// Anything found while building the map that the user should know about.
+    #[serde(skip)]
+    warnings: Vec<FileMapWarning>,
+    /// Pairs of source and destination paths.
+    map: Vec<(PathBuf, PathBuf)>,
+}
+
+impl FileMap {
+    /// The directory that source paths were resolved relative to.
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// The folder that all files will be copied into.
+    pub fn dest_dir(&self) -> &Path {
+        &self.dest_dir
+    }
+
+    /// Whether the destination folder will be archived once the files have been copied.
+    pub fn archive_enabled(&self) -> bool {
+        self.archive.is_enabled()
+    }
+
+    /// Whether the destination folder will be archived once the files have been copied, and in which format.
+    pub fn archive_mode(&self) -> ArchiveMode {
+        self.archive
+    }
+
+    /// Anything found while building the map that the user should know about, but that didn't stop it being built.
+    pub fn warnings(&self) -> &[FileMapWarning] {
+        &self.warnings
+    }
+
+    /// Return a new `FileMap` containing only the pairs for which `predicate(source, dest)` returns `true`.
+    pub fn filter<F>(&self, predicate: F) -> FileMap
+    where
+        F: Fn(&Path, &Path) -> bool,
+    {
+        FileMap {
+            root_dir: self.root_dir.clone(),
+            dest_dir: self.dest_dir.clone(),
+            archive: self.archive,
+            archive_path: self.archive_path.clone(),
+            overwrite: self.overwrite,
+            manifest: self.manifest,
+            checksum_algorithm: self.checksum_algorithm,
+            post_copy: self.post_copy.clone(),
+            compression_level: self.compression_level,
+            archive_comment: self.archive_comment.clone(),
+            temp_dir: self.temp_dir.clone(),
+            logger: self.logger.clone(),
+            mtimes: self.mtimes.clone(),
+            warnings: self.warnings.clone(),
+            map: self
+                .map
+                .iter()
+                .filter(|(src, dst)| predicate(src, dst))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Return a new `FileMap` in which every destination is moved under the folder `prefix` inside the destination
+    /// folder, so that the output of several maps can be kept apart in the same folder or archive. Fails with
+    /// [`FileMapError::InvalidPrefix`][invalid] if `prefix` is absolute or contains `..`, since the files would then
+    /// end up outside the destination folder.
+    ///
+    /// [invalid]: ./enum.FileMapError.html#variant.InvalidPrefix
+    pub fn with_prefix<P>(&self, prefix: P) -> Result<FileMap>
+    where
+        P: AsRef<Path>,
+    {
+        let prefix = prefix.as_ref();
+
+        if !is_plain_relative(prefix) {
+            return Err(FileMapError::InvalidPrefix {
+                prefix: prefix.to_path_buf(),
+            });
+        }
+
+        let mut prefixed = self.clone();
+
+        for (_, dst) in &mut prefixed.map {
+            let relative = dst.strip_prefix(&self.dest_dir).unwrap_or(dst);
+            *dst = join_paths(&self.dest_dir, [prefix, relative]);
+        }
+
+        Ok(prefixed)
+    }
+
+    /// Return a new `FileMap` in which the folders `prefix` are removed from the start of every destination, relative
+    /// to the destination folder, e.g. so that `submission/ab1234/src/a.rs` becomes `src/a.rs`. Destinations that don't
+    /// start with `prefix` are left where they are.
+    pub fn with_stripped_prefix<P>(&self, prefix: P) -> FileMap
+    where
+        P: AsRef<Path>,
+    {
+        let mut stripped = self.clone();
+
+        for (_, dst) in &mut stripped.map {
+            let relative = dst.strip_prefix(&self.dest_dir).unwrap_or(dst);
+
+            if let Ok(remainder) = relative.strip_prefix(prefix.as_ref()) {
+                *dst = join_paths(&self.dest_dir, [remainder]);
+            }
+        }
+
+        stripped
+    }
+
+    /// Return a new `FileMap` that writes its archive to `archive_path`, resolved relative to the root directory,
+    /// instead of the location given in the config.
+    pub fn with_archive_path<P>(&self, archive_path: P) -> FileMap
+    where
+        P: AsRef<Path>,
+    {
+        FileMap {
+            archive_path: Some(join_paths(&self.root_dir, [archive_path.as_ref()])),
+            ..self.clone()
+        }
+    }
+
+    /// A Makefile, to be run from the root directory, with a `pack` target that copies every file to its destination
+    /// with `cp` (and archives the destination folder with `zip` or `tar`, if archiving is enabled), and a `clean`
+    /// target that removes the destination folder and archive. This lets the files be packaged without bathpack.
+    pub fn to_makefile(&self) -> String {
+        let relative = |path: &Path| -> String {
+            makefile_quote(path.strip_prefix(&self.root_dir).unwrap_or(path))
+        };
+
+        let dest_dir = relative(&self.dest_dir);
+        let archive_path = relative(&self.archive_path());
+        let mut makefile =
+            String::from("# Generated by bathpack. Run `make pack` from this folder.\n\n");
+        makefile.push_str(".PHONY: pack clean\n\npack:\n");
+
+        let mut dirs = BTreeSet::new();
+
+        for (src, dst) in &self.map {
+            if let Some(parent) = dst.parent() {
+                if dirs.insert(parent) {
+                    makefile.push_str(&format!("\tmkdir -p {}\n", relative(parent)));
+                }
+            }
+
+            makefile.push_str(&format!("\tcp {} {}\n", relative(src), relative(dst)));
+        }
+
+        // Archive from the destination folder's parent, so that entries start with the folder's name, as they do in
+        // archives written by bathpack.
+        let parent = relative(self.dest_dir.parent().unwrap_or(&self.root_dir));
+        let name = makefile_quote(Path::new(self.dest_dir.file_name().unwrap_or_default()));
+
+        match self.archive {
+            ArchiveMode::Zip => makefile.push_str(&format!(
+                "\tcd {} && zip -qr \"$(CURDIR)\"/{} {}\n",
+                parent, archive_path, name
+            )),
+            ArchiveMode::TarGz => makefile.push_str(&format!(
+                "\ttar -czf {} -C {} {}\n",
+                archive_path, parent, name
+            )),
+            ArchiveMode::None => {}
+        }
+
+        makefile.push_str(&format!("\nclean:\n\trm -rf {}", dest_dir));
+
+        if self.archive.is_enabled() {
+            makefile.push_str(&format!(" {}", archive_path));
+        }
+
+        makefile.push('\n');
+        makefile
+    }
+
+    /// Compare this map with `other`, returning the pairs that were added, removed or sent to a different destination
+    /// in `other`, in order of source path. Pairs are matched up by their source path. A source copied to several
+    /// destinations is only reported for the destinations that differ, with each one that disappeared paired with one
+    /// that appeared as a change, and any left over reported as added or removed.
+    pub fn diff(&self, other: &FileMap) -> FileDiff {
+        let old = destinations_by_source(&self.map);
+        let new = destinations_by_source(&other.map);
+        let none = BTreeSet::new();
+
+        let mut diff = FileDiff::default();
+
+        for (src, new_dsts) in &new {
+            let old_dsts = old.get(src).unwrap_or(&none);
+            let mut appeared = new_dsts.difference(old_dsts);
+            let mut disappeared = old_dsts.difference(new_dsts);
+
+            loop {
+                match (disappeared.next(), appeared.next()) {
+                    (Some(old_dst), Some(new_dst)) => diff.changed.push((
+                        src.to_path_buf(),
+                        old_dst.to_path_buf(),
+                        new_dst.to_path_buf(),
+                    )),
+                    (Some(old_dst), None) => diff
+                        .removed
+                        .push((src.to_path_buf(), old_dst.to_path_buf())),
+                    (None, Some(new_dst)) => {
+                        diff.added.push((src.to_path_buf(), new_dst.to_path_buf()))
+                    }
+                    (None, None) => break,
+                }
+            }
+        }
+
+        for (src, old_dsts) in &old {
+            if !new.contains_key(src) {
+                for dst in old_dsts {
+                    diff.removed.push((src.to_path_buf(), dst.to_path_buf()));
+                }
+            }
+        }
+
+        diff
+    }
+
+    /// Copy every source file to its destination, creating any folders that are needed along the way, and then create
+    /// the archive if one was requested.
+    pub fn execute(&self) -> Result<()> {
+        self.execute_with(&ExecuteOptions::default())
+    }
+
+    /// Like [`execute`][execute], but with the behaviour adjusted by `options`.
+    ///
+    /// [execute]: #method.execute
+    pub fn execute_with(&self, options: &ExecuteOptions) -> Result<()> {
+        let mut journal = CopyJournal::default();
+        let result = self.execute_journaled(options, &mut journal);
+
+        result.and(journal.discard().map_err(FileMapError::from))
+    }
+
+    /// Like [`execute_with`][execute_with], but recording every file that is created or overwritten in `journal`, so
+    /// that the changes can be undone with [`rollback`][rollback] if something goes wrong partway through. The journal
+    /// is filled in even if this returns an error.
+    ///
+    /// [execute_with]: #method.execute_with
+    /// [rollback]: #method.rollback
+    pub fn execute_journaled(
+        &self,
+        options: &ExecuteOptions,
+        journal: &mut CopyJournal,
+    ) -> Result<()> {
+        self.check_overwrite()?;
+
+        let start = Instant::now();
+
+        self.log(LogEvent::Start {
+            dest: self.dest_dir.clone(),
+            files: self.map.len(),
+        })?;
+
+        let mut copies = Vec::new();
+
+        for (i, (src, dst)) in self.map.iter().enumerate() {
+            let copy_start = Instant::now();
+
+            self.record_write(journal, dst)?;
+
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            check_simulated_failure(options, i + 1)?;
+
+            let bytes = fs::copy(src, dst)?;
+
+            self.log(LogEvent::Copy {
+                src: src.clone(),
+                dst: dst.clone(),
+                bytes,
+                duration_ms: copy_start.elapsed().as_millis() as u64,
+            })?;
+
+            if options.copy_log {
+                copies.push(self.copy_record(src, dst, bytes)?);
+            }
+
+            Verbosity::VERBOSE.print(format_args!(
+                "Copied {} → {} ({:.2?})",
+                src.display(),
+                dst.display(),
+                copy_start.elapsed()
+            ));
+        }
+
+        self.finish_execution(options, journal, &copies, start)
+    }
+
+    /// Copy the files using `jobs` threads at once, sending a [`ProgressEvent`][progress] to `tx` before and after each
+    /// copy, so that the caller can show progress however it likes. Once every file has been copied, the copy log,
+    /// manifest and archive are written and the `post_copy` hook is run, as for [`execute_with`][execute_with]. If
+    /// anything fails, the threads stop taking on new files, every file written so far is rolled back as by
+    /// [`rollback`][rollback], and the first error is returned. Events that can't be sent because the receiver has been
+    /// dropped are ignored.
+    ///
+    /// [progress]: ./enum.ProgressEvent.html
+    /// [execute_with]: #method.execute_with
+    /// [rollback]: #method.rollback
+    pub fn execute_parallel_with_progress(
+        &self,
+        jobs: usize,
+        options: &ExecuteOptions,
+        tx: Sender<ProgressEvent>,
+    ) -> Result<ExecuteResult> {
+        self.check_overwrite()?;
+
+        let start = Instant::now();
+
+        self.log(LogEvent::Start {
+            dest: self.dest_dir.clone(),
+            files: self.map.len(),
+        })?;
+
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let bytes = AtomicU64::new(0);
+        let first_error = Mutex::new(None);
+        let journal = Mutex::new(CopyJournal::default());
+        let copies = Mutex::new(Vec::new());
+
+        thread::scope(|scope| {
+            for _ in 0..jobs.max(1) {
+                let tx = tx.clone();
+                let (next, failed, bytes, first_error) = (&next, &failed, &bytes, &first_error);
+                let (journal, copies) = (&journal, &copies);
+
+                scope.spawn(move || {
+                    while !failed.load(Ordering::SeqCst) {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        let (src, dst) = match self.map.get(i) {
+                            Some(pair) => pair,
+                            None => break,
+                        };
+
+                        let _ = tx.send(ProgressEvent::Started(src.clone(), dst.clone()));
+                        let copy_start = Instant::now();
+
+                        match self.copy_one(options, journal, i + 1, src, dst) {
+                            Ok((copied, record)) => {
+                                if let Some(record) = record {
+                                    copies
+                                        .lock()
+                                        .unwrap_or_else(PoisonError::into_inner)
+                                        .push((i, record));
+                                }
+
+                                bytes.fetch_add(copied, Ordering::SeqCst);
+                                let _ = tx.send(ProgressEvent::Completed {
+                                    src: src.clone(),
+                                    dst: dst.clone(),
+                                    bytes: copied,
+                                    duration: copy_start.elapsed(),
+                                });
+                            }
+                            Err(err) => {
+                                failed.store(true, Ordering::SeqCst);
+                                let _ = tx.send(ProgressEvent::Failed {
+                                    src: src.clone(),
+                                    err: err.to_string(),
+                                });
+                                first_error
+                                    .lock()
+                                    .unwrap_or_else(PoisonError::into_inner)
+                                    .get_or_insert(err);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut journal = journal.into_inner().unwrap_or_else(PoisonError::into_inner);
+
+        let result = match first_error
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some(err) => Err(err),
+            None => {
+                // The threads finish their copies in any order, so put the records back in the order of the map.
+                let mut copies = copies.into_inner().unwrap_or_else(PoisonError::into_inner);
+                copies.sort_by_key(|&(i, _)| i);
+                let copies: Vec<CopyRecord> =
+                    copies.into_iter().map(|(_, record)| record).collect();
+
+                self.finish_execution(options, &mut journal, &copies, start)
+            }
+        };
+
+        if let Err(err) = result {
+            self.rollback(&journal)?;
+            journal.discard()?;
+            self.log(LogEvent::Failed {
+                error: err.to_string(),
+                duration_ms: start.elapsed().as_millis() as u64,
+            })?;
+
+            return Err(err);
+        }
+
+        journal.discard()?;
+
+        Ok(ExecuteResult {
+            files: self.map.len(),
+            bytes: bytes.into_inner(),
+            duration: start.elapsed(),
+        })
+    }
+
+    /// Copy `src`, the `n`th file in the map counting from 1, to `dst`, recording the write in `journal`, creating any
+    /// folders that are needed and logging the copy. Returns the number of bytes copied, along with the copy's
+    /// [`CopyRecord`][copy_record] if `options.copy_log` is set.
+    ///
+    /// [copy_record]: ./struct.CopyRecord.html
+    fn copy_one(
+        &self,
+        options: &ExecuteOptions,
+        journal: &Mutex<CopyJournal>,
+        n: usize,
+        src: &Path,
+        dst: &Path,
+    ) -> Result<(u64, Option<CopyRecord>)> {
+        let copy_start = Instant::now();
+
+        self.record_write(
+            &mut journal.lock().unwrap_or_else(PoisonError::into_inner),
+            dst,
+        )?;
+
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        check_simulated_failure(options, n)?;
+
+        let bytes = fs::copy(src, dst)?;
+
+        self.log(LogEvent::Copy {
+            src: src.to_path_buf(),
+            dst: dst.to_path_buf(),
+            bytes,
+            duration_ms: copy_start.elapsed().as_millis() as u64,
+        })?;
+
+        let record = if options.copy_log {
+            Some(self.copy_record(src, dst, bytes)?)
+        } else {
+            None
+        };
+
+        Ok((bytes, record))
+    }
+
+    /// Everything that happens after the files have been copied: writing the copy log and manifest, if they are wanted,
+    /// creating the archive, and running the `post_copy` hook. Every file written is recorded in `journal`.
+    fn finish_execution(
+        &self,
+        options: &ExecuteOptions,
+        journal: &mut CopyJournal,
+        copies: &[CopyRecord],
+        start: Instant,
+    ) -> Result<()> {
+        let mut archived = self.clone();
+
+        if options.copy_log {
+            let copy_log_path = join_paths(&self.dest_dir, [COPY_LOG_FILE]);
+            self.record_write(journal, &copy_log_path)?;
+            fs::create_dir_all(&self.dest_dir)?;
+            write_copy_log(&copy_log_path, copies)?;
+            Verbosity::VERBOSE.print(format_args!("Wrote copy log {}", copy_log_path.display()));
+            archived.map.push((copy_log_path.clone(), copy_log_path));
+        }
+
+        if self.manifest || options.manifest {
+            let algorithm = options
+                .checksum_algorithm
+                .unwrap_or(self.checksum_algorithm);
+            self.record_write(journal, &self.manifest_path(algorithm))?;
+            let manifest_path = self.write_manifest(algorithm)?;
+            Verbosity::VERBOSE.print(format_args!("Wrote manifest {}", manifest_path.display()));
+            archived.map.push((manifest_path.clone(), manifest_path));
+        }
+
+        if self.archive.is_enabled() {
+            let archive_start = Instant::now();
+            let archive_path = self.archive_path();
+            self.record_write(journal, &archive_path)?;
+
+            if let Some(parent) = archive_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            if options.append && archive_path.exists() {
+                archived.append_archive(&archive_path)?;
+            } else {
+                archived.create_archive(&archive_path)?;
+            }
+
+            Verbosity::VERBOSE.print(format_args!(
+                "Wrote archive {} ({:.2?})",
+                archive_path.display(),
+                archive_start.elapsed()
+            ));
+        }
+
+        if let Some(ref command) = self.post_copy {
+            if options.skip_hooks {
+                Verbosity::VERBOSE.print(format_args!("Skipped post-copy hook `{}`", command));
+            } else {
+                self.run_hook(command, &self.dest_dir)?;
+            }
+        }
+
+        Verbosity::VERBOSE.print(format_args!(
+            "Packaged {} files in {:.2?}",
+            self.map.len(),
+            start.elapsed()
+        ));
+
+        self.log(LogEvent::End {
+            files: self.map.len(),
+            duration_ms: start.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Write the archive straight from the source files, without copying them into the destination folder first, so
+    /// that the destination folder is never created. If a manifest is enabled, it is put in the archive but not on
+    /// disk, and the `post_copy` hook is skipped, since there is no destination folder to run it in. Returns a
+    /// [`FileMapWarning::HookSkipped`][hook_skipped] if there was a hook to skip.
+    ///
+    /// [hook_skipped]: ./enum.FileMapWarning.html#variant.HookSkipped
+    pub fn execute_into_archive(&self, options: &ExecuteOptions) -> Result<Vec<FileMapWarning>> {
+        if !self.archive.is_enabled() {
+            return Err(FileMapError::ArchiveOnlyUnsupported);
+        }
+
+        let start = Instant::now();
+
+        self.log(LogEvent::Start {
+            dest: self.archive_path(),
+            files: self.map.len(),
+        })?;
+
+        let mut archived = self.clone();
+        let mut staged_manifest = None;
+
+        if self.manifest || options.manifest {
+            let algorithm = options
+                .checksum_algorithm
+                .unwrap_or(self.checksum_algorithm);
+
+            // Entries are read from their source, so the manifest needs a source outside the destination folder.
+            let staged = join_paths(self.staging_dir(), [format!("MANIFEST.{}", algorithm)]);
+            fs::create_dir_all(staged.parent().unwrap_or(&self.root_dir))?;
+            fs::write(&staged, self.manifest_contents(algorithm)?)?;
+
+            archived
+                .map
+                .push((staged.clone(), self.manifest_path(algorithm)));
+            staged_manifest = Some(staged);
+        }
+
+        let archive_path = self.archive_path();
+
+        if let Some(parent) = archive_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let result = if options.append && archive_path.exists() {
+            archived.append_archive(&archive_path)
+        } else {
+            archived.create_archive(&archive_path)
+        };
+
+        if let Some(staged) = staged_manifest {
+            fs::remove_file(staged)?;
+        }
+
+        result?;
+
+        let mut warnings = Vec::new();
+
+        if let Some(ref command) = self.post_copy {
+            warnings.push(FileMapWarning::HookSkipped {
+                command: command.clone(),
+            });
+        }
+
+        Verbosity::VERBOSE.print(format_args!(
+            "Archived {} files into {} in {:.2?}",
+            self.map.len(),
+            archive_path.display(),
+            start.elapsed()
+        ));
+
+        self.log(LogEvent::End {
+            files: self.map.len(),
+            duration_ms: start.elapsed().as_millis() as u64,
+        })?;
+
+        Ok(warnings)
+    }
+
+    /// Copy every source file to its destination like [`execute`][execute], but hash each source file as it is
+    /// copied, then read the copy back and check that its hash matches. Nothing is archived, no manifest is written and
+    /// no hooks are run. A file whose copy doesn't match is reported with `verified: false` rather than as an error, so
+    /// that the result covers every file. If a copy fails, the files written so far are rolled back.
+    ///
+    /// [execute]: #method.execute
+    pub fn execute_with_verify(&self) -> Result<VerifiedExecution> {
+        self.check_overwrite()?;
+
+        let start = Instant::now();
+
+        self.log(LogEvent::Start {
+            dest: self.dest_dir.clone(),
+            files: self.map.len(),
+        })?;
+
+        let mut journal = CopyJournal::default();
+        let mut pairs = Vec::with_capacity(self.map.len());
+
+        for (src, dst) in &self.map {
+            match self.copy_verified(&mut journal, src, dst) {
+                Ok(pair) => pairs.push(pair),
+                Err(err) => {
+                    self.rollback(&journal)?;
+                    journal.discard()?;
+                    return Err(err);
+                }
+            }
+        }
+
+        journal.discard()?;
+
+        self.log(LogEvent::End {
+            files: self.map.len(),
+            duration_ms: start.elapsed().as_millis() as u64,
+        })?;
+
+        Ok(VerifiedExecution { pairs })
+    }
+
+    /// Copy `src` to `dst` for [`execute_with_verify`][execute_with_verify], recording the write in `journal`, hashing
+    /// the source as it is copied and then checking the copy against it.
+    ///
+    /// [execute_with_verify]: #method.execute_with_verify
+    fn copy_verified(
+        &self,
+        journal: &mut CopyJournal,
+        src: &Path,
+        dst: &Path,
+    ) -> Result<VerifiedPair> {
+        let copy_start = Instant::now();
+
+        self.record_write(journal, dst)?;
+
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut reader = HashingReader::new(File::open(src)?);
+        let bytes = io::copy(&mut reader, &mut File::create(dst)?)?;
+        let checksum = reader.finalize();
+        let verified = sha256_digest(dst)? == checksum;
+
+        self.log(LogEvent::Copy {
+            src: src.to_path_buf(),
+            dst: dst.to_path_buf(),
+            bytes,
+            duration_ms: copy_start.elapsed().as_millis() as u64,
+        })?;
+
+        Verbosity::VERBOSE.print(format_args!(
+            "Copied {} → {} ({})",
+            src.display(),
+            dst.display(),
+            if verified {
+                "verified"
+            } else {
+                "does not match"
+            }
+        ));
+
+        Ok(VerifiedPair {
+            src: src.to_path_buf(),
+            dst: dst.to_path_buf(),
+            bytes,
+            checksum,
+            verified,
+        })
+    }
+
+    /// Check that every destination file has the same SHA-256 digest as its source, once the map has been executed,
+    /// failing with [`FileMapError::IntegrityFailure`][integrity] for the first one that doesn't. The digests are
+    /// computed the same way as by [`execute_with_verify`][execute_with_verify].
+    ///
+    /// [integrity]: ./enum.FileMapError.html#variant.IntegrityFailure
+    /// [execute_with_verify]: #method.execute_with_verify
+    pub fn verify_integrity(&self) -> Result<()> {
+        for (src, dst) in &self.map {
+            if sha256_digest(dst)? != sha256_digest(src)? {
+                return Err(FileMapError::IntegrityFailure {
+                    source: src.clone(),
+                    dest: dst.clone(),
+                });
+            }
+
+            Verbosity::VERBOSE.print(format_args!("Verified {}", dst.display()));
+        }
+
+        Ok(())
+    }
+
+    /// Undo the changes recorded in `journal` by a call to [`execute_journaled`][execute_journaled]: files that were
+    /// created are deleted, along with any folders created to hold them, and files that were overwritten are restored
+    /// from their backups.
+    ///
+    /// [execute_journaled]: #method.execute_journaled
+    pub fn rollback(&self, journal: &CopyJournal) -> Result<()> {
+        for path in journal.created.iter().rev() {
+            match fs::remove_file(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+
+            Verbosity::VERBOSE.print(format_args!("Removed {}", path.display()));
+        }
+
+        for (path, backup) in journal.overwritten.iter().rev() {
+            fs::copy(backup, path)?;
+            Verbosity::VERBOSE.print(format_args!("Restored {}", path.display()));
+        }
+
+        for dir in journal.created_dirs.iter().rev() {
+            // Leave the folder alone if something else has been put in it since.
+            let _ = fs::remove_dir(dir);
+        }
+
+        Ok(())
+    }
+
+    /// Like [`execute`][execute], but first moving every destination file that already exists into `backup_dir`, at the
+    /// same path relative to it as it had relative to the destination folder. If anything goes wrong after that, the
+    /// files that were written are removed and the backups are moved back, so that the destination folder is left as
+    /// it was. Otherwise, the backups are left in `backup_dir`. Fails with
+    /// [`FileMapError::BackupFailed`][backup_failed] if a file can't be backed up, after putting back any that were.
+    ///
+    /// [execute]: #method.execute
+    /// [backup_failed]: ./enum.FileMapError.html#variant.BackupFailed
+    pub fn execute_with_backup(&self, backup_dir: &Path) -> Result<()> {
+        let mut backups = Vec::new();
+
+        for (_, dst) in &self.map {
+            if !dst.is_file() {
+                continue;
+            }
+
+            let relative = dst.strip_prefix(&self.dest_dir).unwrap_or(dst);
+            let backup = join_paths(backup_dir, [relative]);
+
+            if let Err(err) = move_file(dst, &backup) {
+                restore_backups(&backups)?;
+                return Err(FileMapError::BackupFailed {
+                    path: dst.clone(),
+                    err,
+                });
+            }
+
+            Verbosity::VERBOSE.print(format_args!(
+                "Backed up {} to {}",
+                dst.display(),
+                backup.display()
+            ));
+            backups.push((dst.clone(), backup));
+        }
+
+        let mut journal = CopyJournal::default();
+
+        if let Err(err) = self.execute_journaled(&ExecuteOptions::default(), &mut journal) {
+            self.rollback(&journal)?;
+            restore_backups(&backups)?;
+            return Err(err);
+        }
+
+        journal.discard().map_err(FileMapError::from)
+    }
+
+    /// Record in `journal` that `path` is about to be written. If it already exists, a backup is made first so that it
+    /// can be restored; otherwise, it and any of its parent folders that don't exist yet are recorded as created.
+    fn record_write(&self, journal: &mut CopyJournal, path: &Path) -> Result<()> {
+        if path.exists() {
+            let backup_dir = join_paths(&self.root_dir, [BACKUP_DIR]);
+            fs::create_dir_all(&backup_dir)?;
+
+            let backup = join_paths(backup_dir, [journal.overwritten.len().to_string()]);
+            fs::copy(path, &backup)?;
+            journal.overwritten.push((path.to_path_buf(), backup));
+        } else {
+            let mut missing: Vec<PathBuf> = path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                .map(Path::to_path_buf)
+                .collect();
+            missing.reverse();
+
+            journal.created_dirs.extend(missing);
+            journal.created.push(path.to_path_buf());
+        }
+
+        Ok(())
+    }
+
+    /// Substitute `{archive_path}`, `{dest_dir}` and `{file_count}` into `command`, then run it with `sh -c` in the
+    /// root directory, failing if it exits unsuccessfully. Unlike the `post_copy` hook, this runs in the root
+    /// directory, since the destination folder doesn't exist if only the archive was written.
+    pub fn run_after(&self, command: &str) -> Result<()> {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "archive_path".to_string(),
+            self.archive_path().display().to_string(),
+        );
+        vars.insert("dest_dir".to_string(), self.dest_dir.display().to_string());
+        vars.insert("file_count".to_string(), self.map.len().to_string());
+
+        self.run_hook(&strfmt(command, &vars)?, &self.root_dir)
+    }
+
+    /// Run `command` with `sh -c` in `dir`, failing if it exits unsuccessfully.
+    fn run_hook(&self, command: &str, dir: &Path) -> Result<()> {
+        let hook_start = Instant::now();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(dir)
+            .status()?;
+
+        Verbosity::VERBOSE.print(format_args!(
+            "Ran hook `{}` ({:.2?})",
+            command,
+            hook_start.elapsed()
+        ));
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(FileMapError::HookFailed {
+                // A process killed by a signal has no exit code.
+                exit_code: status.code().unwrap_or(-1),
+                command: command.to_string(),
+            })
+        }
+    }
+
+    /// Apply the overwrite policy if the destination folder already exists, returning an error if nothing should be
+    /// copied into it.
+    fn check_overwrite(&self) -> Result<()> {
+        if !self.dest_dir.exists() {
+            return Ok(());
+        }
+
+        let proceed = match self.overwrite {
+            OverwritePolicy::Overwrite => true,
+            OverwritePolicy::Skip => false,
+            OverwritePolicy::Ask => {
+                print!(
+                    "Destination folder {} already exists. Copy into it anyway? [y/N] ",
+                    self.dest_dir.display()
+                );
+                io::stdout().flush()?;
+
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+
+                matches!(answer.trim(), "y" | "Y" | "yes")
+            }
+        };
+
+        if proceed {
+            Ok(())
+        } else {
+            Err(FileMapError::DestinationExists {
+                path: self.dest_dir.clone(),
+            })
+        }
+    }
+
+    /// Whether `path` is one of the source files in the map.
+    pub fn contains_source_path(&self, path: &Path) -> bool {
+        self.map.iter().any(|(src, _)| src == path)
+    }
+
+    /// Whether `path` is one of the destinations in the map.
+    pub fn contains_dest_path(&self, path: &Path) -> bool {
+        self.map.iter().any(|(_, dst)| dst == path)
+    }
+
+    /// The number of source files in the map.
+    pub fn source_count(&self) -> usize {
+        self.map.len()
+    }
+
+    /// The number of pairs of source and destination paths in the map.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether there are no files to copy.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// How many source files will be copied into each folder, keyed by the folder's full path.
+    pub fn pair_count_by_dest_dir(&self) -> HashMap<PathBuf, usize> {
+        let mut counts = HashMap::new();
+
+        for (_, dst) in &self.map {
+            let dir = dst.parent().unwrap_or(&self.dest_dir);
+            *counts.entry(dir.to_path_buf()).or_insert(0) += 1;
+        }
+
+        counts
+    }
+
+    /// The pairs of source and destination paths, grouped by the folder each file will be copied into, keyed by the
+    /// folder's full path. Each group is in the same order as the map.
+    pub fn group_by_dest_dir(&self) -> BTreeMap<PathBuf, Vec<(PathBuf, PathBuf)>> {
+        let mut groups: BTreeMap<PathBuf, Vec<(PathBuf, PathBuf)>> = BTreeMap::new();
+
+        for (src, dst) in &self.map {
+            let dir = dst.parent().unwrap_or(&self.dest_dir);
+            groups
+                .entry(dir.to_path_buf())
+                .or_default()
+                .push((src.clone(), dst.clone()));
+        }
+
+        groups
+    }
+
+    /// The total size in bytes of the source files. Unlike the size limit check, this fails if any source file
+    /// doesn't exist.
+    pub fn total_size(&self) -> io::Result<u64> {
+        self.sum_source_sizes(false)
+    }
+
+    /// The total size in bytes of the source files. Files that don't exist are left out.
+    fn total_source_size(&self) -> io::Result<u64> {
+        self.sum_source_sizes(true)
+    }
+
+    /// The total size in bytes of the source files, leaving out files that don't exist if `skip_missing` is set, or
+    /// failing on them otherwise.
+    fn sum_source_sizes(&self, skip_missing: bool) -> io::Result<u64> {
+        let mut total = 0;
+
+        for (src, _) in &self.map {
+            match fs::metadata(src) {
+                Ok(metadata) => total += metadata.len(),
+                Err(ref e) if skip_missing && e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Write `event` to the logger, if there is one.
+    fn log(&self, event: LogEvent) -> Result<()> {
+        if let Some(ref logger) = self.logger {
+            logger.log_event(event)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a manifest to `MANIFEST.<algorithm>` in the destination folder, returning its path. The manifest starts
+    /// with a comment naming the algorithm, followed by one line per file in the same format as `sha256sum`: the
+    /// checksum, two spaces, and the file's path relative to the destination folder.
+    pub fn write_manifest(&self, algorithm: ChecksumAlgorithm) -> Result<PathBuf> {
+        let manifest_path = self.manifest_path(algorithm);
+        fs::create_dir_all(&self.dest_dir)?;
+        fs::write(&manifest_path, self.manifest_contents(algorithm)?)?;
+
+        Ok(manifest_path)
+    }
+
+    /// The contents of the manifest written by [`write_manifest`][write_manifest].
+    ///
+    /// [write_manifest]: #method.write_manifest
+    fn manifest_contents(&self, algorithm: ChecksumAlgorithm) -> Result<String> {
+        let mut manifest = Manifest {
+            algorithm,
+            entries: Vec::new(),
+        };
+
+        for (src, dst) in &self.map {
+            let relative = dst.strip_prefix(&self.dest_dir).unwrap_or(dst);
+            manifest
+                .entries
+                .push((algorithm.hash_file(src)?, entry_name(relative)));
+        }
+
+        Ok(manifest.to_string())
+    }
+
+    /// The entry in the copy log for copying `bytes` bytes from `src` to `dst`, hashing the copy that was written.
+    fn copy_record(&self, src: &Path, dst: &Path, bytes: u64) -> Result<CopyRecord> {
+        let from = src.strip_prefix(&self.root_dir).unwrap_or(src);
+        let to = dst.strip_prefix(&self.dest_dir).unwrap_or(dst);
+
+        Ok(CopyRecord {
+            from: entry_name(from),
+            to: entry_name(to),
+            bytes,
+            sha256: ChecksumAlgorithm::Sha256.hash_file(dst)?,
+        })
+    }
+
+    /// The location of the manifest written with `algorithm`.
+    fn manifest_path(&self, algorithm: ChecksumAlgorithm) -> PathBuf {
+        join_paths(&self.dest_dir, [format!("MANIFEST.{}", algorithm)])
+    }
+
+    /// Where to write the temporary file that replaces `target` once it is complete: `destination.temp_dir` if it was
+    /// given, which is created if necessary, or otherwise alongside `target`. The name is `target`'s with `.tmp` added.
+    fn temp_path(&self, target: &Path) -> Result<PathBuf> {
+        let mut name = target.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+
+        match self.temp_dir {
+            Some(ref dir) => {
+                fs::create_dir_all(dir)?;
+                Ok(join_paths(dir, [name]))
+            }
+            None => Ok(target.with_file_name(name)),
+        }
+    }
+
+    /// Where files that only go into the archive are written while it is being created: `destination.temp_dir` if it
+    /// was given, or otherwise [`STAGING_DIR`][staging] in the root directory.
+    ///
+    /// [staging]: ./constant.STAGING_DIR.html
+    fn staging_dir(&self) -> PathBuf {
+        match self.temp_dir {
+            Some(ref dir) => dir.clone(),
+            None => join_paths(&self.root_dir, [STAGING_DIR]),
+        }
+    }
+
+    /// The location of the archive: `destination.archive_path` if it was given, or otherwise a file alongside the
+    /// destination folder, with the same name and the extension of the archive format. This is where a ZIP archive
+    /// would be if archiving is disabled.
+    pub fn archive_path(&self) -> PathBuf {
+        if let Some(ref archive_path) = self.archive_path {
+            return archive_path.clone();
+        }
+
+        let mut name = self.dest_dir.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(self.archive.extension().unwrap_or("zip"));
+
+        join_paths(&self.root_dir, [name])
+    }
+
+    /// Write an archive of the files in this map, in the configured format, straight to `writer`, reading each file
+    /// from its source. Nothing is copied to the destination folder and no file is created, so `writer` can be e.g. a
+    /// network connection. ZIP archives are written in streaming mode, so `writer` doesn't need to be seekable. Returns
+    /// [`FileMapError::StreamingUnsupported`][unsupported] if archiving is turned off.
+    ///
+    /// [unsupported]: ./enum.FileMapError.html#variant.StreamingUnsupported
+    pub fn execute_streaming_archive(&self, writer: impl Write) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+
+        match self.archive {
+            ArchiveMode::Zip => {
+                let mut zip = ZipWriter::new_stream(&mut writer);
+                self.write_zip_entries(&mut zip, &BTreeSet::new())?;
+                self.set_zip_comment(&mut zip)?;
+                zip.finish()?;
+            }
+            ArchiveMode::TarGz => {
+                let encoder = GzEncoder::new(&mut writer, self.gz_compression());
+                let mut tar = tar::Builder::new(encoder);
+                self.write_tar_entries(&mut tar, &BTreeSet::new())?;
+                tar.into_inner()?.finish()?;
+            }
+            ArchiveMode::None => {
+                return Err(FileMapError::StreamingUnsupported {
+                    format: self.archive,
+                })
+            }
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// The names of the folder and file entries that make up the archive, with each file paired with the source it is
+    /// read from. All entries are inside a top-level folder with the same name as the destination folder.
+    fn archive_entries(&self) -> (BTreeSet<String>, Vec<(String, &Path)>) {
+        let top_level = Path::new(self.dest_dir.file_name().unwrap_or_default());
+
+        let mut dirs = BTreeSet::new();
+        let mut files = Vec::new();
+
+        for (src, dst) in &self.map {
+            let relative = join_paths(top_level, [dst.strip_prefix(&self.dest_dir).unwrap_or(dst)]);
+
+            for ancestor in relative.ancestors().skip(1) {
+                let name = entry_name(ancestor);
+
+                if !name.is_empty() {
+                    dirs.insert(format!("{}/", name));
+                }
+            }
+
+            files.push((entry_name(&relative), src.as_path()));
+        }
+
+        (dirs, files)
+    }
+
+    /// The options for ZIP entries at the configured compression level. Level 0 stores entries without compressing
+    /// them.
+    fn zip_options(&self) -> SimpleFileOptions {
+        match self.compression_level {
+            Some(0) => SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+            Some(level) => SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .compression_level(Some(i64::from(level))),
+            None => SimpleFileOptions::default(),
+        }
+    }
+
+    /// The gzip compression for tarballs at the configured compression level.
+    fn gz_compression(&self) -> Compression {
+        self.compression_level
+            .map_or_else(Compression::default, Compression::new)
+    }
+
+    /// Create a new archive at `archive_path` in the configured format, replacing any existing file.
+    fn create_archive(&self, archive_path: &Path) -> Result<()> {
+        match self.archive {
+            ArchiveMode::TarGz => self.create_tar_gz(archive_path),
+            ArchiveMode::Zip | ArchiveMode::None => self.create_zip(archive_path),
+        }
+    }
+
+    /// Add the files in this map to the existing archive at `archive_path`, replacing any entries with the same name.
+    /// Gzipped tarballs can't be added to, so they are rebuilt with a warning. Fails with
+    /// [`FileMapError::AppendUnsupported`][unsupported] if archiving is turned off.
+    ///
+    /// [unsupported]: ./enum.FileMapError.html#variant.AppendUnsupported
+    fn append_archive(&self, archive_path: &Path) -> Result<()> {
+        match self.archive {
+            ArchiveMode::TarGz => {
+                let warning = FileMapWarning::ArchiveRebuilt {
+                    path: archive_path.to_path_buf(),
+                    format: self.archive,
+                };
+                eprintln!("[WARN] {}", warning);
+
+                self.append_tar_gz(archive_path)
+            }
+            ArchiveMode::Zip => self.append_zip(archive_path),
+            ArchiveMode::None => Err(FileMapError::AppendUnsupported {
+                format: self.archive,
+            }),
+        }
+    }
+
+    /// Create a new ZIP archive at `archive_path`, replacing any existing file.
+    fn create_zip(&self, archive_path: &Path) -> Result<()> {
+        let mut zip = ZipWriter::new(File::create(archive_path)?);
+        self.write_zip_entries(&mut zip, &BTreeSet::new())?;
+        self.set_zip_comment(&mut zip)?;
+        zip.finish()?;
+
+        Ok(())
+    }
+
+    /// Add the files in this map to the existing ZIP archive at `archive_path`, replacing any entries with the same
+    /// name.
+    fn append_zip(&self, archive_path: &Path) -> Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(archive_path)?;
+        let existing: BTreeSet<String> = ZipArchive::new(&file)?
+            .file_names()
+            .map(String::from)
+            .collect();
+
+        let (_, files) = self.archive_entries();
+        let replaced: BTreeSet<&str> = files
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| existing.contains(*name))
+            .collect();
+
+        if replaced.is_empty() {
+            let mut zip = ZipWriter::new_append(file)?;
+            self.write_zip_entries(&mut zip, &existing)?;
+            self.set_zip_comment(&mut zip)?;
+            zip.finish()?;
+        } else {
+            // ZIP entries can't be replaced in place, so copy everything that isn't being replaced into a new archive.
+            let mut old = ZipArchive::new(file)?;
+            let temp_path = self.temp_path(archive_path)?;
+            let mut zip = ZipWriter::new(File::create(&temp_path)?);
+
+            for i in 0..old.len() {
+                let entry = old.by_index_raw(i)?;
+
+                if !replaced.contains(entry.name()) {
+                    zip.raw_copy_file(entry)?;
+                }
+            }
+
+            self.write_zip_entries(&mut zip, &existing)?;
+            self.set_zip_comment(&mut zip)?;
+            zip.finish()?;
+
+            move_file(&temp_path, archive_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a ZIP folder entry for every folder not in `skip_dirs`, and a file entry for every file. Files are
+    /// compressed in parallel when there is more than one thread available to do it. The two are compared by the `zip`
+    /// benchmark.
+    fn write_zip_entries<W>(
+        &self,
+        zip: &mut ZipWriter<W>,
+        skip_dirs: &BTreeSet<String>,
+    ) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        let options = self.zip_options();
+
+        if rayon::current_num_threads() > 1 && self.map.len() > 1 {
+            self.write_zip_entries_parallel(zip, skip_dirs, options)
+        } else {
+            self.write_zip_entries_sequential(zip, skip_dirs, options)
+        }
+    }
+
+    /// Write the entries for [`write_zip_entries`][write_zip_entries] one file at a time, with `options`.
+    ///
+    /// [write_zip_entries]: ./struct.FileMap.html#method.write_zip_entries
+    fn write_zip_entries_sequential<W>(
+        &self,
+        zip: &mut ZipWriter<W>,
+        skip_dirs: &BTreeSet<String>,
+        options: SimpleFileOptions,
+    ) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        let (dirs, files) = self.archive_entries();
+
+        for dir in dirs.difference(skip_dirs) {
+            zip.add_directory(dir.as_str(), options)?;
+        }
+
+        for (name, src) in files {
+            zip.start_file(name, self.zip_file_options(src, options)?)?;
+            io::copy(&mut File::open(src)?, zip)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the entries for [`write_zip_entries`][write_zip_entries] with `options`, compressing the files on the
+    /// rayon thread pool.
+    ///
+    /// ZIP entries have to be written one after another, so each file is compressed into a single-entry archive in
+    /// memory and then copied into `zip` as is, in the same order as the sequential writer. The archive is byte for
+    /// byte the same as the one [`write_zip_entries_sequential`][write_zip_entries_sequential] writes with the same
+    /// options. Files are compressed `PARALLEL_ZIP_BATCH` at a time so that only that many are held in memory.
+    ///
+    /// [write_zip_entries]: ./struct.FileMap.html#method.write_zip_entries
+    /// [write_zip_entries_sequential]: ./struct.FileMap.html#method.write_zip_entries_sequential
+    fn write_zip_entries_parallel<W>(
+        &self,
+        zip: &mut ZipWriter<W>,
+        skip_dirs: &BTreeSet<String>,
+        options: SimpleFileOptions,
+    ) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        let (dirs, files) = self.archive_entries();
+
+        for dir in dirs.difference(skip_dirs) {
+            zip.add_directory(dir.as_str(), options)?;
+        }
+
+        for batch in files.chunks(PARALLEL_ZIP_BATCH) {
+            let compressed: Vec<Result<Vec<u8>>> = batch
+                .par_iter()
+                .map(|(name, src)| self.compress_zip_entry(name, src, options))
+                .collect();
+
+            for entry in compressed {
+                let mut single = ZipArchive::new(Cursor::new(entry?))?;
+                zip.raw_copy_file(single.by_index_raw(0)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A ZIP archive in memory holding just `src`, compressed under `name`.
+    fn compress_zip_entry(
+        &self,
+        name: &str,
+        src: &Path,
+        options: SimpleFileOptions,
+    ) -> Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(name, self.zip_file_options(src, options)?)?;
+        io::copy(&mut File::open(src)?, &mut zip)?;
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// `options` with the modification time of `src` as the entry's timestamp.
+    fn zip_file_options(
+        &self,
+        src: &Path,
+        options: SimpleFileOptions,
+    ) -> Result<SimpleFileOptions> {
+        let mtime = match self.mtimes.get(src) {
+            Some(mtime) => *mtime,
+            None => fs::metadata(src)?.modified()?,
+        };
+
+        // ZIP timestamps can't be before 1980, so anything earlier gets the default instead.
+        Ok(match zip_datetime(mtime) {
+            Some(datetime) => options.last_modified_time(datetime),
+            None => options,
+        })
+    }
+
+    /// Set the archive comment, if there is one, on `zip`. An archive being appended to keeps its old comment
+    /// otherwise.
+    fn set_zip_comment<W>(&self, zip: &mut ZipWriter<W>) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        if let Some(ref comment) = self.archive_comment {
+            zip.set_comment(comment.as_str())?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a new gzipped tarball at `archive_path`, replacing any existing file.
+    fn create_tar_gz(&self, archive_path: &Path) -> Result<()> {
+        let encoder = GzEncoder::new(File::create(archive_path)?, self.gz_compression());
+        let mut tar = tar::Builder::new(encoder);
+        self.write_tar_entries(&mut tar, &BTreeSet::new())?;
+        tar.into_inner()?.finish()?;
+
+        Ok(())
+    }
+
+    /// Add the files in this map to the existing gzipped tarball at `archive_path`, replacing any entries with the same
+    /// name. The compressed stream can't be added to, so the tarball is always written again from scratch.
+    fn append_tar_gz(&self, archive_path: &Path) -> Result<()> {
+        let (_, files) = self.archive_entries();
+        let replaced: BTreeSet<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+
+        let temp_path = self.temp_path(archive_path)?;
+        let encoder = GzEncoder::new(File::create(&temp_path)?, self.gz_compression());
+        let mut tar = tar::Builder::new(encoder);
+        let mut existing = BTreeSet::new();
+
+        let mut old = tar::Archive::new(GzDecoder::new(File::open(archive_path)?));
+
+        for entry in old.entries()? {
+            let entry = entry?;
+            let mut name = entry.path()?.to_string_lossy().into_owned();
+
+            if entry.header().entry_type().is_dir() && !name.ends_with('/') {
+                name.push('/');
+            }
+
+            if !replaced.contains(name.as_str()) {
+                let mut header = entry.header().clone();
+                tar.append_data(&mut header, &name, entry)?;
+                existing.insert(name);
+            }
+        }
+
+        self.write_tar_entries(&mut tar, &existing)?;
+        tar.into_inner()?.finish()?;
+
+        move_file(&temp_path, archive_path)?;
+
+        Ok(())
+    }
+
+    /// Write a tar folder entry for every folder not in `skip_dirs`, and a file entry for every file.
+    fn write_tar_entries<W>(
+        &self,
+        tar: &mut tar::Builder<W>,
+        skip_dirs: &BTreeSet<String>,
+    ) -> Result<()>
+    where
+        W: Write,
+    {
+        let (dirs, files) = self.archive_entries();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        for dir in dirs.difference(skip_dirs) {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_mtime(now);
+            header.set_size(0);
+            tar.append_data(&mut header, dir, io::empty())?;
+        }
+
+        for (name, src) in files {
+            match self.mtimes.get(src) {
+                Some(mtime) => {
+                    let file = File::open(src)?;
+                    let mut header = tar::Header::new_gnu();
+                    header.set_metadata(&file.metadata()?);
+                    header.set_mtime(
+                        mtime
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |duration| duration.as_secs()),
+                    );
+                    tar.append_data(&mut header, name, file)?;
+                }
+                None => tar.append_path_with_name(src, name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for FileMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files → {} (archive: {})",
+            self.len(),
+            self.dest_dir.display(),
+            if self.archive_enabled() { "yes" } else { "no" }
+        )
+    }
+}
+
+/// Options that adjust the behaviour of [`FileMap::execute_with`][execute_with].
+///
+/// [execute_with]: ./struct.FileMap.html#method.execute_with
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExecuteOptions {
+    /// Add files to an existing archive rather than recreating it.
+    pub append: bool,
+    /// Write a manifest even if `destination.manifest` is not set.
+    pub manifest: bool,
+    /// Use this algorithm for the manifest instead of `destination.checksum_algorithm`.
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Don't run any hooks configured to run around copying.
+    pub skip_hooks: bool,
+    /// Write a [copy log][copy_log] to `bathpack.log` in the destination folder, which is included in the archive.
+    /// Ignored by [`FileMap::execute_into_archive`][execute_into_archive], since nothing is copied.
+    ///
+    /// [copy_log]: ./struct.CopyRecord.html
+    /// [execute_into_archive]: ./struct.FileMap.html#method.execute_into_archive
+    pub copy_log: bool,
+    /// Fail with a permission error in place of copying the file with this number, counting from 1, to test how
+    /// partial failures are handled. Only available in debug builds or with the `testing` feature.
+    #[cfg(any(debug_assertions, feature = "testing"))]
+    pub simulate_failure: Option<usize>,
+}
+
+/// The name of the copy log written into the destination folder when [`ExecuteOptions::copy_log`][copy_log] is set.
+///
+/// [copy_log]: ./struct.ExecuteOptions.html#structfield.copy_log
+pub const COPY_LOG_FILE: &str = "bathpack.log";
+
+/// The outcome of [`FileMap::execute_parallel_with_progress`][execute_parallel].
+///
+/// [execute_parallel]: ./struct.FileMap.html#method.execute_parallel_with_progress
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExecuteResult {
+    /// The number of files copied.
+    pub files: usize,
+    /// The total number of bytes copied.
+    pub bytes: u64,
+    /// How long packaging took altogether.
+    pub duration: Duration,
+}
+
+/// Sent by [`FileMap::execute_parallel_with_progress`][execute_parallel] as each file is copied. Events for different
+/// files may be interleaved, since several are copied at once.
+///
+/// [execute_parallel]: ./struct.FileMap.html#method.execute_parallel_with_progress
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProgressEvent {
+    /// The source file (first) is about to be copied to the destination (second).
+    Started(PathBuf, PathBuf),
+    /// The file has been copied, which took `duration`.
+    Completed {
+        src: PathBuf,
+        dst: PathBuf,
+        bytes: u64,
+        duration: Duration,
+    },
+    /// The file could not be copied, with this error message.
+    Failed { src: PathBuf, err: String },
+}
+
+/// One line of the copy log, `bathpack.log`, which records every file copied by
+/// [`FileMap::execute_with`][execute_with] when [`ExecuteOptions::copy_log`][copy_log] is set.
+///
+/// The log is in [JSON Lines][jsonl] format, with one object per copied file in the order they were copied, e.g.
+///
+/// ```text
+/// {"from":"src/Main.java","to":"src/Main.java","bytes":1234,"sha256":"9f86d081..."}
+/// ```
+///
+/// `from` is the source relative to the root directory (or absolute, if it is outside it), `to` is the destination
+/// relative to the destination folder, `bytes` is the size of the copy and `sha256` is the lowercase hexadecimal
+/// SHA-256 checksum of the copy. Paths always use `/` as the separator. New fields may be added, but these won't be
+/// removed or renamed.
+///
+/// [execute_with]: ./struct.FileMap.html#method.execute_with
+/// [copy_log]: ./struct.ExecuteOptions.html#structfield.copy_log
+/// [jsonl]: https://jsonlines.org
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CopyRecord {
+    /// The source of the copy, relative to the root directory.
+    pub from: String,
+    /// The destination of the copy, relative to the destination folder.
+    pub to: String,
+    /// The number of bytes copied.
+    pub bytes: u64,
+    /// The SHA-256 checksum of the copy.
+    pub sha256: String,
+}
+
+/// Write `copies` to the copy log at `path`, one JSON object per line.
+fn write_copy_log(path: &Path, copies: &[CopyRecord]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    for copy in copies {
+        writeln!(writer, "{}", serde_json::to_string(copy)?)?;
+    }
+
+    writer.flush()
+}
+
+/// Where files overwritten by [`FileMap::execute_journaled`][execute_journaled] are backed up to, relative to the root
+/// directory.
+///
+/// [execute_journaled]: ./struct.FileMap.html#method.execute_journaled
+const BACKUP_DIR: &str = ".bathpack/backup";
+
+/// Where files that only go into the archive are written while it is being created by
+/// [`FileMap::execute_into_archive`][execute_into_archive], relative to the root directory.
+///
+/// [execute_into_archive]: ./struct.FileMap.html#method.execute_into_archive
+const STAGING_DIR: &str = ".bathpack/staging";
+
+/// How many files [`FileMap::write_zip_entries_parallel`][write_zip_entries_parallel] compresses at once.
+///
+/// [write_zip_entries_parallel]: ./struct.FileMap.html#method.write_zip_entries_parallel
+const PARALLEL_ZIP_BATCH: usize = 64;
+
+/// A record of the files created and overwritten by [`FileMap::execute_journaled`][execute_journaled], which can be
+/// undone with [`FileMap::rollback`][rollback].
+///
+/// [execute_journaled]: ./struct.FileMap.html#method.execute_journaled
+/// [rollback]: ./struct.FileMap.html#method.rollback
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CopyJournal {
+    /// Files that didn't exist before, in the order they were written.
+    created: Vec<PathBuf>,
+    /// Folders that didn't exist before, each after its parent.
+    created_dirs: Vec<PathBuf>,
+    /// Files that were overwritten, each paired with the backup of its previous contents.
+    overwritten: Vec<(PathBuf, PathBuf)>,
+}
+
+impl CopyJournal {
+    /// The files that were created.
+    pub fn created(&self) -> &[PathBuf] {
+        &self.created
+    }
+
+    /// The files that were overwritten.
+    pub fn overwritten(&self) -> impl Iterator<Item = &Path> {
+        self.overwritten.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Whether nothing was written at all.
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.overwritten.is_empty()
+    }
+
+    /// Delete the backups of overwritten files, once it's certain they won't be needed for a rollback.
+    pub fn discard(self) -> io::Result<()> {
+        for (_, backup) in self.overwritten {
+            fs::remove_file(backup)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The result of [`FileMap::execute_with_verify`][execute_with_verify].
+///
+/// [execute_with_verify]: ./struct.FileMap.html#method.execute_with_verify
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifiedExecution {
+    /// Every file that was copied, in the order they were copied.
+    pub pairs: Vec<VerifiedPair>,
+}
+
+impl VerifiedExecution {
+    /// Whether every copy matched its source.
+    pub fn all_verified(&self) -> bool {
+        self.pairs.iter().all(|pair| pair.verified)
+    }
+}
+
+/// A file copied by [`FileMap::execute_with_verify`][execute_with_verify].
+///
+/// [execute_with_verify]: ./struct.FileMap.html#method.execute_with_verify
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifiedPair {
+    /// The source file.
+    pub src: PathBuf,
+    /// The copy.
+    pub dst: PathBuf,
+    /// The number of bytes copied.
+    pub bytes: u64,
+    /// The SHA-256 digest of the source file, computed as it was copied.
+    pub checksum: [u8; 32],
+    /// Whether the digest of the copy matched `checksum`.
+    pub verified: bool,
+}
+
+/// The differences between two [`FileMap`][filemap]s, as produced by [`FileMap::diff`][diff].
+///
+/// [filemap]: ./struct.FileMap.html
+/// [diff]: ./struct.FileMap.html#method.diff
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileDiff {
+    /// Source and destination pairs whose source only appears in the new map.
+    pub added: Vec<(PathBuf, PathBuf)>,
+    /// Source and destination pairs whose source only appears in the old map.
+    pub removed: Vec<(PathBuf, PathBuf)>,
+    /// Sources that appear in both maps, with their old and new destinations.
+    pub changed: Vec<(PathBuf, PathBuf, PathBuf)>,
+}
+
+impl FileDiff {
+    /// Whether there are no differences at all.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "No changes");
+        }
+
+        let mut lines = Vec::new();
+
+        for (src, dst) in &self.added {
+            lines.push(format!("+ {} -> {}", src.display(), dst.display()));
+        }
+
+        for (src, dst) in &self.removed {
+            lines.push(format!("- {} -> {}", src.display(), dst.display()));
+        }
+
+        for (src, old_dst, new_dst) in &self.changed {
+            lines.push(format!(
+                "~ {}: {} -> {}",
+                src.display(),
+                old_dst.display(),
+                new_dst.display()
+            ));
+        }
+
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// How many folders [`FileMapBuilder::download_dir`][download_dir] has handed out in this process, so that each build
+/// downloads into its own.
+///
+/// [download_dir]: ./struct.FileMapBuilder.html#method.download_dir
+static DOWNLOAD_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// Download the file at `url` into `dir`, returning the path of the downloaded file. The file is named after the last
+/// segment of the URL's path. If `expected_sha256` is given, the file's SHA-256 checksum must match it, and the file is
+/// deleted if it doesn't.
+fn download(url: &str, expected_sha256: Option<&str>, dir: &Path) -> Result<PathBuf> {
+    let mut response = reqwest::blocking::get(url)?.error_for_status()?;
+
+    let file_name = response
+        .url()
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("download")
+        .to_string();
+
+    fs::create_dir_all(dir)?;
+    let path = join_paths(dir, [file_name]);
+    response.copy_to(&mut File::create(&path)?)?;
+
+    let actual = ChecksumAlgorithm::Sha256.hash_file(&path)?;
+    Verbosity::VERBOSE.print(format_args!("Downloaded {} (sha256 {})", url, actual));
+
+    match expected_sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(&actual) => {
+            fs::remove_file(&path)?;
+
+            Err(FileMapError::ChecksumMismatch {
+                url: url.to_string(),
+                expected: expected.to_string(),
+                actual,
+            })
+        }
+        _ => Ok(path),
+    }
+}
+
+/// Substitute the environment variables and then the template variables from `config` into `template`.
+pub(crate) fn format_template(config: &Config, template: &str) -> Result<String> {
+    let vars: HashMap<String, &str> = config
+        .iter_template_vars()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+
+    Ok(strfmt(&substitute_env_vars(template)?, &vars)?)
+}
+
+/// Substitute the parts of `file`'s name into `template`, from a [`DestLoc::Template`][template] location: `{stem}` for
+/// the name without its extension, `{ext}` for the extension, and `{filename}` for the whole name.
+///
+/// [template]: ../config/enum.DestLoc.html#variant.Template
+pub(crate) fn format_file_template(
+    template: &str,
+    file: &Path,
+) -> std::result::Result<String, strfmt::FmtError> {
+    let part = |part: Option<&OsStr>| part.unwrap_or_default().to_string_lossy().into_owned();
+
+    let mut vars = HashMap::new();
+    vars.insert("stem".to_string(), part(file.file_stem()));
+    vars.insert("ext".to_string(), part(file.extension()));
+    vars.insert("filename".to_string(), part(file.file_name()));
+
+    strfmt(template, &vars)
+}
+
+/// The folder that `file` is copied into for `folder`, one of the folders of `location`. For a template location, the
+/// parts of the file's name are substituted in; templates are checked when the config is validated, so one that can't
+/// be formatted is used as it is.
+fn location_folder(location: &DestLoc, folder: &str, file: &Path) -> PathBuf {
+    match location {
+        DestLoc::Template(_) => {
+            PathBuf::from(format_file_template(folder, file).unwrap_or_else(|_| folder.to_string()))
+        }
+        _ => PathBuf::from(folder),
+    }
+}
+
+/// Whether `name` is one of the folder names in `exclude_dirs`.
+fn is_excluded(name: &OsStr, exclude_dirs: &[String]) -> bool {
+    exclude_dirs.iter().any(|dir| name == dir.as_str())
+}
+
+/// `time` as a ZIP timestamp in UTC, or `None` if it is outside the range ZIP timestamps can represent.
+fn zip_datetime(time: SystemTime) -> Option<zip::DateTime> {
+    use chrono::{Datelike, Timelike};
+    use std::convert::TryFrom;
+
+    let time = chrono::DateTime::<chrono::Utc>::from(time);
+
+    zip::DateTime::from_date_and_time(
+        u16::try_from(time.year()).ok()?,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .ok()
+}
+
+/// Fail with a permission error if `options` asks for the copy of file number `n` to fail.
+#[cfg(any(debug_assertions, feature = "testing"))]
+fn check_simulated_failure(options: &ExecuteOptions, n: usize) -> io::Result<()> {
+    if options.simulate_failure == Some(n) {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "simulated failure",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Simulated failures are only available in debug builds or with the `testing` feature, so never fail.
+#[cfg(not(any(debug_assertions, feature = "testing")))]
+fn check_simulated_failure(_options: &ExecuteOptions, _n: usize) -> io::Result<()> {
+    Ok(())
+}
+
+/// `path` quoted for a shell command in a Makefile recipe: in single quotes for the shell, with `$` doubled for make.
+/// An empty path becomes `.`.
+fn makefile_quote(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let path = if path.is_empty() { "." } else { &path };
+
+    format!("'{}'", path.replace('\'', "'\\''").replace('$', "$$"))
+}
+
+/// Leave out each file matched by a source in `keys` whose contents are the same as a file that came before it, going
+/// through `sources` in order. Single files that don't exist yet are skipped, since they are checked later.
+fn deduplicate_sources(
+    sources: &mut BTreeMap<String, ExpandedSource>,
+    keys: &BTreeSet<String>,
+) -> io::Result<()> {
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+
+    for (key, source) in sources.iter_mut() {
+        match source {
+            ExpandedSource::File(file) => {
+                if let Ok(hash) = ChecksumAlgorithm::Sha256.hash_file(&*file) {
+                    seen.entry(hash).or_insert_with(|| file.clone());
+                }
+            }
+            ExpandedSource::FileMatches { items, .. } => {
+                let mut kept = Vec::with_capacity(items.len());
+
+                for item in items.drain(..) {
+                    if item.is_dir() {
+                        kept.push(item);
+                        continue;
+                    }
+
+                    let hash = ChecksumAlgorithm::Sha256.hash_file(&item)?;
+
+                    match seen.get(&hash) {
+                        Some(original) if keys.contains(key) => {
+                            Verbosity::VERBOSE.print(format_args!(
+                                "Leaving out {}, which is identical to {}",
+                                item.display(),
+                                original.display()
+                            ));
+                        }
+                        Some(_) => kept.push(item),
+                        None => {
+                            seen.insert(hash, item.clone());
+                            kept.push(item);
+                        }
+                    }
+                }
+
+                *items = kept;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Move the file at `from` to `to`, creating any folders that are needed. If it can't simply be renamed, e.g. because
+/// `to` is on another filesystem, it is copied and then deleted.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+/// Move each backup made by [`FileMap::execute_with_backup`][execute_with_backup] back to where it came from.
+///
+/// [execute_with_backup]: ./struct.FileMap.html#method.execute_with_backup
+fn restore_backups(backups: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    for (path, backup) in backups.iter().rev() {
+        move_file(backup, path)?;
+        Verbosity::VERBOSE.print(format_args!("Restored {}", path.display()));
+    }
+
+    Ok(())
+}
+
+/// Keep only the `items` that were last modified after `since`.
+fn retain_modified_since(items: &mut Vec<PathBuf>, since: SystemTime) -> io::Result<()> {
+    let mut kept = Vec::with_capacity(items.len());
+
+    for item in items.drain(..) {
+        if fs::metadata(&item)?.modified()? > since {
+            kept.push(item);
+        }
+    }
+
+    *items = kept;
+    Ok(())
+}
+
+/// Put `items` in the order `sort`. Ties keep their existing order.
+fn sort_items(items: &mut Vec<PathBuf>, sort: SortOrder) -> io::Result<()> {
+    match sort {
+        SortOrder::Name => items.sort(),
+        SortOrder::Modified => {
+            let mut keyed = items
+                .drain(..)
+                .map(|item| Ok((fs::metadata(&item)?.modified()?, item)))
+                .collect::<io::Result<Vec<_>>>()?;
+            keyed.sort_by(|(a, _), (b, _)| b.cmp(a));
+            items.extend(keyed.into_iter().map(|(_, item)| item));
+        }
+        SortOrder::Size => {
+            let mut keyed = items
+                .drain(..)
+                .map(|item| Ok((fs::metadata(&item)?.len(), item)))
+                .collect::<io::Result<Vec<_>>>()?;
+            keyed.sort_by(|(a, _), (b, _)| b.cmp(a));
+            items.extend(keyed.into_iter().map(|(_, item)| item));
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace every `{env:NAME}` token in `template` with the value of the environment variable `NAME`, leaving the rest
+/// of the template (including `{{` escapes) for `strfmt`. Braces in the substituted values are escaped so that they
+/// come through formatting unchanged.
+fn substitute_env_vars(template: &str) -> Result<String> {
+    const PREFIX: &str = "{env:";
+
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("{{") {
+            result.push_str("{{");
+            rest = &rest[2..];
+        } else if let Some(end) = rest.find('}').filter(|_| rest.starts_with(PREFIX)) {
+            let name = &rest[PREFIX.len()..end];
+
+            let value = std::env::var(name).map_err(|source| FileMapError::MissingEnvVar {
+                name: name.to_string(),
+                source,
+            })?;
+            result.push_str(&value.replace('{', "{{").replace('}', "}}"));
+
+            rest = &rest[end + 1..];
+        } else {
+            result.push('{');
+            rest = &rest[1..];
+        }
+    }
+
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Whether `path` is relative and made only of folder names and `.`, so that joining it onto a folder can't lead out of
+/// that folder.
+pub fn is_plain_relative(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// `path` with `.` components removed and `..` components collapsed into the folder before them, without looking at the
+/// filesystem.
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            _ => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// The SHA-256 digest of the file at `path`.
+fn sha256_digest(path: &Path) -> Result<[u8; 32]> {
+    let mut reader = HashingReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+
+    Ok(reader.finalize())
+}
+
+/// The destinations of each source in `map`, keyed by source.
+fn destinations_by_source(map: &[(PathBuf, PathBuf)]) -> BTreeMap<&Path, BTreeSet<&Path>> {
+    let mut destinations: BTreeMap<&Path, BTreeSet<&Path>> = BTreeMap::new();
+
+    for (src, dst) in map {
+        destinations.entry(src).or_default().insert(dst);
+    }
+
+    destinations
+}
+
+/// Convert a relative path into the name of a ZIP entry, which always uses `/` as a separator.
+fn entry_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Builds a [`FileMap`][filemap] from a [`Config`][config] by running it through each stage of the pipeline in turn.
+///
+/// [filemap]: ./struct.FileMap.html
+/// [config]: ../config/struct.Config.html
+#[derive(Clone, Debug)]
+pub struct FileMapBuilder {
+    config: Config,
+    root_dir: PathBuf,
+    logger: Option<Logger>,
+    verify_existence: bool,
+    max_size_bytes: Option<u64>,
+    exclude_keys: BTreeSet<String>,
+    extra_vars: HashMap<String, String>,
+    archive_comment: Option<String>,
+    modified_since: Option<SystemTime>,
+}
+
+impl FileMapBuilder {
+    /// Create a new `FileMapBuilder` that will resolve the sources in `config` relative to `root_dir`.
+    pub fn from(config: Config, root_dir: PathBuf) -> FileMapBuilder {
+        FileMapBuilder {
+            config,
+            root_dir,
+            logger: None,
+            verify_existence: true,
+            max_size_bytes: None,
+            exclude_keys: BTreeSet::new(),
+            extra_vars: HashMap::new(),
+            archive_comment: None,
+            modified_since: None,
+        }
+    }
+
+    /// Create a new `FileMapBuilder` from the config file at `path`, including any files it includes, resolving the
+    /// sources relative to the folder the file is in.
+    pub fn from_config_file(path: &Path) -> Result<FileMapBuilder> {
+        let config = Config::load_with_includes(path).map_err(|err| FileMapError::Config {
+            path: path.to_path_buf(),
+            err,
+        })?;
+        let root_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        Ok(FileMapBuilder::from(config, root_dir))
+    }
+
+    /// Record the operations performed when the built `FileMap` is executed using `logger`.
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// Whether to check that every source file exists before producing the `FileMap`. This is on by default, and
+    /// should only be turned off when the files are known to exist by the time the map is executed.
+    pub fn with_verify_existence(mut self, verify_existence: bool) -> Self {
+        self.verify_existence = verify_existence;
+        self
+    }
+
+    /// Refuse to build a `FileMap` whose source files add up to more than `max_size_bytes` bytes, instead of using
+    /// `destination.max_size_bytes`.
+    pub fn with_max_size(mut self, max_size_bytes: u64) -> Self {
+        self.max_size_bytes = Some(max_size_bytes);
+        self
+    }
+
+    /// Leave the sources with each of `keys` out of the built `FileMap`, along with their destination locations. An
+    /// excluded source doesn't need a location, and is dropped before anything is read or downloaded for it.
+    pub fn with_exclude_keys(mut self, keys: &[&str]) -> Self {
+        self.exclude_keys
+            .extend(keys.iter().map(|key| key.to_string()));
+        self
+    }
+
+    /// Make each of `vars` available as a template variable in the destination name and archive path, alongside the
+    /// variables from the config. These take precedence over `[vars]` in the config, but the built-in variables
+    /// (`username` and `assignment`) take precedence over them.
+    pub fn with_extra_vars(mut self, vars: HashMap<String, String>) -> Self {
+        self.extra_vars.extend(vars);
+        self
+    }
+
+    /// Store `comment` in ZIP archives instead of `destination.archive_comment`. Template variables are substituted in
+    /// the same way.
+    pub fn with_archive_comment(mut self, comment: &str) -> Self {
+        self.archive_comment = Some(comment.to_string());
+        self
+    }
+
+    /// Leave out files matched by folder, recursive folder and glob sources that were last modified before `since`,
+    /// e.g. for an incremental submission. A [`FileMapWarning::NoFilesModifiedSince`][not_modified] is given for each
+    /// source that this leaves empty.
+    ///
+    /// [not_modified]: ./enum.FileMapWarning.html#variant.NoFilesModifiedSince
+    pub fn with_modified_since(mut self, since: SystemTime) -> Self {
+        self.modified_since = Some(since);
+        self
+    }
+
+    /// Run every stage of the pipeline, producing a `FileMap` in which every source file is known to exist (unless
+    /// this was turned off with [`with_verify_existence`][with_verify_existence]), and the source files are no bigger
+    /// than the size limit in total.
+    ///
+    /// [with_verify_existence]: #method.with_verify_existence
+    pub fn build(self) -> Result<FileMap> {
+        let max_size_bytes = self
+            .max_size_bytes
+            .or(self.config.destination.max_size_bytes);
+
+        let flattened = self.run_until_existence_check()?;
+
+        let file_map = if flattened.builder.verify_existence {
+            flattened.verify_existence()?
+        } else {
+            flattened.into_file_map()
+        };
+
+        if let Some(limit) = max_size_bytes {
+            let actual = file_map.total_source_size()?;
+
+            if actual > limit {
+                return Err(FileMapError::SizeLimit { limit, actual });
+            }
+        }
+
+        Ok(file_map)
+    }
+
+    /// Run every stage of the pipeline except checking that the source files exist and are within the size limit, so
+    /// that globbing and pairing can be tested with synthetic paths. Folder and glob sources are still expanded by
+    /// looking at the disk, but file sources are taken at their word.
+    ///
+    /// The returned `FileMap` may refer to files that don't exist, so it shouldn't be executed.
+    pub fn dry_build(self) -> Result<FileMap> {
+        Ok(self.run_until_existence_check()?.into_file_map())
+    }
+
+    /// Run the stages of the pipeline up to checking that the source files exist.
+    fn run_until_existence_check(self) -> Result<ConflictsVerified> {
+        self.expand_paths()
+            .verify_patterns()?
+            .expand_sources()?
+            .pair_locations()?
+            .format_destination()?
+            .flatten_locations()
+            .verify_no_conflicts()
+    }
+
+    /// The destination folder and every archive that packaging could create, without looking at the sources. If no
+    /// `destination.archive_path` is given, this includes an archive alongside the folder in each format, since the
+    /// format may have been changed since the last run.
+    pub fn output_paths(&self) -> Result<Vec<PathBuf>> {
+        let destination = &self.config.destination;
+        let dest_dir = join_paths(
+            &self.root_dir,
+            [format_template(&self.config, &destination.name)?],
+        );
+        let mut paths = vec![dest_dir.clone()];
+
+        match destination.archive_path {
+            Some(ref template) => paths.push(join_paths(
+                &self.root_dir,
+                [format_template(&self.config, template)?],
+            )),
+            None => {
+                for mode in &[ArchiveMode::Zip, ArchiveMode::TarGz] {
+                    let mut name = dest_dir.file_name().unwrap_or_default().to_os_string();
+                    name.push(".");
+                    name.push(mode.extension().unwrap_or_default());
+                    paths.push(join_paths(&self.root_dir, [name]));
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// The [`output_paths`][output_paths] that it is safe for `bathpack clean` to delete. Fails with
+    /// [`FileMapError::OutputOutsideRoot`][outside] if any of them is the root directory itself or isn't inside it,
+    /// e.g. because the destination name is empty, `.` or `..`, so that nothing outside the project can be removed.
+    ///
+    /// [output_paths]: ./struct.FileMapBuilder.html#method.output_paths
+    /// [outside]: ./enum.FileMapError.html#variant.OutputOutsideRoot
+    pub fn clean_paths(&self) -> Result<Vec<PathBuf>> {
+        let root_dir = normalize_lexically(&self.root_dir);
+        let paths = self.output_paths()?;
+
+        for path in &paths {
+            let normalized = normalize_lexically(path);
+
+            if normalized == root_dir || !normalized.starts_with(&root_dir) {
+                return Err(FileMapError::OutputOutsideRoot { path: path.clone() });
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// A new folder to download URL sources into, in `destination.temp_dir` if it was given, or otherwise the system's
+    /// temporary folder, so that nothing is written into the project by commands that only read it.
+    fn download_dir(&self) -> PathBuf {
+        let base = match self.config.destination.temp_dir {
+            Some(ref dir) => join_paths(&self.root_dir, [dir]),
+            None => std::env::temp_dir(),
+        };
+
+        join_paths(
+            base,
+            [format!(
+                "bathpack-downloads-{}-{}",
+                std::process::id(),
+                DOWNLOAD_DIRS.fetch_add(1, Ordering::Relaxed)
+            )],
+        )
+    }
+
+    /// Run only as far as the `expand_sources` stage, returning the files matched by each source, keyed by the
+    /// source's key, along with any warnings found while matching them. URL sources are downloaded, and their
+    /// downloaded file is returned.
+    pub fn matched_files(self) -> Result<(MatchedFiles, Vec<FileMapWarning>)> {
+        let expanded = self.expand_paths().verify_patterns()?.expand_sources()?;
+
+        let matched = expanded
+            .sources
+            .into_iter()
+            .map(|(key, source)| {
+                let files = match source {
+                    ExpandedSource::FileMatches { items, .. } => items,
+                    ExpandedSource::File(file) => vec![file],
+                };
+
+                (key, files)
+            })
+            .collect();
+
+        Ok((matched, expanded.warnings))
+    }
+
+    /// Resolve each source path relative to `root_dir`, leaving out excluded sources. Absolute paths are used as they
+    /// are, since joining an absolute path replaces `root_dir`. For folders, the folder path is joined with the glob
+    /// pattern to produce a pattern that can be matched directly.
+    ///
+    /// This is the first stage of the pipeline that [`build`][build] runs. Each stage returns the next one's input, so
+    /// the pipeline can be run step by step to inspect or adjust what is passed between stages. The size limit is only
+    /// checked by `build`.
+    ///
+    /// [build]: #method.build
+    pub fn expand_paths(self) -> PathsExpanded {
+        let root_dir = &self.root_dir;
+
+        let paths = self
+            .config
+            .sources
+            .iter()
+            .filter(|(key, _)| !self.exclude_keys.contains(*key))
+            .map(|(key, source)| {
+                let path = match source {
+                    Source::Folder {
+                        path,
+                        pattern,
+                        max_path_length,
+                        rename_pattern,
+                        rename_replacement,
+                        exclude_dirs,
+                        allow_empty,
+                        follow_symlinks,
+                        sort,
+                        mtime_from,
+                        deduplicate,
+                        ..
+                    } => {
+                        let base = join_paths(root_dir, [path]);
+                        let pattern =
+                            join_paths(Pattern::escape(&base.to_string_lossy()), [pattern]);
+
+                        SourcePath::Folder {
+                            base,
+                            pattern: pattern.to_string_lossy().into_owned(),
+                            max_path_length: *max_path_length,
+                            rename: rename_pattern.clone().map(|pattern| {
+                                (pattern, rename_replacement.clone().unwrap_or_default())
+                            }),
+                            exclude_dirs: exclude_dirs.clone(),
+                            allow_empty: *allow_empty,
+                            follow_symlinks: *follow_symlinks,
+                            sort: *sort,
+                            mtime_from: mtime_from
+                                .as_ref()
+                                .map(|file| join_paths(root_dir, [file])),
+                            deduplicate: *deduplicate,
+                        }
+                    }
+                    Source::RecursiveFolder { path, exclude_dirs } => SourcePath::RecursiveFolder {
+                        base: join_paths(root_dir, [path]),
+                        exclude_dirs: exclude_dirs.clone(),
+                    },
+                    Source::Url { url, sha256 } => SourcePath::Url {
+                        url: url.clone(),
+                        sha256: sha256.clone(),
+                    },
+                    Source::Glob(pattern) => SourcePath::Glob {
+                        base: root_dir.clone(),
+                        pattern: join_paths(
+                            Pattern::escape(&root_dir.to_string_lossy()),
+                            [pattern],
+                        )
+                        .to_string_lossy()
+                        .into_owned(),
+                    },
+                    Source::TextFile { file, .. } | Source::File(file) => {
+                        SourcePath::File(join_paths(root_dir, [file]))
+                    }
+                };
+
+                (key.clone(), path)
+            })
+            .collect();
+
+        PathsExpanded {
+            builder: self,
+            paths,
+        }
+    }
+}
+
+/// A source whose path has been resolved relative to the root directory.
+#[derive(Clone, Debug)]
+pub enum SourcePath {
+    /// A folder, along with the full glob pattern matching the files inside it.
+    /// Matches whose path is longer than `max_path_length`, if given, are skipped. If `rename` is given, it is the
+    /// regular expression and replacement used to rename each match. Matches inside a subfolder named in
+    /// `exclude_dirs` are skipped. Unless `allow_empty` is set, it is an error for nothing to match. Matches that are
+    /// symlinks are skipped unless `follow_symlinks` is set. If `sort` is given, the matches are put in that order. If
+    /// `mtime_from` is given, the matches are archived with that file's modification time. If `deduplicate` is set,
+    /// matches identical to a file already seen are left out.
+    Folder {
+        base: PathBuf,
+        pattern: String,
+        max_path_length: Option<usize>,
+        rename: Option<(String, String)>,
+        exclude_dirs: Vec<String>,
+        allow_empty: bool,
+        follow_symlinks: bool,
+        sort: Option<SortOrder>,
+        mtime_from: Option<PathBuf>,
+        deduplicate: bool,
+    },
+    /// A folder, all of whose files are matched, except those inside a subfolder named in `exclude_dirs`.
+    RecursiveFolder {
+        base: PathBuf,
+        exclude_dirs: Vec<String>,
+    },
+    /// A file to be downloaded, along with its expected SHA-256 checksum, if known.
+    Url { url: String, sha256: Option<String> },
+    /// The full glob pattern for a glob source, and the root directory, which its matches are relative to.
+    Glob { base: PathBuf, pattern: String },
+    /// A single file.
+    File(PathBuf),
+}
+
+/// A source whose glob pattern, if any, has been matched against the filesystem.
+#[derive(Clone, Debug)]
+pub enum ExpandedSource {
+    /// The files matched inside the folder `base`, how to rename them, if at all, and the modification time to archive
+    /// them with, if not their own.
+    FileMatches {
+        base: PathBuf,
+        items: Vec<PathBuf>,
+        rename: Option<Rename>,
+        mtime: Option<SystemTime>,
+    },
+    /// A single file.
+    File(PathBuf),
+}
+
+/// A regular expression to replace in the name of each file matched in a folder, and what to replace it with.
+#[derive(Clone, Debug)]
+pub struct Rename {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl Rename {
+    /// Create a `Rename` that replaces the first match of the regular expression `pattern` with `replacement`.
+    pub fn new(pattern: &str, replacement: &str) -> Result<Rename> {
+        Ok(Rename {
+            pattern: Regex::new(pattern).map_err(|err| FileMapError::RenamePattern { err })?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// The regular expression to replace.
+    pub fn pattern(&self) -> &Regex {
+        &self.pattern
+    }
+
+    /// What to replace the regular expression with.
+    pub fn replacement(&self) -> &str {
+        &self.replacement
+    }
+
+    /// `file_name` with the first match of the pattern replaced, or `None` if the pattern doesn't match.
+    pub fn apply(&self, file_name: &str) -> Option<String> {
+        if self.pattern.is_match(file_name) {
+            Some(
+                self.pattern
+                    .replace(file_name, self.replacement.as_str())
+                    .into_owned(),
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// Output of [`FileMapBuilder::expand_paths`][expand_paths].
+///
+/// [expand_paths]: ./struct.FileMapBuilder.html#method.expand_paths
+#[derive(Clone, Debug)]
+pub struct PathsExpanded {
+    builder: FileMapBuilder,
+    paths: BTreeMap<String, SourcePath>,
+}
+
+impl PathsExpanded {
+    /// The resolved path of each source, keyed by the source's key.
+    pub fn paths(&self) -> &BTreeMap<String, SourcePath> {
+        &self.paths
+    }
+
+    /// A mutable reference to [`paths`][paths], so that it can be adjusted before the next stage.
+    ///
+    /// [paths]: #method.paths
+    pub fn paths_mut(&mut self) -> &mut BTreeMap<String, SourcePath> {
+        &mut self.paths
+    }
+
+    /// Check that every folder's glob pattern and rename pattern is valid, as well as every glob source's pattern.
+    pub fn verify_patterns(self) -> Result<PatternsVerified> {
+        let mut renames = BTreeMap::new();
+
+        for (key, path) in &self.paths {
+            match path {
+                SourcePath::Folder {
+                    pattern, rename, ..
+                } => {
+                    Pattern::new(pattern)?;
+
+                    if let Some((pattern, replacement)) = rename {
+                        renames.insert(key.clone(), Rename::new(pattern, replacement)?);
+                    }
+                }
+                SourcePath::Glob { pattern, .. } => {
+                    Pattern::new(pattern)?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(PatternsVerified {
+            builder: self.builder,
+            paths: self.paths,
+            renames,
+        })
+    }
+}
+
+/// Output of [`PathsExpanded::verify_patterns`][verify_patterns].
+///
+/// [verify_patterns]: ./struct.PathsExpanded.html#method.verify_patterns
+#[derive(Clone, Debug)]
+pub struct PatternsVerified {
+    builder: FileMapBuilder,
+    paths: BTreeMap<String, SourcePath>,
+    renames: BTreeMap<String, Rename>,
+}
+
+impl PatternsVerified {
+    /// The resolved path of each source, keyed by the source's key.
+    pub fn paths(&self) -> &BTreeMap<String, SourcePath> {
+        &self.paths
+    }
+
+    /// Match each folder's and glob source's pattern against the filesystem to find the files it contains, walk each
+    /// recursive folder to find every file inside it, and download each URL source into a new folder for this build in
+    /// `destination.temp_dir` or the system's temporary folder. Matches modified before the time given to
+    /// [`FileMapBuilder::with_modified_since`][modified_since], if any, are dropped. Finally, matches in folders with
+    /// `deduplicate` set are left out if their contents are the same as a file that came before them, going through the
+    /// sources in order of their keys. A [`FileMapWarning::NoFilesModifiedSince`][not_modified] is added to the
+    /// [`warnings`][warnings] for each source that the time leaves empty, and a
+    /// [`FileMapWarning::PathTooLong`][too_long] for each match left out for being longer than `max_path_length`.
+    ///
+    /// [modified_since]: ./struct.FileMapBuilder.html#method.with_modified_since
+    /// [not_modified]: ./enum.FileMapWarning.html#variant.NoFilesModifiedSince
+    /// [too_long]: ./enum.FileMapWarning.html#variant.PathTooLong
+    /// [warnings]: ./struct.SourcesExpanded.html#method.warnings
+    pub fn expand_sources(mut self) -> Result<SourcesExpanded> {
+        let mut sources = BTreeMap::new();
+        let mut deduplicated = BTreeSet::new();
+        let mut download_dir = None;
+        let mut warnings = Vec::new();
+
+        for (key, path) in self.paths {
+            if let SourcePath::Folder {
+                deduplicate: true, ..
+            } = path
+            {
+                deduplicated.insert(key.clone());
+            }
+
+            let mut expanded = match path {
+                SourcePath::Folder {
+                    base,
+                    pattern,
+                    max_path_length,
+                    exclude_dirs,
+                    allow_empty,
+                    follow_symlinks,
+                    sort,
+                    mtime_from,
+                    ..
+                } => {
+                    let mut items = Vec::new();
+                    let options = MatchOptions {
+                        require_literal_leading_dot: false,
+                        ..MatchOptions::new()
+                    };
+
+                    for entry in glob_with(&pattern, options)? {
+                        let item = entry?;
+
+                        // Symlinks are copied by reading through them, so they only need to be dealt with when they
+                        // shouldn't be followed.
+                        if !follow_symlinks && fs::symlink_metadata(&item)?.file_type().is_symlink()
+                        {
+                            continue;
+                        }
+
+                        // Glob patterns can't prune folders, so check each match's folders instead.
+                        let excluded = item
+                            .strip_prefix(&base)
+                            .unwrap_or(&item)
+                            .parent()
+                            .into_iter()
+                            .flat_map(Path::components)
+                            .any(|component| is_excluded(component.as_os_str(), &exclude_dirs));
+
+                        if excluded {
+                            continue;
+                        }
+
+                        if let Some(limit) = max_path_length {
+                            let relative =
+                                item.strip_prefix(&self.builder.root_dir).unwrap_or(&item);
+                            let actual = relative.to_string_lossy().chars().count();
+
+                            if actual > limit {
+                                warnings.push(FileMapWarning::PathTooLong {
+                                    path: item,
+                                    limit,
+                                    actual,
+                                });
+                                continue;
+                            }
+                        }
+
+                        items.push(item);
+                    }
+
+                    if items.is_empty() && !allow_empty {
+                        return Err(FileMapError::NoMatches { pattern });
+                    }
+
+                    if let Some(sort) = sort {
+                        sort_items(&mut items, sort)?;
+                    }
+
+                    let mtime = match mtime_from {
+                        Some(file) => Some(fs::metadata(file)?.modified()?),
+                        None => None,
+                    };
+
+                    ExpandedSource::FileMatches {
+                        base,
+                        items,
+                        rename: self.renames.remove(&key),
+                        mtime,
+                    }
+                }
+                SourcePath::RecursiveFolder { base, exclude_dirs } => {
+                    let mut items = Vec::new();
+                    let walk = WalkDir::new(&base)
+                        .sort_by_file_name()
+                        .into_iter()
+                        // The top-level folder is never excluded, even if it has an excluded name.
+                        .filter_entry(|entry| {
+                            entry.depth() == 0
+                                || !entry.file_type().is_dir()
+                                || !is_excluded(entry.file_name(), &exclude_dirs)
+                        });
+
+                    for entry in walk {
+                        let entry = entry?;
+
+                        if !entry.file_type().is_dir() {
+                            items.push(entry.into_path());
+                        }
+                    }
+
+                    ExpandedSource::FileMatches {
+                        base,
+                        items,
+                        rename: None,
+                        mtime: None,
+                    }
+                }
+                SourcePath::Glob { base, pattern } => {
+                    let options = MatchOptions {
+                        require_literal_leading_dot: false,
+                        ..MatchOptions::new()
+                    };
+                    let items = glob_with(&pattern, options)?
+                        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+                    if items.is_empty() {
+                        return Err(FileMapError::NoMatches { pattern });
+                    }
+
+                    ExpandedSource::FileMatches {
+                        base,
+                        items,
+                        rename: None,
+                        mtime: None,
+                    }
+                }
+                SourcePath::Url { url, sha256 } => {
+                    let builder = &self.builder;
+                    let download_dir = download_dir.get_or_insert_with(|| builder.download_dir());
+                    let dir = join_paths(download_dir, [&key]);
+                    ExpandedSource::File(download(&url, sha256.as_deref(), &dir)?)
+                }
+                SourcePath::File(file) => ExpandedSource::File(file),
+            };
+
+            if let (Some(since), ExpandedSource::FileMatches { items, .. }) =
+                (self.builder.modified_since, &mut expanded)
+            {
+                let matched = items.len();
+                retain_modified_since(items, since)?;
+
+                if matched > 0 && items.is_empty() {
+                    warnings.push(FileMapWarning::NoFilesModifiedSince { key: key.clone() });
+                }
+            }
+
+            sources.insert(key, expanded);
+        }
+
+        if !deduplicated.is_empty() {
+            deduplicate_sources(&mut sources, &deduplicated)?;
+        }
+
+        Ok(SourcesExpanded {
+            builder: self.builder,
+            sources,
+            warnings,
+        })
+    }
+}
+
+/// Output of [`PatternsVerified::expand_sources`][expand_sources].
+///
+/// [expand_sources]: ./struct.PatternsVerified.html#method.expand_sources
+#[derive(Clone, Debug)]
+pub struct SourcesExpanded {
+    builder: FileMapBuilder,
+    sources: BTreeMap<String, ExpandedSource>,
+    warnings: Vec<FileMapWarning>,
+}
+
+impl SourcesExpanded {
+    /// The files matched by each source, keyed by the source's key.
+    pub fn sources(&self) -> &BTreeMap<String, ExpandedSource> {
+        &self.sources
+    }
+
+    /// A mutable reference to [`sources`][sources], so that it can be adjusted before the next stage.
+    ///
+    /// [sources]: #method.sources
+    pub fn sources_mut(&mut self) -> &mut BTreeMap<String, ExpandedSource> {
+        &mut self.sources
+    }
+
+    /// Anything found while expanding the sources that the user should know about. These are passed on through the
+    /// later stages to the finished [`FileMap`][filemap].
+    ///
+    /// [filemap]: ./struct.FileMap.html
+    pub fn warnings(&self) -> &[FileMapWarning] {
+        &self.warnings
+    }
+
+    /// Pair each source with the destination location that has the same key.
+    pub fn pair_locations(self) -> Result<LocationsPaired> {
+        let mut pairs = BTreeMap::new();
+
+        for (key, source) in self.sources {
+            let location = match self.builder.config.destination.locations.get(&key) {
+                Some(location) => location.clone(),
+                None => return Err(FileMapError::MissingDest { key }),
+            };
+
+            pairs.insert(key, (source, location));
+        }
+
+        Ok(LocationsPaired {
+            builder: self.builder,
+            pairs,
+            warnings: self.warnings,
+        })
+    }
+}
+
+/// Output of [`SourcesExpanded::pair_locations`][pair_locations].
+///
+/// [pair_locations]: ./struct.SourcesExpanded.html#method.pair_locations
+#[derive(Clone, Debug)]
+pub struct LocationsPaired {
+    builder: FileMapBuilder,
+    pairs: BTreeMap<String, (ExpandedSource, DestLoc)>,
+    warnings: Vec<FileMapWarning>,
+}
+
+impl LocationsPaired {
+    /// Each source paired with its destination location, keyed by the source's key.
+    pub fn pairs(&self) -> &BTreeMap<String, (ExpandedSource, DestLoc)> {
+        &self.pairs
+    }
+
+    /// A mutable reference to [`pairs`][pairs], so that it can be adjusted before the next stage.
+    ///
+    /// [pairs]: #method.pairs
+    pub fn pairs_mut(&mut self) -> &mut BTreeMap<String, (ExpandedSource, DestLoc)> {
+        &mut self.pairs
+    }
+
+    /// Substitute the template variables, including any passed to
+    /// [`FileMapBuilder::with_extra_vars`][with_extra_vars], into the destination name to find the destination
+    /// folder, and into the archive path and archive comment, if there are any.
+    ///
+    /// [with_extra_vars]: ./struct.FileMapBuilder.html#method.with_extra_vars
+    pub fn format_destination(self) -> Result<DestinationFormatted> {
+        let mut config = self.builder.config.clone();
+        config.vars.extend(self.builder.extra_vars.clone());
+        let format = |template: &str| format_template(&config, template);
+
+        let dest_dir = join_paths(&self.builder.root_dir, [format(&config.destination.name)?]);
+        let archive_path = match config.destination.archive_path {
+            Some(ref template) => Some(join_paths(&self.builder.root_dir, [format(template)?])),
+            None => None,
+        };
+        let archive_comment = match self
+            .builder
+            .archive_comment
+            .as_ref()
+            .or(config.destination.archive_comment.as_ref())
+        {
+            Some(template) => Some(format(template)?),
+            None => None,
+        };
+
+        Ok(DestinationFormatted {
+            builder: self.builder,
+            dest_dir,
+            archive_path,
+            archive_comment,
+            pairs: self.pairs,
+            warnings: self.warnings,
+        })
+    }
+}
+
+/// Output of [`LocationsPaired::format_destination`][format_destination].
+///
+/// [format_destination]: ./struct.LocationsPaired.html#method.format_destination
+#[derive(Clone, Debug)]
+pub struct DestinationFormatted {
+    builder: FileMapBuilder,
+    dest_dir: PathBuf,
+    archive_path: Option<PathBuf>,
+    archive_comment: Option<String>,
+    pairs: BTreeMap<String, (ExpandedSource, DestLoc)>,
+    warnings: Vec<FileMapWarning>,
+}
+
+impl DestinationFormatted {
+    /// The folder that all files will be copied into.
+    pub fn dest_dir(&self) -> &Path {
+        &self.dest_dir
+    }
+
+    /// Where to write the archive, if not alongside the destination folder.
+    pub fn archive_path(&self) -> Option<&Path> {
+        self.archive_path.as_deref()
+    }
+
+    /// Each source paired with its destination location, keyed by the source's key.
+    pub fn pairs(&self) -> &BTreeMap<String, (ExpandedSource, DestLoc)> {
+        &self.pairs
+    }
+
+    /// Work out the destination of every individual source file. Files matched in a folder keep their path relative
+    /// to that folder, with their name changed if the source has a rename pattern, and single files keep their file
+    /// name unless they are given a file location. A source with several folders as its location has its files copied
+    /// into each of them.
+    pub fn flatten_locations(self) -> LocationsFlattened {
+        let dest_dir = &self.dest_dir;
+        let mut map = Vec::new();
+        let mut renamed = HashSet::new();
+        let mut mtimes = BTreeMap::new();
+        let mut utf8_files = BTreeSet::new();
+
+        for (key, (source, location)) in &self.pairs {
+            let requires_utf8 = self
+                .builder
+                .config
+                .sources
+                .get(key)
+                .is_some_and(Source::requires_utf8);
+
+            if requires_utf8 {
+                match source {
+                    ExpandedSource::File(file) => {
+                        utf8_files.insert(file.clone());
+                    }
+                    ExpandedSource::FileMatches { items, .. } => {
+                        utf8_files.extend(items.iter().cloned());
+                    }
+                }
+            }
+
+            let folders = match location {
+                DestLoc::File(dst) => {
+                    // File locations for folders are rejected when the config is parsed, so treat any that get here
+                    // as folders.
+                    if let ExpandedSource::File(file) = source {
+                        map.push((file.clone(), join_paths(dest_dir, [dst])));
+                        continue;
+                    }
+
+                    std::slice::from_ref(dst)
+                }
+                DestLoc::Folder(folder) | DestLoc::Template(folder) => std::slice::from_ref(folder),
+                DestLoc::Multiple(folders) => folders.as_slice(),
+            };
+
+            for folder in folders {
+                match source {
+                    ExpandedSource::File(file) => {
+                        let dst = join_paths(
+                            dest_dir,
+                            [
+                                location_folder(location, folder, file).as_path(),
+                                Path::new(file.file_name().unwrap_or_default()),
+                            ],
+                        );
+                        map.push((file.clone(), dst));
+                    }
+                    ExpandedSource::FileMatches {
+                        base,
+                        items,
+                        rename,
+                        mtime,
+                    } => {
+                        for item in items {
+                            let folder = location_folder(location, folder, item);
+                            let mut dst = match item.strip_prefix(base) {
+                                Ok(relative) => join_paths(dest_dir, [folder.as_path(), relative]),
+                                Err(_) => join_paths(
+                                    dest_dir,
+                                    [
+                                        folder.as_path(),
+                                        Path::new(item.file_name().unwrap_or_default()),
+                                    ],
+                                ),
+                            };
+
+                            let new_name = rename.as_ref().and_then(|rename| {
+                                rename
+                                    .apply(&item.file_name().unwrap_or_default().to_string_lossy())
+                            });
+
+                            if let Some(new_name) = new_name {
+                                dst.set_file_name(new_name);
+                                renamed.insert(dst.clone());
+                            }
+
+                            if let Some(mtime) = mtime {
+                                mtimes.insert(item.clone(), *mtime);
+                            }
+
+                            map.push((item.clone(), dst));
+                        }
+                    }
+                }
+            }
+        }
+
+        LocationsFlattened {
+            builder: self.builder,
+            dest_dir: self.dest_dir,
+            archive_path: self.archive_path,
+            archive_comment: self.archive_comment,
+            map,
+            renamed,
+            mtimes,
+            utf8_files,
+            warnings: self.warnings,
+        }
+    }
+}
+
+/// Output of [`DestinationFormatted::flatten_locations`][flatten_locations].
+///
+/// [flatten_locations]: ./struct.DestinationFormatted.html#method.flatten_locations
+#[derive(Clone, Debug)]
+pub struct LocationsFlattened {
+    builder: FileMapBuilder,
+    dest_dir: PathBuf,
+    archive_path: Option<PathBuf>,
+    archive_comment: Option<String>,
+    map: Vec<(PathBuf, PathBuf)>,
+    /// The destinations whose file name was changed by a rename pattern.
+    renamed: HashSet<PathBuf>,
+    /// The modification time to archive each source file with, if not its own.
+    mtimes: BTreeMap<PathBuf, SystemTime>,
+    /// The source files that should be valid UTF-8.
+    utf8_files: BTreeSet<PathBuf>,
+    warnings: Vec<FileMapWarning>,
+}
+
+impl LocationsFlattened {
+    /// The folder that all files will be copied into.
+    pub fn dest_dir(&self) -> &Path {
+        &self.dest_dir
+    }
+
+    /// Pairs of source and destination paths.
+    pub fn map(&self) -> &Vec<(PathBuf, PathBuf)> {
+        &self.map
+    }
+
+    /// A mutable reference to [`map`][map], so that it can be adjusted before the next stage.
+    ///
+    /// [map]: #method.map
+    pub fn map_mut(&mut self) -> &mut Vec<(PathBuf, PathBuf)> {
+        &mut self.map
+    }
+
+    /// Check that no two different source files would be copied to the same destination, since the second would
+    /// silently overwrite the first.
+    pub fn verify_no_conflicts(self) -> Result<ConflictsVerified> {
+        let mut claims: HashMap<&Path, Vec<&Path>> = HashMap::new();
+
+        for (src, dst) in &self.map {
+            let sources = claims.entry(dst.as_path()).or_default();
+
+            if !sources.contains(&src.as_path()) {
+                sources.push(src.as_path());
+            }
+        }
+
+        // Report the first conflicting destination in map order, so that the error is deterministic.
+        let conflict = self
+            .map
+            .iter()
+            .map(|(_, dst)| dst)
+            .find(|dst| claims[dst.as_path()].len() > 1);
+
+        if let Some(dst) = conflict {
+            let path = dst.clone();
+            let sources = claims[dst.as_path()]
+                .iter()
+                .map(|src| src.to_path_buf())
+                .collect();
+
+            // A clash caused by renaming is reported separately, since the fix is to change the rename pattern.
+            return Err(if self.renamed.contains(dst) {
+                FileMapError::RenameConflict { path, sources }
+            } else {
+                FileMapError::DestinationConflict { path, sources }
+            });
+        }
+
+        Ok(ConflictsVerified {
+            builder: self.builder,
+            dest_dir: self.dest_dir,
+            archive_path: self.archive_path,
+            archive_comment: self.archive_comment,
+            map: self.map,
+            mtimes: self.mtimes,
+            utf8_files: self.utf8_files,
+            warnings: self.warnings,
+        })
+    }
+}
+
+/// Output of [`LocationsFlattened::verify_no_conflicts`][verify_no_conflicts].
+///
+/// [verify_no_conflicts]: ./struct.LocationsFlattened.html#method.verify_no_conflicts
+#[derive(Clone, Debug)]
+pub struct ConflictsVerified {
+    builder: FileMapBuilder,
+    dest_dir: PathBuf,
+    archive_path: Option<PathBuf>,
+    archive_comment: Option<String>,
+    map: Vec<(PathBuf, PathBuf)>,
+    mtimes: BTreeMap<PathBuf, SystemTime>,
+    utf8_files: BTreeSet<PathBuf>,
+    warnings: Vec<FileMapWarning>,
+}
+
+impl ConflictsVerified {
+    /// The folder that all files will be copied into.
+    pub fn dest_dir(&self) -> &Path {
+        &self.dest_dir
+    }
+
+    /// Pairs of source and destination paths.
+    pub fn map(&self) -> &Vec<(PathBuf, PathBuf)> {
+        &self.map
+    }
+
+    /// Check that every source file exists, producing the finished `FileMap` if so. Files from sources with `encoding`
+    /// set to UTF-8 are then read, and a [`FileMapWarning::NonUtf8File`][non_utf8] is added to the map's
+    /// [`warnings`][warnings] for each one that isn't valid UTF-8, without failing the build.
+    ///
+    /// [non_utf8]: ./enum.FileMapWarning.html#variant.NonUtf8File
+    /// [warnings]: ./struct.FileMap.html#method.warnings
+    pub fn verify_existence(self) -> Result<FileMap> {
+        let nonexistent: Vec<(PathBuf, io::Error)> = self
+            .map
+            .iter()
+            .filter_map(|(src, _)| fs::metadata(src).err().map(|err| (src.clone(), err)))
+            .collect();
+
+        if !nonexistent.is_empty() {
+            return Err(FileMapError::NonexistentFiles(nonexistent));
+        }
+
+        let warnings = self.encoding_warnings()?;
+        let mut file_map = self.into_file_map();
+        file_map.warnings.extend(warnings);
+
+        Ok(file_map)
+    }
+
+    /// A warning for each source file that should be valid UTF-8 but isn't.
+    fn encoding_warnings(&self) -> Result<Vec<FileMapWarning>> {
+        let mut warnings = Vec::new();
+
+        for path in &self.utf8_files {
+            if std::str::from_utf8(&fs::read(path)?).is_err() {
+                warnings.push(FileMapWarning::NonUtf8File { path: path.clone() });
+            }
+        }
+
+        Ok(warnings)
+    }
+
+    /// Produce the finished `FileMap` without checking that the source files exist.
+    pub fn into_file_map(self) -> FileMap {
+        let destination = &self.builder.config.destination;
+        let temp_dir = destination
+            .temp_dir
+            .as_ref()
+            .map(|dir| join_paths(&self.builder.root_dir, [dir]));
+
+        FileMap {
+            root_dir: self.builder.root_dir,
+            dest_dir: self.dest_dir,
+            archive: destination.archive,
+            archive_path: self.archive_path,
+            overwrite: destination.overwrite,
+            manifest: destination.manifest,
+            checksum_algorithm: destination.checksum_algorithm,
+            post_copy: destination.post_copy.clone(),
+            compression_level: destination.compression_level,
+            archive_comment: self.archive_comment,
+            temp_dir,
+            logger: self.builder.logger,
+            mtimes: self.mtimes,
+            warnings: self.warnings,
+            map: self.map,
+        }
+    }
+}
+
+/// The files matched by each source, keyed by the source's key, as returned by
+/// [`FileMapBuilder::matched_files`][matched_files].
+///
+/// [matched_files]: ./struct.FileMapBuilder.html#method.matched_files
+pub type MatchedFiles = BTreeMap<String, Vec<PathBuf>>;
+
+/// Convenience alias for functions that return [`FileMapError`][error]s.
+///
+/// [error]: ./enum.FileMapError.html
+pub type Result<T> = std::result::Result<T, FileMapError>;
+
+/// Errors to do with building and executing a [`FileMap`][filemap].
+///
+/// [filemap]: ./struct.FileMap.html
+#[derive(Debug)]
+pub enum FileMapError {
+    /// Wraps a [`std::io::Error`][ioerr].
+    ///
+    /// [ioerr]: https://doc.rust-lang.org/std/io/struct.Error.html
+    Io(std::io::Error),
+    /// A source folder's glob pattern could not be parsed.
+    Pattern { err: glob::PatternError },
+    /// The destination name could not be formatted.
+    FormatError(strfmt::FmtError),
+    /// The source with this key has no matching entry in `destination.locations`.
+    MissingDest { key: String },
+    /// These source files could not be found, each with the error from trying to access it.
+    NonexistentFiles(Vec<(PathBuf, io::Error)>),
+    /// Wraps a [`glob::GlobError`][globerr] from reading a folder matched by a glob pattern.
+    ///
+    /// [globerr]: ../../glob/struct.GlobError.html
+    Glob(glob::GlobError),
+    /// Wraps a [`walkdir::Error`][walkdirerr] from walking a recursive folder.
+    ///
+    /// [walkdirerr]: ../../walkdir/struct.Error.html
+    Walk(walkdir::Error),
+    /// Wraps a [`reqwest::Error`][reqwesterr] from downloading a URL source.
+    ///
+    /// [reqwesterr]: ../../reqwest/struct.Error.html
+    Download(reqwest::Error),
+    /// A downloaded file's SHA-256 checksum did not match the one given in the config.
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    /// The destination name refers to an environment variable that is not set.
+    MissingEnvVar {
+        name: String,
+        source: std::env::VarError,
+    },
+    /// Wraps a [`zip::result::ZipError`][ziperr].
+    ///
+    /// [ziperr]: ../../zip/result/enum.ZipError.html
+    Zip(zip::result::ZipError),
+    /// Writing the archive directly was requested, but the destination isn't archived, or its archive can't be written
+    /// without the destination folder.
+    ArchiveOnlyUnsupported,
+    /// The source files add up to more than the size limit, in bytes.
+    SizeLimit { limit: u64, actual: u64 },
+    /// More than one source file would be copied to the same destination path.
+    DestinationConflict {
+        path: PathBuf,
+        sources: Vec<PathBuf>,
+    },
+    /// The `post_copy` hook exited unsuccessfully.
+    HookFailed { exit_code: i32, command: String },
+    /// The destination folder already exists, and the overwrite policy said not to copy into it.
+    DestinationExists { path: PathBuf },
+    /// A source folder's glob pattern matched no files, and the source doesn't allow that with `allow_empty`.
+    NoMatches { pattern: String },
+    /// A source folder's rename pattern could not be parsed as a regular expression.
+    RenamePattern { err: regex::Error },
+    /// Renaming files with a source folder's rename pattern would give more than one source file the same destination.
+    RenameConflict {
+        path: PathBuf,
+        sources: Vec<PathBuf>,
+    },
+    /// The archive can't be streamed in this format.
+    StreamingUnsupported { format: ArchiveMode },
+    /// The config file at `path` could not be read.
+    Config { path: PathBuf, err: config::Error },
+    /// The copy at `dest` doesn't have the same contents as `source`.
+    IntegrityFailure { source: PathBuf, dest: PathBuf },
+    /// The existing file at `path` could not be moved out of the way before packing.
+    BackupFailed { path: PathBuf, err: io::Error },
+    /// The output path `path` is the root directory or outside it, so it won't be deleted.
+    OutputOutsideRoot { path: PathBuf },
+    /// The prefix for every destination is absolute or contains `..`, so it would lead outside the destination folder.
+    InvalidPrefix { prefix: PathBuf },
+    /// The existing archive can't be added to in this format.
+    AppendUnsupported { format: ArchiveMode },
+    /// The `BATHPACK_*` environment variables could not be read into the config.
+    EnvConfig { err: config::Error },
+    /// A template variable given on the command line as `arg` could not be set, for the given reason.
+    InvalidSetVar { arg: String, reason: String },
+}
+
+impl FileMapError {
+    /// A short, stable code identifying the kind of error, e.g. `E005` for a missing source file, for tools that
+    /// handle errors without parsing their messages.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            FileMapError::Io(_) => "E001",
+            FileMapError::Pattern { .. } => "E002",
+            FileMapError::FormatError(_) => "E003",
+            FileMapError::MissingDest { .. } => "E004",
+            FileMapError::NonexistentFiles(_) => "E005",
+            FileMapError::Glob(_) => "E006",
+            FileMapError::Walk(_) => "E007",
+            FileMapError::Download(_) => "E008",
+            FileMapError::ChecksumMismatch { .. } => "E009",
+            FileMapError::MissingEnvVar { .. } => "E010",
+            FileMapError::Zip(_) => "E011",
+            FileMapError::ArchiveOnlyUnsupported => "E013",
+            FileMapError::SizeLimit { .. } => "E014",
+            FileMapError::DestinationConflict { .. } => "E015",
+            FileMapError::HookFailed { .. } => "E016",
+            FileMapError::DestinationExists { .. } => "E017",
+            FileMapError::NoMatches { .. } => "E018",
+            FileMapError::RenamePattern { .. } => "E019",
+            FileMapError::RenameConflict { .. } => "E020",
+            FileMapError::StreamingUnsupported { .. } => "E021",
+            FileMapError::Config { .. } => "E022",
+            FileMapError::IntegrityFailure { .. } => "E023",
+            FileMapError::BackupFailed { .. } => "E024",
+            FileMapError::OutputOutsideRoot { .. } => "E025",
+            FileMapError::InvalidPrefix { .. } => "E026",
+            FileMapError::EnvConfig { .. } => "E027",
+            FileMapError::InvalidSetVar { .. } => "E028",
+            FileMapError::AppendUnsupported { .. } => "E029",
+        }
+    }
+
+    /// The files this error is about, if any.
+    pub fn files(&self) -> Vec<&Path> {
+        match *self {
+            FileMapError::NonexistentFiles(ref files) => {
+                files.iter().map(|(file, _)| file.as_path()).collect()
+            }
+            FileMapError::Glob(ref glob_err) => vec![glob_err.path()],
+            FileMapError::Walk(ref walkdir_err) => walkdir_err.path().into_iter().collect(),
+            FileMapError::DestinationExists { ref path }
+            | FileMapError::Config { ref path, .. }
+            | FileMapError::BackupFailed { ref path, .. }
+            | FileMapError::OutputOutsideRoot { ref path } => vec![path],
+            FileMapError::DestinationConflict {
+                ref path,
+                ref sources,
+            }
+            | FileMapError::RenameConflict {
+                ref path,
+                ref sources,
+            } => std::iter::once(path)
+                .chain(sources)
+                .map(PathBuf::as_path)
+                .collect(),
+            FileMapError::IntegrityFailure {
+                ref source,
+                ref dest,
+            } => vec![source, dest],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for FileMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileMapError::Io(ref io_err) => write!(f, "{}", io_err),
+            FileMapError::Pattern { ref err } => write!(f, "Invalid glob pattern: {}", err),
+            FileMapError::FormatError(ref fmt_err) => {
+                write!(f, "Could not format destination name: {}", fmt_err)
+            }
+            FileMapError::MissingDest { ref key } => {
+                write!(f, "No destination location for source `{}`", key)
+            }
+            FileMapError::NonexistentFiles(ref files) => {
+                write!(f, "The following files do not exist:")?;
+
+                for (file, err) in files {
+                    write!(f, "\n    {} ({})", file.display(), err)?;
+                }
+
+                Ok(())
+            }
+            FileMapError::Glob(ref glob_err) => write!(f, "Could not match pattern: {}", glob_err),
+            FileMapError::Walk(ref walkdir_err) => {
+                write!(f, "Could not read folder: {}", walkdir_err)
+            }
+            FileMapError::Download(ref reqwest_err) => {
+                write!(f, "Could not download source: {}", reqwest_err)
+            }
+            FileMapError::ChecksumMismatch {
+                ref url,
+                ref expected,
+                ref actual,
+            } => write!(
+                f,
+                "Checksum mismatch for {}: expected sha256 {}, got {}",
+                url, expected, actual
+            ),
+            FileMapError::MissingEnvVar { ref name, .. } => {
+                write!(
+                    f,
+                    "Environment variable `{}` used in destination name is not set",
+                    name
+                )
+            }
+            FileMapError::Zip(ref zip_err) => write!(f, "Could not write archive: {}", zip_err),
+            FileMapError::ArchiveOnlyUnsupported => write!(
+                f,
+                "Can't write the archive directly, because the destination is not archived"
+            ),
+            FileMapError::SizeLimit { limit, actual } => write!(
+                f,
+                "The source files add up to {} bytes, over the limit of {} bytes",
+                actual, limit
+            ),
+            FileMapError::DestinationConflict {
+                ref path,
+                ref sources,
+            } => {
+                write!(
+                    f,
+                    "More than one source file would be copied to {}:",
+                    path.display()
+                )?;
+
+                for source in sources {
+                    write!(f, "\n    {}", source.display())?;
+                }
+
+                Ok(())
+            }
+            FileMapError::HookFailed {
+                exit_code,
+                ref command,
+            } => write!(f, "Hook `{}` failed with exit code {}", command, exit_code),
+            FileMapError::DestinationExists { ref path } => write!(
+                f,
+                "Destination folder {} already exists, so nothing was copied",
+                path.display()
+            ),
+            FileMapError::NoMatches { ref pattern } => write!(
+                f,
+                "No files match {} (set `allow_empty = true` on the source if this is expected)",
+                pattern
+            ),
+            FileMapError::RenamePattern { ref err } => write!(f, "Invalid rename pattern: {}", err),
+            FileMapError::RenameConflict {
+                ref path,
+                ref sources,
+            } => {
+                write!(
+                    f,
+                    "Renaming would give more than one source file the destination {}:",
+                    path.display()
+                )?;
+
+                for source in sources {
+                    write!(f, "\n    {}", source.display())?;
+                }
+
+                Ok(())
+            }
+            FileMapError::StreamingUnsupported { format } => match format.extension() {
+                Some(extension) => {
+                    write!(f, "Archives in the {} format can't be streamed", extension)
+                }
+                None => write!(
+                    f,
+                    "Archiving is turned off, so there is no archive to stream"
+                ),
+            },
+            FileMapError::AppendUnsupported { format } => match format.extension() {
+                Some(extension) => {
+                    write!(
+                        f,
+                        "Archives in the {} format can't be appended to",
+                        extension
+                    )
+                }
+                None => write!(
+                    f,
+                    "Archiving is turned off, so there is no archive to append to"
+                ),
+            },
+            FileMapError::Config { ref path, ref err } => {
+                write!(f, "Could not read {}: {}", path.display(), err)
+            }
+            FileMapError::IntegrityFailure {
+                ref source,
+                ref dest,
+            } => write!(
+                f,
+                "{} does not match its source {}",
+                dest.display(),
+                source.display()
+            ),
+            FileMapError::BackupFailed { ref path, ref err } => {
+                write!(f, "Could not back up {}: {}", path.display(), err)
+            }
+            FileMapError::OutputOutsideRoot { ref path } => write!(
+                f,
+                "Refusing to remove {}, because it is not inside the project folder",
+                path.display()
+            ),
+            FileMapError::InvalidPrefix { ref prefix } => write!(
+                f,
+                "Invalid prefix {}: it must be a relative path without `..`",
+                prefix.display()
+            ),
+            FileMapError::EnvConfig { ref err } => {
+                write!(f, "Could not read config from the environment: {}", err)
+            }
+            FileMapError::InvalidSetVar {
+                ref arg,
+                ref reason,
+            } => write!(f, "Invalid --set-var `{}`: {}", arg, reason),
+        }
+    }
+}
+
+impl std::error::Error for FileMapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            FileMapError::Io(ref io_err) => Some(io_err),
+            FileMapError::Pattern { ref err } => Some(err),
+            FileMapError::RenamePattern { ref err } => Some(err),
+            FileMapError::FormatError(ref fmt_err) => Some(fmt_err),
+            FileMapError::Download(ref reqwest_err) => Some(reqwest_err),
+            FileMapError::Zip(ref zip_err) => Some(zip_err),
+            FileMapError::NonexistentFiles(ref files) => files.first().map(|(_, err)| err as _),
+            FileMapError::Glob(ref glob_err) => Some(glob_err),
+            FileMapError::Walk(ref walkdir_err) => Some(walkdir_err),
+            FileMapError::MissingEnvVar { ref source, .. } => Some(source),
+            FileMapError::Config { ref err, .. } | FileMapError::EnvConfig { ref err } => Some(err),
+            FileMapError::BackupFailed { ref err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FileMapError {
+    fn from(io_error: std::io::Error) -> Self {
+        FileMapError::Io(io_error)
+    }
+}
+
+impl From<glob::GlobError> for FileMapError {
+    fn from(glob_error: glob::GlobError) -> Self {
+        FileMapError::Glob(glob_error)
+    }
+}
+
+impl From<walkdir::Error> for FileMapError {
+    fn from(walkdir_error: walkdir::Error) -> Self {
+        FileMapError::Walk(walkdir_error)
+    }
+}
+
+impl From<glob::PatternError> for FileMapError {
+    fn from(err: glob::PatternError) -> Self {
+        FileMapError::Pattern { err }
+    }
+}
+
+impl From<reqwest::Error> for FileMapError {
+    fn from(reqwest_error: reqwest::Error) -> Self {
+        FileMapError::Download(reqwest_error)
+    }
+}
+
+impl From<zip::result::ZipError> for FileMapError {
+    fn from(zip_error: zip::result::ZipError) -> Self {
+        FileMapError::Zip(zip_error)
+    }
+}
+
+impl From<strfmt::FmtError> for FileMapError {
+    fn from(fmt_error: strfmt::FmtError) -> Self {
+        FileMapError::FormatError(fmt_error)
+    }
+}
+
+/// Something about the source files that the user should know about, but that doesn't stop them being packaged.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FileMapWarning {
+    /// This file is from a source with `encoding = "utf-8"`, but isn't valid UTF-8.
+    NonUtf8File { path: PathBuf },
+    /// The archive at `path` can't be appended to in its format, so it is being written again from scratch.
+    ArchiveRebuilt { path: PathBuf, format: ArchiveMode },
+    /// The source with this key matched files, but none of them were modified since the time given to
+    /// [`FileMapBuilder::with_modified_since`][modified_since].
+    ///
+    /// [modified_since]: ./struct.FileMapBuilder.html#method.with_modified_since
+    NoFilesModifiedSince { key: String },
+    /// A matched file's path is longer than its source's `max_path_length`, so it was left out.
+    PathTooLong {
+        path: PathBuf,
+        limit: usize,
+        actual: usize,
+    },
+    /// The `post_copy` hook `command` wasn't run, because only the archive was written.
+    HookSkipped { command: String },
+}
+
+impl fmt::Display for FileMapWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileMapWarning::NonUtf8File { ref path } => {
+                write!(f, "{} is not valid UTF-8", path.display())
+            }
+            FileMapWarning::ArchiveRebuilt { ref path, format } => write!(
+                f,
+                "{} archives can't be appended to in place, so {} is being rebuilt",
+                format.extension().unwrap_or("These"),
+                path.display()
+            ),
+            FileMapWarning::NoFilesModifiedSince { ref key } => write!(
+                f,
+                "Source `{}` has no files modified since the given date",
+                key
+            ),
+            FileMapWarning::PathTooLong {
+                ref path,
+                limit,
+                actual,
+            } => write!(
+                f,
+                "Skipping {}, which is {} characters long, over the limit of {}",
+                path.display(),
+                actual,
+                limit
+            ),
+            FileMapWarning::HookSkipped { ref command } => write!(
+                f,
+                "Skipped post-copy hook `{}`: there is no destination folder to run it in",
+                command
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Destination;
+
+    use tempfile::TempDir;
+
+    /// Create a temporary project folder containing `src/Main.java`, `src/util/Util.java`, `src/notes.txt` and
+    /// `README.md`.
+    fn project() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(dir.path().join("src/util")).unwrap();
+        fs::write(dir.path().join("src/Main.java"), "class Main {}").unwrap();
+        fs::write(dir.path().join("src/util/Util.java"), "class Util {}").unwrap();
+        fs::write(dir.path().join("src/notes.txt"), "notes").unwrap();
+        fs::write(dir.path().join("README.md"), "# Project").unwrap();
+
+        dir
+    }
+
+    /// Parse `toml_str` and build a `FileMap` from it, rooted at `root_dir`.
+    fn build(toml_str: &str, root_dir: &Path) -> Result<FileMap> {
+        let config = Config::parse(toml_str).unwrap();
+        FileMapBuilder::from(config, root_dir.to_path_buf()).build()
+    }
+
+    /// Test that folder and file sources are mapped to the correct destinations.
+    #[test]
+    fn build_map() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let dest_dir = root.join("test-user987");
+        assert_eq!(file_map.root_dir(), root);
+        assert_eq!(file_map.dest_dir(), dest_dir);
+        assert!(!file_map.archive_enabled());
+        assert_eq!(
+            file_map.map,
+            vec![
+                (root.join("README.md"), dest_dir.join("README.md")),
+                (root.join("src/Main.java"), dest_dir.join("code/Main.java")),
+                (
+                    root.join("src/util/Util.java"),
+                    dest_dir.join("code/util/Util.java")
+                ),
+            ]
+        );
+    }
+
+    /// Test that `overwrite = "skip"` refuses to copy into an existing destination folder, and that the default policy
+    /// copies into it.
+    #[test]
+    fn overwrite_policy() {
+        let dir = project();
+        let root = dir.path();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+            overwrite = "skip"
+
+            [destination.locations]
+            readme = "."
+        "#;
+
+        let file_map = build(toml_str, root).unwrap();
+        file_map.execute().unwrap();
+        assert!(root.join("test-user987/README.md").exists());
+
+        fs::write(root.join("README.md"), "# Updated").unwrap();
+        match file_map.execute() {
+            Err(FileMapError::DestinationExists { path }) => {
+                assert_eq!(path, root.join("test-user987"))
+            }
+            other => panic!("expected DestinationExists, got {:?}", other),
+        }
+        assert_eq!(
+            fs::read_to_string(root.join("test-user987/README.md")).unwrap(),
+            "# Project"
+        );
+
+        let file_map = build(&toml_str.replace("overwrite = \"skip\"", ""), root).unwrap();
+        file_map.execute().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("test-user987/README.md")).unwrap(),
+            "# Updated"
+        );
+    }
+
+    /// Test that `source_count`, `len`, `total_size` and `Display` summarise the source files, that `total_size` fails
+    /// if one of them has gone, and that `contains_source_path` and `contains_dest_path` check the right side of the
+    /// map.
+    #[test]
+    fn source_count_and_total_size() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        assert_eq!(file_map.source_count(), 3);
+        assert_eq!(file_map.len(), 3);
+        assert!(!file_map.is_empty());
+        assert!(file_map.filter(|_, _| false).is_empty());
+        assert_eq!(file_map.total_size().unwrap(), 35);
+        assert_eq!(
+            file_map.to_string(),
+            format!(
+                "3 files → {} (archive: no)",
+                root.join("test-user987").display()
+            )
+        );
+
+        let counts = file_map.pair_count_by_dest_dir();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[&root.join("test-user987")], 1);
+        assert_eq!(counts[&root.join("test-user987/code")], 1);
+        assert_eq!(counts[&root.join("test-user987/code/util")], 1);
+
+        assert!(file_map.contains_source_path(&root.join("src/Main.java")));
+        assert!(!file_map.contains_source_path(&root.join("src/notes.txt")));
+        assert!(file_map.contains_dest_path(&root.join("test-user987/code/Main.java")));
+        assert!(!file_map.contains_dest_path(&root.join("src/Main.java")));
+
+        fs::remove_file(root.join("README.md")).unwrap();
+        assert!(file_map.total_size().is_err());
+    }
+
+    /// Test that the pipeline can be run one stage at a time, with the state between stages adjusted along the way.
+    #[test]
+    fn build_step_by_step() {
+        let dir = project();
+        let root = dir.path();
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+            readme = "."
+        "#,
+        )
+        .unwrap();
+
+        let expanded = FileMapBuilder::from(config, root.to_path_buf())
+            .expand_paths()
+            .verify_patterns()
+            .unwrap()
+            .expand_sources()
+            .unwrap();
+        assert!(matches!(
+            expanded.sources()["readme"],
+            ExpandedSource::File(_)
+        ));
+
+        let mut paired = expanded.pair_locations().unwrap();
+        paired.pairs_mut().remove("src");
+
+        let formatted = paired.format_destination().unwrap();
+        assert_eq!(formatted.dest_dir(), root.join("test-user987"));
+        assert_eq!(formatted.archive_path(), None);
+
+        let file_map = formatted
+            .flatten_locations()
+            .verify_no_conflicts()
+            .unwrap()
+            .verify_existence()
+            .unwrap();
+        assert_eq!(
+            file_map.map,
+            vec![(root.join("README.md"), root.join("test-user987/README.md"))]
+        );
+    }
+
+    /// Test that `matched_files` returns the files matched by each source, without needing destination locations.
+    #[test]
+    fn matched_files() {
+        let dir = project();
+        let root = dir.path();
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+        "#,
+        )
+        .unwrap();
+
+        let (matched, warnings) = FileMapBuilder::from(config, root.to_path_buf())
+            .matched_files()
+            .unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(matched["readme"], vec![root.join("README.md")]);
+        assert_eq!(
+            matched["src"],
+            vec![root.join("src/Main.java"), root.join("src/util/Util.java")]
+        );
+    }
+
+    /// Test that a single file with a file location is copied to exactly that path.
+    #[test]
+    fn build_file_location() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            readme = { file = "docs/INDEX.md" }
+        "#,
+            root,
+        )
+        .unwrap();
+
+        assert_eq!(
+            file_map.map,
+            vec![(
+                root.join("README.md"),
+                root.join("test-user987/docs/INDEX.md")
+            )]
+        );
+    }
+
+    /// Test that a recursive folder matches every file inside it, but not the folders.
+    #[test]
+    fn build_recursive_folder() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let dest_dir = root.join("test-user987");
+        assert_eq!(
+            file_map.map,
+            vec![
+                (root.join("src/Main.java"), dest_dir.join("code/Main.java")),
+                (root.join("src/notes.txt"), dest_dir.join("code/notes.txt")),
+                (
+                    root.join("src/util/Util.java"),
+                    dest_dir.join("code/util/Util.java")
+                ),
+            ]
+        );
+    }
+
+    /// Test that absolute source paths are used as they are, rather than being put inside the root directory.
+    #[test]
+    fn build_absolute_source() {
+        let dir = project();
+        let root = dir.path();
+        let elsewhere = tempfile::tempdir().unwrap();
+        fs::write(elsewhere.path().join("data.csv"), "a,b").unwrap();
+        fs::create_dir_all(elsewhere.path().join("lib")).unwrap();
+        fs::write(elsewhere.path().join("lib/Lib.java"), "class Lib {}").unwrap();
+
+        let toml_str = format!(
+            r#"
+            username = "user987"
+
+            [sources]
+            data = "{dir}/data.csv"
+            lib = {{ path = "{dir}/lib", pattern = "*.java" }}
+
+            [destination]
+            name = "test-{{username}}"
+            archive = false
+
+            [destination.locations]
+            data = "."
+            lib = "lib"
+        "#,
+            dir = elsewhere.path().display()
+        );
+
+        let file_map = build(&toml_str, root).unwrap();
+
+        let dest_dir = root.join("test-user987");
+        assert_eq!(
+            file_map.map,
+            vec![
+                (elsewhere.path().join("data.csv"), dest_dir.join("data.csv")),
+                (
+                    elsewhere.path().join("lib/Lib.java"),
+                    dest_dir.join("lib/Lib.java")
+                ),
+            ]
+        );
+    }
+
+    /// Test that a glob source matches relative to the root directory, and that its matches keep their path relative
+    /// to it.
+    #[test]
+    fn build_glob() {
+        let dir = project();
+        let root = dir.path();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            java = { glob = "src/**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            java = "code"
+        "#;
+
+        let file_map = build(toml_str, root).unwrap();
+
+        let dest_dir = root.join("test-user987");
+        assert_eq!(
+            file_map.map,
+            vec![
+                (
+                    root.join("src/Main.java"),
+                    dest_dir.join("code/src/Main.java")
+                ),
+                (
+                    root.join("src/util/Util.java"),
+                    dest_dir.join("code/src/util/Util.java")
+                ),
+            ]
+        );
+
+        match build(&toml_str.replace("*.java", "*.rs"), root) {
+            Err(FileMapError::NoMatches { .. }) => {}
+            other => panic!("expected NoMatches, got {:?}", other),
+        }
+    }
+
+    /// Test that `exclude_dirs` skips files inside subfolders with those names, at any depth, for both recursive
+    /// folders and folders with a pattern, but not files that merely share the name.
+    #[test]
+    fn build_exclude_dirs() {
+        let dir = project();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/target/debug")).unwrap();
+        fs::create_dir_all(root.join("src/util/target")).unwrap();
+        fs::write(root.join("src/target/debug/Out.java"), "").unwrap();
+        fs::write(root.join("src/util/target/Gen.java"), "").unwrap();
+        fs::write(root.join("src/util/target.java"), "").unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", exclude_dirs = ["target"] }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#;
+
+        let sources = |file_map: FileMap| -> Vec<PathBuf> {
+            file_map.map.into_iter().map(|(src, _)| src).collect()
+        };
+
+        let file_map = build(toml_str, root).unwrap();
+        assert_eq!(
+            sources(file_map),
+            vec![
+                root.join("src/Main.java"),
+                root.join("src/notes.txt"),
+                root.join("src/util/Util.java"),
+                root.join("src/util/target.java"),
+            ]
+        );
+
+        let file_map = build(
+            &toml_str.replace(
+                "path = \"src\",",
+                "path = \"src\", pattern = \"**/*.java\",",
+            ),
+            root,
+        )
+        .unwrap();
+        assert_eq!(
+            sources(file_map),
+            vec![
+                root.join("src/Main.java"),
+                root.join("src/util/Util.java"),
+                root.join("src/util/target.java"),
+            ]
+        );
+    }
+
+    /// Test that symlinks matched by a folder's pattern are copied as the file they point to, or skipped if
+    /// `follow_symlinks` is turned off.
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks() {
+        let dir = project();
+        let root = dir.path();
+        std::os::unix::fs::symlink(root.join("README.md"), root.join("src/Linked.md")).unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "*.md" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#;
+
+        let file_map = build(toml_str, root).unwrap();
+        file_map.execute().unwrap();
+
+        let copied = root.join("test-user987/Linked.md");
+        assert!(!fs::symlink_metadata(&copied)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&copied).unwrap(), "# Project");
+
+        let err = build(
+            &toml_str.replace(
+                "pattern = \"*.md\"",
+                "pattern = \"*.md\", follow_symlinks = false",
+            ),
+            root,
+        )
+        .unwrap_err();
+        assert!(matches!(err, FileMapError::NoMatches { .. }));
+    }
+
+    /// Test that only files modified after the given time are kept, and that a source can be left empty by it.
+    #[test]
+    fn build_modified_since() {
+        let dir = project();
+        let root = dir.path();
+        let now = SystemTime::now();
+        for (file, age) in &[("src/Main.java", 7200), ("README.md", 7200)] {
+            File::options()
+                .write(true)
+                .open(root.join(file))
+                .unwrap()
+                .set_modified(now - std::time::Duration::from_secs(*age))
+                .unwrap();
+        }
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            docs = { glob = "*.md" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+            docs = "."
+        "#;
+        let config = Config::parse(toml_str).unwrap();
+        let file_map = FileMapBuilder::from(config, root.to_path_buf())
+            .with_modified_since(now - std::time::Duration::from_secs(3600))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            file_map.map,
+            vec![(
+                root.join("src/util/Util.java"),
+                root.join("test-user987/util/Util.java")
+            )]
+        );
+        assert_eq!(
+            file_map.warnings(),
+            &[FileMapWarning::NoFilesModifiedSince {
+                key: "docs".to_string()
+            }]
+        );
+    }
+
+    /// Test that template locations put each file in a folder named after parts of the file's name.
+    #[test]
+    fn build_template_location() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "*.*" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = { template = "by-ext/{ext}" }
+            readme = { template = "docs/{stem}" }
+        "#,
+            root,
+        )
+        .unwrap();
+
+        assert_eq!(
+            file_map.map,
+            vec![
+                (
+                    root.join("README.md"),
+                    root.join("test-user987/docs/README/README.md")
+                ),
+                (
+                    root.join("src/Main.java"),
+                    root.join("test-user987/by-ext/java/Main.java")
+                ),
+                (
+                    root.join("src/notes.txt"),
+                    root.join("test-user987/by-ext/txt/notes.txt")
+                ),
+            ]
+        );
+    }
+
+    /// Test that a folder's matches are put in the order given by `sort`.
+    #[test]
+    fn build_sorted() {
+        let dir = project();
+        let root = dir.path();
+        let old = SystemTime::now() - std::time::Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(root.join("src/util/Util.java"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*", sort = "name" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#;
+
+        let sources = |sort: &str| -> Vec<PathBuf> {
+            let toml_str = toml_str.replace("\"name\"", sort);
+            let file_map = build(&toml_str, root).unwrap();
+            file_map
+                .map
+                .into_iter()
+                .map(|(src, _)| src)
+                .filter(|src| src.is_file())
+                .collect()
+        };
+
+        assert_eq!(
+            sources("\"name\""),
+            vec![
+                root.join("src/Main.java"),
+                root.join("src/notes.txt"),
+                root.join("src/util/Util.java"),
+            ]
+        );
+        assert_eq!(
+            sources("\"size\""),
+            vec![
+                root.join("src/Main.java"),
+                root.join("src/util/Util.java"),
+                root.join("src/notes.txt"),
+            ]
+        );
+        assert_eq!(
+            sources("\"modified\"").last(),
+            Some(&root.join("src/util/Util.java"))
+        );
+    }
+
+    /// Test that the output paths are found without matching any sources, with an archive alongside the folder in
+    /// each format unless an archive path is given.
+    #[test]
+    fn output_paths() {
+        let root = Path::new("/root");
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "missing", pattern = "*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = "zip"
+
+            [destination.locations]
+            src = "."
+        "#;
+
+        let builder = FileMapBuilder::from(Config::parse(toml_str).unwrap(), root.to_path_buf());
+        assert_eq!(
+            builder.output_paths().unwrap(),
+            vec![
+                root.join("test-user987"),
+                root.join("test-user987.zip"),
+                root.join("test-user987.tar.gz"),
+            ]
+        );
+
+        let toml_str = toml_str.replace(
+            "archive = \"zip\"",
+            "archive = \"zip\"\narchive_path = \"out/{username}.zip\"",
+        );
+        let builder = FileMapBuilder::from(Config::parse(&toml_str).unwrap(), root.to_path_buf());
+        assert_eq!(
+            builder.output_paths().unwrap(),
+            vec![root.join("test-user987"), root.join("out/user987.zip")]
+        );
+    }
+
+    /// Test that `clean_paths` refuses a destination that is the root directory or outside it, as with an empty name,
+    /// `.` or `..`, and allows one inside it.
+    #[test]
+    fn clean_paths() {
+        let root = Path::new("/root/project");
+
+        for name in &["", ".", "..", "sub/../..", "/tmp/elsewhere"] {
+            // Built without parsing, since parsing rejects some of these names already.
+            let config = Config::new(
+                "user987",
+                BTreeMap::new(),
+                Destination::new(name, BTreeMap::new()),
+            )
+            .with_archive_format(ArchiveMode::Zip);
+            let builder = FileMapBuilder::from(config, root.to_path_buf());
+
+            assert!(
+                matches!(
+                    builder.clean_paths(),
+                    Err(FileMapError::OutputOutsideRoot { .. })
+                ),
+                "`{}` should be refused",
+                name
+            );
+        }
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "./out/../test-{username}"
+            archive = "zip"
+
+            [destination.locations]
+        "#,
+        )
+        .unwrap();
+        let builder = FileMapBuilder::from(config, root.to_path_buf());
+        assert_eq!(
+            builder.clean_paths().unwrap(),
+            builder.output_paths().unwrap()
+        );
+    }
+
+    /// Test that a builder made from a config file resolves sources relative to the file's folder, and that a file
+    /// that can't be parsed gives an error naming it.
+    #[test]
+    fn from_config_file() {
+        let dir = project();
+        let root = dir.path();
+        let config_file = root.join("bathpack.toml");
+        fs::write(
+            &config_file,
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            readme = "."
+        "#,
+        )
+        .unwrap();
+
+        let file_map = FileMapBuilder::from_config_file(&config_file)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(file_map.root_dir(), root);
+        assert_eq!(
+            file_map.map,
+            vec![(root.join("README.md"), root.join("test-user987/README.md"))]
+        );
+
+        fs::write(&config_file, "username = ").unwrap();
+        let err = FileMapBuilder::from_config_file(&config_file).unwrap_err();
+        assert!(matches!(err, FileMapError::Config { ref path, .. } if *path == config_file));
+        assert!(err.to_string().contains("bathpack.toml"));
+    }
+
+    /// Test that a source with several folders as its location is copied into each of them.
+    #[test]
+    fn build_multiple_locations() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = ["marking", "autograder/src"]
+            readme = ["marking", "."]
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let dest = root.join("test-user987");
+        assert_eq!(
+            file_map.map,
+            vec![
+                (root.join("README.md"), dest.join("marking/README.md")),
+                (root.join("README.md"), dest.join("README.md")),
+                (root.join("src/Main.java"), dest.join("marking/Main.java")),
+                (
+                    root.join("src/Main.java"),
+                    dest.join("autograder/src/Main.java")
+                ),
+            ]
+        );
+    }
+
+    /// Test that files identical to one from an earlier source are left out of a source with `deduplicate` set, but not
+    /// out of other sources.
+    #[test]
+    fn build_deduplicated() {
+        let dir = project();
+        let root = dir.path();
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(root.join("lib/Main.java"), "class Main {}").unwrap();
+        fs::write(root.join("lib/Lib.java"), "class Lib {}").unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            app = { path = "src", pattern = "*.java" }
+            lib = { path = "lib", pattern = "*.java", deduplicate = true }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            app = "app"
+            lib = "lib"
+        "#;
+
+        let sources = |toml_str: &str| -> Vec<PathBuf> {
+            let file_map = build(toml_str, root).unwrap();
+            file_map.map.into_iter().map(|(src, _)| src).collect()
+        };
+
+        assert_eq!(
+            sources(toml_str),
+            vec![root.join("src/Main.java"), root.join("lib/Lib.java")]
+        );
+        assert_eq!(
+            sources(&toml_str.replace(", deduplicate = true", "")),
+            vec![
+                root.join("src/Main.java"),
+                root.join("lib/Lib.java"),
+                root.join("lib/Main.java"),
+            ]
+        );
+    }
+
+    /// Test that `[vars]` entries are substituted into the destination name.
+    #[test]
+    fn build_with_vars() {
+        let dir = project();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "{unit}-{username}"
+            archive = false
+
+            [destination.locations]
+
+            [vars]
+            unit = "cm10228"
+        "#,
+            dir.path(),
+        )
+        .unwrap();
+
+        assert_eq!(file_map.dest_dir, dir.path().join("cm10228-user987"));
+    }
+
+    /// Test that extra variables are substituted into the destination name, overriding `[vars]` but not `username`.
+    #[test]
+    fn build_with_extra_vars() {
+        let dir = project();
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "{unit}-{group}-{username}"
+            archive = false
+
+            [destination.locations]
+
+            [vars]
+            unit = "cm10228"
+            group = "g1"
+        "#,
+        )
+        .unwrap();
+
+        let vars = vec![("group", "g2"), ("username", "someone-else")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        let file_map = FileMapBuilder::from(config, dir.path().to_path_buf())
+            .with_extra_vars(vars)
+            .build()
+            .unwrap();
+
+        assert_eq!(file_map.dest_dir, dir.path().join("cm10228-g2-user987"));
+    }
+
+    /// Test that `{env:NAME}` tokens are replaced with environment variables alongside the other variables.
+    #[test]
+    fn build_with_env_vars() {
+        let dir = project();
+        std::env::set_var("BATHPACK_TEST_ROUND", "round{2}");
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "{username}-{env:BATHPACK_TEST_ROUND}-{{literal}}"
+            archive = false
+
+            [destination.locations]
+        "#,
+            dir.path(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            file_map.dest_dir,
+            dir.path().join("user987-round{2}-{literal}")
+        );
+    }
+
+    /// Test that an `{env:NAME}` token referring to an unset variable fails to build.
+    #[test]
+    fn missing_env_var() {
+        let dir = project();
+        std::env::remove_var("BATHPACK_TEST_UNSET");
+
+        let result = build(
+            r#"
+            username = "user987"
+
+            [sources]
+
+            [destination]
+            name = "{username}-{env:BATHPACK_TEST_UNSET}"
+            archive = false
+
+            [destination.locations]
+        "#,
+            dir.path(),
+        );
+
+        match result {
+            Err(FileMapError::MissingEnvVar { name, source }) => {
+                assert_eq!(name, "BATHPACK_TEST_UNSET");
+                assert_eq!(source, std::env::VarError::NotPresent);
+            }
+            other => panic!("expected MissingEnvVar, got {:?}", other),
+        }
+    }
+
+    /// Serve `body` over HTTP on a local port for a single request, returning the URL of `/data.csv` on that server.
+    fn serve_once(body: &'static str) -> String {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        format!("http://{}/data.csv", addr)
+    }
+
+    /// Test that a URL source is downloaded into the temporary folder, not the project, and mapped like a file source
+    /// when its checksum matches.
+    #[test]
+    fn build_url_source() {
+        let dir = project();
+        let url = serve_once("abc");
+
+        let file_map = build(
+            &format!(
+                r#"
+            username = "user987"
+
+            [sources]
+            data = {{ url = "{}", sha256 = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD" }}
+
+            [destination]
+            name = "test-{{username}}"
+            archive = false
+
+            [destination.locations]
+            data = "data"
+        "#,
+                url
+            ),
+            dir.path(),
+        )
+        .unwrap();
+
+        let (downloaded, dest) = &file_map.map[0];
+        assert!(downloaded.starts_with(std::env::temp_dir()));
+        assert!(downloaded.ends_with("data/data.csv"));
+        assert_eq!(fs::read_to_string(downloaded).unwrap(), "abc");
+        assert_eq!(*dest, dir.path().join("test-user987/data/data.csv"));
+        assert!(!dir.path().join(".bathpack").exists());
+
+        fs::remove_dir_all(downloaded.ancestors().nth(2).unwrap()).unwrap();
+    }
+
+    /// Test that a URL source whose checksum does not match fails to build, and its download is deleted, here from
+    /// `destination.temp_dir`.
+    #[test]
+    fn url_checksum_mismatch() {
+        let dir = project();
+        let url = serve_once("not abc");
+
+        let result = build(
+            &format!(
+                r#"
+            username = "user987"
+
+            [sources]
+            data = {{ url = "{}", sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad" }}
+
+            [destination]
+            name = "test-{{username}}"
+            archive = false
+            temp_dir = "tmp"
+
+            [destination.locations]
+            data = "."
+        "#,
+                url
+            ),
+            dir.path(),
+        );
+
+        let downloads: Vec<PathBuf> = WalkDir::new(dir.path().join("tmp"))
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.is_file())
+            .collect();
+        assert!(downloads.is_empty(), "left behind: {:?}", downloads);
+
+        match result {
+            Err(FileMapError::ChecksumMismatch { expected, .. }) => {
+                assert!(expected.starts_with("ba7816bf"))
+            }
+            other => panic!("expected ChecksumMismatch, got {:?}", other),
+        }
+    }
+
+    /// Test that a source with no matching destination location fails to build.
+    #[test]
+    fn missing_dest() {
+        let dir = project();
+
+        let result = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+        "#,
+            dir.path(),
+        );
+
+        match result {
+            Err(FileMapError::MissingDest { key }) => assert_eq!(key, "readme"),
+            other => panic!("expected MissingDest, got {:?}", other),
+        }
+    }
+
+    /// Test that excluded sources are left out of the map, and don't need a destination location.
+    #[test]
+    fn exclude_keys() {
+        let dir = project();
+        let root = dir.path();
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+        "#,
+        )
+        .unwrap();
+
+        let file_map = FileMapBuilder::from(config, root.to_path_buf())
+            .with_exclude_keys(&["readme"])
+            .build()
+            .unwrap();
+
+        let sources: Vec<&Path> = file_map.map.iter().map(|(src, _)| src.as_path()).collect();
+        assert_eq!(
+            sources,
+            vec![root.join("src/Main.java"), root.join("src/util/Util.java")]
+        );
+
+        // An excluded source is never read, so it doesn't matter that its folder doesn't exist.
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            gone = { path = "nope" }
+            remote = { url = "http://127.0.0.1:9/unreachable.txt" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+        "#,
+        )
+        .unwrap();
+
+        let builder =
+            FileMapBuilder::from(config, root.to_path_buf()).with_exclude_keys(&["gone", "remote"]);
+        assert_eq!(builder.clone().build().unwrap().map.len(), 2);
+        assert!(!root.join(".bathpack").exists());
+        assert_eq!(
+            builder
+                .matched_files()
+                .unwrap()
+                .0
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["src"]
+        );
+    }
+
+    /// Test that a folder whose pattern matches nothing fails to build, unless it allows being empty.
+    #[test]
+    fn no_matches() {
+        let dir = project();
+        let root = dir.path();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "*.kt" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#;
+
+        match build(toml_str, root) {
+            Err(FileMapError::NoMatches { pattern }) => assert!(pattern.ends_with("*.kt")),
+            other => panic!("expected NoMatches, got {:?}", other),
+        }
+
+        let file_map = build(
+            &toml_str.replace(
+                "pattern = \"*.kt\"",
+                "pattern = \"*.kt\", allow_empty = true",
+            ),
+            root,
+        )
+        .unwrap();
+        assert_eq!(file_map.source_count(), 0);
+    }
+
+    /// Test that a source file that does not exist fails to build, unless the existence check is turned off.
+    #[test]
+    fn nonexistent_file() {
+        let dir = project();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            missing = "MISSING.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            missing = "."
+        "#;
+
+        let result = build(toml_str, dir.path());
+
+        match result {
+            Err(FileMapError::NonexistentFiles(files)) => {
+                let paths: Vec<&PathBuf> = files.iter().map(|(path, _)| path).collect();
+                assert_eq!(paths, vec![&dir.path().join("MISSING.md")]);
+                assert_eq!(files[0].1.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("expected NonexistentFiles, got {:?}", other),
+        }
+
+        let config = Config::parse(toml_str).unwrap();
+        let file_map = FileMapBuilder::from(config, dir.path().to_path_buf())
+            .with_verify_existence(false)
+            .build()
+            .unwrap();
+        assert_eq!(file_map.map.len(), 1);
+    }
+
+    /// Test that `dry_build` pairs up file sources that don't exist, ignoring the size limit.
+    #[test]
+    fn dry_build() {
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            main = "src/Main.java"
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+            max_size_bytes = 1
+
+            [destination.locations]
+            main = "src"
+            readme = "."
+        "#;
+        let root = Path::new("/nonexistent/project");
+
+        let config = Config::parse(toml_str).unwrap();
+        let file_map = FileMapBuilder::from(config, root.to_path_buf())
+            .dry_build()
+            .unwrap();
+
+        assert_eq!(
+            file_map.map,
+            vec![
+                (
+                    root.join("src/Main.java"),
+                    root.join("test-user987/src/Main.java")
+                ),
+                (root.join("README.md"), root.join("test-user987/README.md")),
+            ]
+        );
+    }
+
+    /// Test that files from sources with a UTF-8 encoding are checked, and only those that aren't valid UTF-8 are
+    /// warned about.
+    #[test]
+    fn encoding_warnings() {
+        let dir = project();
+        let root = dir.path();
+        fs::write(root.join("src/latin1.txt"), b"caf\xe9").unwrap();
+        fs::write(root.join("report.md"), b"\xff\xfe").unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "*.txt", encoding = "utf-8" }
+            report = { file = "report.md", encoding = "UTF-8" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "src"
+            report = "."
+            readme = "."
+        "#;
+        let config = Config::parse(toml_str).unwrap();
+        let verified = FileMapBuilder::from(config, root.to_path_buf())
+            .expand_paths()
+            .verify_patterns()
+            .unwrap()
+            .expand_sources()
+            .unwrap()
+            .pair_locations()
+            .unwrap()
+            .format_destination()
+            .unwrap()
+            .flatten_locations()
+            .verify_no_conflicts()
+            .unwrap();
+
+        assert_eq!(
+            verified.encoding_warnings().unwrap(),
+            vec![
+                FileMapWarning::NonUtf8File {
+                    path: root.join("report.md")
+                },
+                FileMapWarning::NonUtf8File {
+                    path: root.join("src/latin1.txt")
+                },
+            ]
+        );
+
+        let file_map = verified.clone().verify_existence().unwrap();
+        assert_eq!(file_map.len(), 4);
+        assert_eq!(
+            file_map.warnings(),
+            &verified.encoding_warnings().unwrap()[..]
+        );
+    }
+
+    /// Test that files whose paths are longer than `max_path_length` are skipped with a warning.
+    #[test]
+    fn max_path_length() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java", max_path_length = 14 }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        // `src/Main.java` is 13 characters long, and `src/util/Util.java` is 18.
+        assert_eq!(
+            file_map.map,
+            vec![(
+                root.join("src/Main.java"),
+                root.join("test-user987/Main.java")
+            )]
+        );
+        assert_eq!(
+            file_map.warnings(),
+            &[FileMapWarning::PathTooLong {
+                path: root.join("src/util/Util.java"),
+                limit: 14,
+                actual: 18
+            }]
+        );
+    }
+
+    /// Test that a map whose source files are bigger than the size limit fails to build, and that the builder's limit
+    /// takes precedence over the config's.
+    #[test]
+    fn size_limit() {
+        let dir = project();
+        let root = dir.path();
+
+        // `class Main {}` and `class Util {}` are 13 bytes each.
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+            max_size_bytes = 26
+
+            [destination.locations]
+            src = "."
+        "#;
+
+        assert!(build(toml_str, root).is_ok());
+
+        match build(&toml_str.replace("26", "25"), root) {
+            Err(FileMapError::SizeLimit { limit, actual }) => {
+                assert_eq!(limit, 25);
+                assert_eq!(actual, 26);
+            }
+            other => panic!("expected SizeLimit, got {:?}", other),
+        }
+
+        let config = Config::parse(toml_str).unwrap();
+        let result = FileMapBuilder::from(config, root.to_path_buf())
+            .with_max_size(10)
+            .build();
+        assert!(result.is_err());
+    }
+
+    /// Test that two source files with the same destination fail to build.
+    #[test]
+    fn destination_conflict() {
+        let dir = project();
+        let root = dir.path();
+
+        let result = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+            docs = { path = ".", pattern = "*.md" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            readme = "."
+            docs = "."
+        "#,
+            root,
+        );
+
+        // The same file claimed twice isn't a conflict.
+        assert!(result.is_ok());
+
+        fs::write(root.join("src/README.md"), "# Source").unwrap();
+
+        let result = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+            docs = { path = "src", pattern = "*.md" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            readme = "."
+            docs = "."
+        "#,
+            root,
+        );
+
+        match result {
+            Err(FileMapError::DestinationConflict { path, sources }) => {
+                assert_eq!(path, root.join("test-user987/README.md"));
+                assert_eq!(
+                    sources,
+                    vec![root.join("src/README.md"), root.join("README.md")]
+                );
+            }
+            other => panic!("expected DestinationConflict, got {:?}", other),
+        }
+    }
+
+    /// Test that a rename pattern changes the names of matched files but not their folders, that two files renamed to
+    /// the same name are reported as a rename conflict, and that an invalid pattern fails to build.
+    #[test]
+    fn rename_pattern() {
+        let dir = project();
+        let root = dir.path();
+        fs::create_dir_all(root.join("reports/old_draft_v1")).unwrap();
+        fs::write(root.join("reports/Report_draft_v3.pdf"), "report").unwrap();
+        fs::write(
+            root.join("reports/old_draft_v1/Notes_draft_v1.pdf"),
+            "notes",
+        )
+        .unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            reports = { path = "reports", pattern = "**/*.pdf", rename_pattern = "_draft_v(\\d+)", rename_replacement = "REPLACEMENT" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            reports = "."
+        "#;
+
+        let file_map = build(&toml_str.replace("REPLACEMENT", ""), root).unwrap();
+        let dest_dir = root.join("test-user987");
+        assert_eq!(
+            file_map.map,
+            vec![
+                (
+                    root.join("reports/Report_draft_v3.pdf"),
+                    dest_dir.join("Report.pdf")
+                ),
+                (
+                    root.join("reports/old_draft_v1/Notes_draft_v1.pdf"),
+                    dest_dir.join("old_draft_v1/Notes.pdf")
+                ),
+            ]
+        );
+
+        let file_map = build(&toml_str.replace("REPLACEMENT", "-$1"), root).unwrap();
+        assert_eq!(file_map.map[0].1, dest_dir.join("Report-3.pdf"));
+
+        fs::write(root.join("reports/Report_draft_v4.pdf"), "newer report").unwrap();
+
+        match build(&toml_str.replace("REPLACEMENT", ""), root) {
+            Err(FileMapError::RenameConflict { path, sources }) => {
+                assert_eq!(path, dest_dir.join("Report.pdf"));
+                assert_eq!(
+                    sources,
+                    vec![
+                        root.join("reports/Report_draft_v3.pdf"),
+                        root.join("reports/Report_draft_v4.pdf")
+                    ]
+                );
+            }
+            other => panic!("expected RenameConflict, got {:?}", other),
+        }
+
+        let result = build(&toml_str.replace("_draft_v(\\\\d+)", "("), root);
+        assert!(matches!(result, Err(FileMapError::RenamePattern { .. })));
+    }
+
+    /// Test that errors wrapping another error return it from `source`, and others return `None`.
+    #[test]
+    fn error_source() {
+        use std::error::Error;
+
+        let err = FileMapError::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert_eq!(err.source().unwrap().to_string(), "gone");
+
+        let err = FileMapError::from(Pattern::new("[").unwrap_err());
+        assert!(err.source().unwrap().is::<glob::PatternError>());
+
+        let err = FileMapError::MissingDest {
+            key: "src".to_string(),
+        };
+        assert!(err.source().is_none());
+    }
+
+    /// Test that `filter` keeps only the pairs matching the predicate, and leaves the original untouched.
+    #[test]
+    fn filter() {
+        let file_map = FileMap {
+            root_dir: PathBuf::from("/root"),
+            dest_dir: PathBuf::from("/root/dest"),
+            archive: ArchiveMode::None,
+            overwrite: OverwritePolicy::Overwrite,
+            archive_path: None,
+            manifest: false,
+            checksum_algorithm: ChecksumAlgorithm::Sha256,
+            post_copy: None,
+            compression_level: None,
+            archive_comment: None,
+            temp_dir: None,
+            logger: None,
+            mtimes: BTreeMap::new(),
+            warnings: Vec::new(),
+            map: vec![
+                (
+                    PathBuf::from("/root/a.rs"),
+                    PathBuf::from("/root/dest/a.rs"),
+                ),
+                (
+                    PathBuf::from("/root/b.md"),
+                    PathBuf::from("/root/dest/docs/b.md"),
+                ),
+            ],
+        };
+
+        let filtered = file_map.filter(|src, _| src.extension() == Some("rs".as_ref()));
+        assert_eq!(filtered.map, vec![file_map.map[0].clone()]);
+        assert_eq!(filtered.dest_dir, file_map.dest_dir);
+
+        let filtered = file_map.filter(|_, dst| dst.starts_with("/root/dest/docs"));
+        assert_eq!(filtered.map, vec![file_map.map[1].clone()]);
+
+        assert_eq!(file_map.map.len(), 2);
+    }
+
+    /// Test that `with_prefix` moves every destination under the prefix inside the destination folder.
+    #[test]
+    fn with_prefix() {
+        let file_map = FileMap {
+            root_dir: PathBuf::from("/root"),
+            dest_dir: PathBuf::from("/root/dest"),
+            archive: ArchiveMode::None,
+            overwrite: OverwritePolicy::Overwrite,
+            archive_path: None,
+            manifest: false,
+            checksum_algorithm: ChecksumAlgorithm::Sha256,
+            post_copy: None,
+            compression_level: None,
+            archive_comment: None,
+            temp_dir: None,
+            logger: None,
+            mtimes: BTreeMap::new(),
+            warnings: Vec::new(),
+            map: vec![(
+                PathBuf::from("/root/src/a.rs"),
+                PathBuf::from("/root/dest/code/a.rs"),
+            )],
+        };
+
+        let prefixed = file_map.with_prefix("submission/").unwrap();
+        assert_eq!(
+            prefixed.map,
+            vec![(
+                PathBuf::from("/root/src/a.rs"),
+                PathBuf::from("/root/dest/submission/code/a.rs"),
+            )]
+        );
+        assert_eq!(
+            prefixed.with_stripped_prefix("submission/").map,
+            file_map.map
+        );
+
+        for prefix in &["/tmp/x", "../..", "submission/../../x"] {
+            match file_map.with_prefix(prefix) {
+                Err(FileMapError::InvalidPrefix { prefix: actual }) => {
+                    assert_eq!(actual, Path::new(prefix))
+                }
+                other => panic!("expected InvalidPrefix for `{}`, got {:?}", prefix, other),
+            }
+        }
+    }
+
+    /// Test that `with_stripped_prefix` removes the prefix from the destinations that start with it, and leaves the
+    /// rest alone.
+    #[test]
+    fn with_stripped_prefix() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "submission/user987/code"
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let dest_dir = root.join("test-user987");
+        assert_eq!(
+            file_map.with_stripped_prefix("submission/user987").map,
+            vec![
+                (root.join("README.md"), dest_dir.join("README.md")),
+                (root.join("src/Main.java"), dest_dir.join("code/Main.java")),
+            ]
+        );
+    }
+
+    /// Test that executing a `FileMap` copies every file to its destination.
+    #[test]
+    fn execute() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        file_map.execute().unwrap();
+
+        let dest_dir = root.join("test-user987");
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("Main.java")).unwrap(),
+            "class Main {}"
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("util/Util.java")).unwrap(),
+            "class Util {}"
+        );
+        assert!(!dest_dir.join("notes.txt").exists());
+    }
+
+    /// Test that rolling back a failed execution deletes the files it created and restores the ones it overwrote.
+    #[test]
+    fn rollback() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let dest_dir = root.join("test-user987");
+        fs::create_dir_all(&dest_dir).unwrap();
+        fs::write(dest_dir.join("Main.java"), "old").unwrap();
+
+        // Make the last copy fail after the others have happened.
+        fs::remove_file(root.join("src/util/Util.java")).unwrap();
+
+        let mut journal = CopyJournal::default();
+        assert!(file_map
+            .execute_journaled(&ExecuteOptions::default(), &mut journal)
+            .is_err());
+        assert_eq!(
+            journal.created(),
+            &[dest_dir.join("README.md"), dest_dir.join("util/Util.java")][..]
+        );
+        assert_eq!(
+            journal.overwritten().collect::<Vec<_>>(),
+            vec![dest_dir.join("Main.java")]
+        );
+
+        file_map.rollback(&journal).unwrap();
+        journal.discard().unwrap();
+
+        assert!(!dest_dir.join("README.md").exists());
+        assert!(!dest_dir.join("util").exists());
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("Main.java")).unwrap(),
+            "old"
+        );
+    }
+
+    /// Test that copying in parallel reports each file's progress, copies every file and writes the copy log, and that
+    /// a failed copy is reported, stops packaging before the archive is created and rolls back the files written.
+    #[test]
+    fn execute_parallel_with_progress() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.*" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            src = "."
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let options = ExecuteOptions {
+            copy_log: true,
+            ..ExecuteOptions::default()
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let result = file_map
+            .execute_parallel_with_progress(3, &options, tx)
+            .unwrap();
+        let events: Vec<ProgressEvent> = rx.iter().collect();
+
+        assert_eq!(result.files, 4);
+        assert_eq!(result.bytes, file_map.total_source_size().unwrap());
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| matches!(event, ProgressEvent::Started(..)))
+                .count(),
+            4
+        );
+
+        let mut completed: Vec<&Path> = events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Completed { dst, .. } => Some(dst.as_path()),
+                _ => None,
+            })
+            .collect();
+        completed.sort();
+        let mut expected: Vec<&Path> = file_map.map.iter().map(|(_, dst)| dst.as_path()).collect();
+        expected.sort();
+        assert_eq!(completed, expected);
+        assert!(expected.iter().all(|dst| dst.is_file()));
+        assert!(root.join("test-user987.zip").is_file());
+
+        let copy_log = fs::read_to_string(root.join("test-user987").join(COPY_LOG_FILE)).unwrap();
+        assert_eq!(copy_log.lines().count(), 4);
+
+        fs::remove_dir_all(root.join("test-user987")).unwrap();
+        fs::remove_file(root.join("test-user987.zip")).unwrap();
+
+        let options = ExecuteOptions {
+            simulate_failure: Some(3),
+            ..ExecuteOptions::default()
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        assert!(file_map
+            .execute_parallel_with_progress(1, &options, tx)
+            .is_err());
+        assert!(rx
+            .iter()
+            .any(|event| matches!(event, ProgressEvent::Failed { .. })));
+        assert!(!root.join("test-user987").exists());
+        assert!(!root.join("test-user987.zip").exists());
+    }
+
+    /// Test that existing destination files are moved into the backup folder, and moved back if packing fails.
+    #[test]
+    fn execute_with_backup() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let dest_dir = root.join("test-user987");
+        let backup_dir = root.join("backup");
+        fs::create_dir_all(dest_dir.join("util")).unwrap();
+        fs::write(dest_dir.join("Main.java"), "old main").unwrap();
+        fs::write(dest_dir.join("util/Util.java"), "old util").unwrap();
+
+        file_map.execute_with_backup(&backup_dir).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("Main.java")).unwrap(),
+            "class Main {}"
+        );
+        assert_eq!(
+            fs::read_to_string(backup_dir.join("util/Util.java")).unwrap(),
+            "old util"
+        );
+
+        // Make the last copy fail after the others have happened.
+        fs::write(dest_dir.join("Main.java"), "old main").unwrap();
+        fs::remove_file(root.join("src/util/Util.java")).unwrap();
+
+        assert!(file_map.execute_with_backup(&backup_dir).is_err());
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("Main.java")).unwrap(),
+            "old main"
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("util/Util.java")).unwrap(),
+            "class Util {}"
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.join("README.md")).unwrap(),
+            "# Project"
+        );
+        assert!(!backup_dir.join("Main.java").exists());
+    }
+
+    /// Test that `execute_with_verify` copies every file and verifies each copy, and applies the overwrite policy.
+    #[test]
+    fn execute_with_verify() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let execution = file_map.execute_with_verify().unwrap();
+        assert!(execution.all_verified());
+        assert_eq!(execution.pairs.len(), 2);
+
+        let pair = &execution.pairs[0];
+        assert_eq!(pair.dst, root.join("test-user987/Main.java"));
+        assert_eq!(pair.bytes, 13);
+        assert_eq!(
+            pair.checksum
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+            ChecksumAlgorithm::Sha256.hash_file(&pair.src).unwrap()
+        );
+        assert_eq!(fs::read_to_string(&pair.dst).unwrap(), "class Main {}");
+
+        let skipping = FileMap {
+            overwrite: OverwritePolicy::Skip,
+            ..file_map
+        };
+        assert!(matches!(
+            skipping.execute_with_verify(),
+            Err(FileMapError::DestinationExists { .. })
+        ));
+    }
+
+    /// Test that executing a `FileMap` built with a logger records the start, every copy, and the end.
+    #[test]
+    fn execute_with_logger() {
+        let dir = project();
+        let root = dir.path();
+        let log_path = root.join("pack.log");
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#,
+        )
+        .unwrap();
+
+        let file_map = FileMapBuilder::from(config, root.to_path_buf())
+            .with_logger(Logger::create(&log_path).unwrap())
+            .build()
+            .unwrap();
+        file_map.execute().unwrap();
+        drop(file_map);
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let events: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "start");
+        assert_eq!(events[1]["event"], "copy");
+        assert_eq!(
+            events[1]["src"],
+            root.join("src/Main.java").to_str().unwrap()
+        );
+        assert_eq!(events[1]["bytes"], 13);
+        assert_eq!(events[2]["event"], "end");
+        assert_eq!(events[2]["files"], 1);
+    }
+
+    /// Test that `diff` finds added, removed and re-mapped files, matching pairs up by source.
+    #[test]
+    fn diff() {
+        let old = FileMap {
+            root_dir: PathBuf::from("/root"),
+            dest_dir: PathBuf::from("/root/dest"),
+            archive: ArchiveMode::None,
+            overwrite: OverwritePolicy::Overwrite,
+            archive_path: None,
+            manifest: false,
+            checksum_algorithm: ChecksumAlgorithm::Sha256,
+            post_copy: None,
+            compression_level: None,
+            archive_comment: None,
+            temp_dir: None,
+            logger: None,
+            mtimes: BTreeMap::new(),
+            warnings: Vec::new(),
+            map: vec![
+                (
+                    PathBuf::from("/root/a.rs"),
+                    PathBuf::from("/root/dest/a.rs"),
+                ),
+                (
+                    PathBuf::from("/root/b.rs"),
+                    PathBuf::from("/root/dest/b.rs"),
+                ),
+                (
+                    PathBuf::from("/root/c.rs"),
+                    PathBuf::from("/root/dest/c.rs"),
+                ),
+            ],
+        };
+        let new = FileMap {
+            map: vec![
+                (
+                    PathBuf::from("/root/a.rs"),
+                    PathBuf::from("/root/dest/a.rs"),
+                ),
+                (
+                    PathBuf::from("/root/c.rs"),
+                    PathBuf::from("/root/dest/src/c.rs"),
+                ),
+                (
+                    PathBuf::from("/root/d.rs"),
+                    PathBuf::from("/root/dest/d.rs"),
+                ),
+            ],
+            ..old.clone()
+        };
+
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.added,
+            vec![(
+                PathBuf::from("/root/d.rs"),
+                PathBuf::from("/root/dest/d.rs")
+            )]
+        );
+        assert_eq!(
+            diff.removed,
+            vec![(
+                PathBuf::from("/root/b.rs"),
+                PathBuf::from("/root/dest/b.rs")
+            )]
+        );
+        assert_eq!(
+            diff.changed,
+            vec![(
+                PathBuf::from("/root/c.rs"),
+                PathBuf::from("/root/dest/c.rs"),
+                PathBuf::from("/root/dest/src/c.rs"),
+            )]
+        );
+
+        assert!(old.diff(&old).is_empty());
+    }
+
+    /// Test that a source copied to more than one destination is only reported for the destinations that differ, and
+    /// not at all if none of them do.
+    #[test]
+    fn diff_fan_out() {
+        let pair = |src: &str, dst: &str| (PathBuf::from(src), PathBuf::from(dst));
+
+        let old = FileMap {
+            root_dir: PathBuf::from("/root"),
+            dest_dir: PathBuf::from("/root/dest"),
+            archive: ArchiveMode::None,
+            overwrite: OverwritePolicy::Overwrite,
+            archive_path: None,
+            manifest: false,
+            checksum_algorithm: ChecksumAlgorithm::Sha256,
+            post_copy: None,
+            compression_level: None,
+            archive_comment: None,
+            temp_dir: None,
+            logger: None,
+            mtimes: BTreeMap::new(),
+            warnings: Vec::new(),
+            map: vec![
+                pair("/root/src/sub/b.rs", "/root/dest/rec/b.rs"),
+                pair("/root/src/sub/b.rs", "/root/dest/src/sub/b.rs"),
+                pair("/root/a.rs", "/root/dest/marking/a.rs"),
+                pair("/root/a.rs", "/root/dest/autograder/a.rs"),
+            ],
+        };
+
+        assert!(old.diff(&old.clone()).is_empty());
+
+        let new = FileMap {
+            map: vec![
+                pair("/root/src/sub/b.rs", "/root/dest/rec/b.rs"),
+                pair("/root/src/sub/b.rs", "/root/dest/src/sub/b.rs"),
+                pair("/root/src/sub/b.rs", "/root/dest/extra/b.rs"),
+                pair("/root/a.rs", "/root/dest/marking/a.rs"),
+                pair("/root/a.rs", "/root/dest/tests/a.rs"),
+            ],
+            ..old.clone()
+        };
+
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.added,
+            vec![pair("/root/src/sub/b.rs", "/root/dest/extra/b.rs")]
+        );
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.changed,
+            vec![(
+                PathBuf::from("/root/a.rs"),
+                PathBuf::from("/root/dest/autograder/a.rs"),
+                PathBuf::from("/root/dest/tests/a.rs"),
+            )]
+        );
+
+        let diff = new.diff(&old);
+        assert_eq!(
+            diff.removed,
+            vec![pair("/root/src/sub/b.rs", "/root/dest/extra/b.rs")]
+        );
+    }
+
+    /// Read the names and contents of every file entry in the ZIP archive at `path`.
+    fn read_archive(path: &Path) -> BTreeMap<String, String> {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut entries = BTreeMap::new();
+
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).unwrap();
+            let mut contents = String::new();
+            io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+            entries.insert(entry.name().to_string(), contents);
+        }
+
+        entries
+    }
+
+    /// Read the names and contents of every entry in the gzipped tarball at `path`. Folder entries have empty contents.
+    fn read_tar_gz(path: &Path) -> BTreeMap<String, String> {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(path).unwrap()));
+        let mut entries = BTreeMap::new();
+
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut contents = String::new();
+
+            if entry.header().entry_type().is_dir() {
+                if !name.ends_with('/') {
+                    name.push('/');
+                }
+            } else {
+                io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+            }
+
+            entries.insert(name, contents);
+        }
+
+        entries
+    }
+
+    /// Test that executing a `FileMap` with `archive = true` creates an archive next to the destination folder.
+    #[test]
+    fn execute_archive() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            src = "code"
+        "#,
+            root,
+        )
+        .unwrap();
+
+        file_map.execute().unwrap();
+
+        let entries = read_archive(&root.join("test-user987.zip"));
+        let names: Vec<&str> = entries.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            vec![
+                "test-user987/",
+                "test-user987/code/",
+                "test-user987/code/Main.java",
+                "test-user987/code/util/",
+                "test-user987/code/util/Util.java",
+            ]
+        );
+        assert_eq!(entries["test-user987/code/Main.java"], "class Main {}");
+    }
+
+    /// Test that the parallel ZIP writer produces exactly the same bytes as the sequential one, across more files than
+    /// fit in one batch and at each kind of compression level.
+    #[test]
+    fn write_zip_entries_parallel() {
+        let dir = project();
+        let root = dir.path();
+
+        for i in 0..PARALLEL_ZIP_BATCH + 10 {
+            let folder = root.join(format!("src/gen{}", i % 7));
+            fs::create_dir_all(&folder).unwrap();
+            fs::write(
+                folder.join(format!("Gen{}.java", i)),
+                format!("class Gen{} {{}}\n", i).repeat(i + 1),
+            )
+            .unwrap();
+        }
+
+        for level in ["", "compression_level = 0", "compression_level = 9"] {
+            let file_map = build(
+                &format!(
+                    r#"
+                    username = "user987"
+
+                    [sources]
+                    src = {{ path = "src", pattern = "**/*.java" }}
+
+                    [destination]
+                    name = "test-{{username}}"
+                    archive = true
+                    {}
+
+                    [destination.locations]
+                    src = "code"
+                "#,
+                    level
+                ),
+                root,
+            )
+            .unwrap();
+            // Folder entries are stamped with the time the options are created, so both writers share them.
+            let options = file_map.zip_options();
+
+            let mut sequential = ZipWriter::new(Cursor::new(Vec::new()));
+            file_map
+                .write_zip_entries_sequential(&mut sequential, &BTreeSet::new(), options)
+                .unwrap();
+            let sequential = sequential.finish().unwrap().into_inner();
+
+            let mut parallel = ZipWriter::new(Cursor::new(Vec::new()));
+            file_map
+                .write_zip_entries_parallel(&mut parallel, &BTreeSet::new(), options)
+                .unwrap();
+            let parallel = parallel.finish().unwrap().into_inner();
+
+            let archive = ZipArchive::new(Cursor::new(&parallel)).unwrap();
+            let files = archive.file_names().filter(|name| !name.ends_with('/'));
+            assert_eq!(files.count(), PARALLEL_ZIP_BATCH + 12);
+            assert!(sequential == parallel, "archives differ with `{}`", level);
+        }
+    }
+
+    /// Test that `execute_into_archive` writes the archive, including the manifest, without creating the destination
+    /// folder or running the hook, and refuses to run if the destination isn't archived.
+    #[test]
+    fn execute_into_archive() {
+        let dir = project();
+        let root = dir.path();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+            manifest = true
+            post_copy = "touch built"
+
+            [destination.locations]
+            src = "code"
+        "#;
+
+        let file_map = build(toml_str, root).unwrap();
+        let warnings = file_map
+            .execute_into_archive(&ExecuteOptions::default())
+            .unwrap();
+
+        assert!(!root.join("test-user987").exists());
+        assert_eq!(
+            warnings,
+            vec![FileMapWarning::HookSkipped {
+                command: "touch built".to_string()
+            }]
+        );
+
+        let entries = read_archive(&root.join("test-user987.zip"));
+        assert_eq!(entries["test-user987/code/Main.java"], "class Main {}");
+        assert!(entries["test-user987/MANIFEST.sha256"].contains("code/util/Util.java"));
+
+        let file_map = build(&toml_str.replace("archive = true", "archive = false"), root).unwrap();
+        assert!(matches!(
+            file_map.execute_into_archive(&ExecuteOptions::default()),
+            Err(FileMapError::ArchiveOnlyUnsupported)
+        ));
+    }
+
+    /// Test that the archive is written to `archive_path` when it is given, creating its folder, and that
+    /// `with_archive_path` overrides it.
+    #[test]
+    fn execute_archive_path() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+            archive_path = "dist/{username}/submission.zip"
+
+            [destination.locations]
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        file_map.execute().unwrap();
+
+        let entries = read_archive(&root.join("dist/user987/submission.zip"));
+        assert_eq!(entries["test-user987/README.md"], "# Project");
+        assert!(!root.join("test-user987.zip").exists());
+
+        file_map.with_archive_path("other.zip").execute().unwrap();
+        assert!(root.join("other.zip").exists());
+    }
+
+    /// Test that appending adds new files to an existing archive and replaces files that are already in it.
+    #[test]
+    fn execute_append() {
+        let dir = project();
+        let root = dir.path();
+
+        let config = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            src = "."
+            readme = "."
+        "#;
+        let append = ExecuteOptions {
+            append: true,
+            ..ExecuteOptions::default()
+        };
+
+        let file_map = build(config, root).unwrap();
+        file_map
+            .filter(|src, _| src.ends_with("README.md"))
+            .execute()
+            .unwrap();
+
+        file_map
+            .filter(|src, _| src.ends_with("Main.java"))
+            .execute_with(&append)
+            .unwrap();
+
+        let entries = read_archive(&root.join("test-user987.zip"));
+        assert_eq!(entries["test-user987/README.md"], "# Project");
+        assert_eq!(entries["test-user987/Main.java"], "class Main {}");
+        assert!(!entries.contains_key("test-user987/util/Util.java"));
+
+        fs::write(root.join("README.md"), "# Updated").unwrap();
+        file_map.execute_with(&append).unwrap();
+
+        let entries = read_archive(&root.join("test-user987.zip"));
+        let names: Vec<&str> = entries.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            vec![
+                "test-user987/",
+                "test-user987/Main.java",
+                "test-user987/README.md",
+                "test-user987/util/",
+                "test-user987/util/Util.java",
+            ]
+        );
+        assert_eq!(entries["test-user987/README.md"], "# Updated");
+        assert_eq!(entries["test-user987/util/Util.java"], "class Util {}");
+    }
+
+    /// Test that the staged manifest and the archive being rebuilt when appending are written to `temp_dir`, and
+    /// nothing is left behind there or in the default staging folder.
+    #[test]
+    fn execute_temp_dir() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+            manifest = true
+            temp_dir = "tmp/work"
+
+            [destination.locations]
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+        let append = ExecuteOptions {
+            append: true,
+            ..ExecuteOptions::default()
+        };
+
+        file_map
+            .execute_into_archive(&ExecuteOptions::default())
+            .unwrap();
+        fs::write(root.join("README.md"), "# Updated").unwrap();
+        file_map.execute_into_archive(&append).unwrap();
+
+        let entries = read_archive(&root.join("test-user987.zip"));
+        assert_eq!(entries["test-user987/README.md"], "# Updated");
+        assert!(entries["test-user987/MANIFEST.sha256"].contains("README.md"));
+
+        assert_eq!(fs::read_dir(root.join("tmp/work")).unwrap().count(), 0);
+        assert!(!root.join(".bathpack").exists());
+    }
+
+    /// Test that `archive = "tar.gz"` creates a gzipped tarball next to the destination folder, and that appending to
+    /// it replaces files that are already in it.
+    #[test]
+    fn execute_tar_gz() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = "tar.gz"
+
+            [destination.locations]
+            src = "."
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+        assert_eq!(file_map.archive_mode(), ArchiveMode::TarGz);
+        assert_eq!(file_map.archive_path(), root.join("test-user987.tar.gz"));
+
+        file_map
+            .filter(|src, _| !src.ends_with("Util.java"))
+            .execute()
+            .unwrap();
+
+        fs::write(root.join("README.md"), "# Updated").unwrap();
+        file_map
+            .filter(|src, _| !src.ends_with("Main.java"))
+            .execute_with(&ExecuteOptions {
+                append: true,
+                ..ExecuteOptions::default()
+            })
+            .unwrap();
+
+        let entries = read_tar_gz(&root.join("test-user987.tar.gz"));
+        let names: Vec<&str> = entries.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            vec![
+                "test-user987/",
+                "test-user987/Main.java",
+                "test-user987/README.md",
+                "test-user987/util/",
+                "test-user987/util/Util.java",
+            ]
+        );
+        assert_eq!(entries["test-user987/Main.java"], "class Main {}");
+        assert_eq!(entries["test-user987/README.md"], "# Updated");
+        assert!(!root.join("test-user987.zip").exists());
+
+        let unarchived = FileMap {
+            archive: ArchiveMode::None,
+            ..file_map
+        };
+        assert!(matches!(
+            unarchived.append_archive(&root.join("test-user987.tar.gz")),
+            Err(FileMapError::AppendUnsupported {
+                format: ArchiveMode::None
+            })
+        ));
+    }
+
+    /// Test that a streamed ZIP archive can be read back without anything being written to disk, and that streaming
+    /// fails if archiving is turned off.
+    #[test]
+    fn execute_streaming_archive() {
+        let dir = project();
+        let root = dir.path();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = "zip"
+
+            [destination.locations]
+            src = "."
+        "#;
+
+        let mut bytes = Vec::new();
+        build(toml_str, root)
+            .unwrap()
+            .execute_streaming_archive(&mut bytes)
+            .unwrap();
+
+        let mut zip = ZipArchive::new(io::Cursor::new(bytes)).unwrap();
+        let mut contents = String::new();
+        io::Read::read_to_string(
+            &mut zip.by_name("test-user987/Main.java").unwrap(),
+            &mut contents,
+        )
+        .unwrap();
+        assert_eq!(contents, "class Main {}");
+        assert!(zip.by_name("test-user987/util/Util.java").is_ok());
+        assert!(!root.join("test-user987").exists());
+        assert!(!root.join("test-user987.zip").exists());
+
+        let file_map = build(&toml_str.replace("\"zip\"", "false"), root).unwrap();
+        assert!(matches!(
+            file_map.execute_streaming_archive(io::sink()),
+            Err(FileMapError::StreamingUnsupported {
+                format: ArchiveMode::None
+            })
+        ));
+    }
+
+    /// Test that files from a folder with `mtime_from` are archived with the reference file's modification time, in
+    /// both archive formats, and that other files keep their own.
+    #[test]
+    fn execute_mtime_from() {
+        let dir = project();
+        let root = dir.path();
+        // 2020-01-02 03:04:06 UTC, an even number of seconds since ZIP timestamps have two-second precision.
+        let reference = UNIX_EPOCH + std::time::Duration::from_secs(1_577_934_246);
+        File::options()
+            .write(true)
+            .open(root.join("README.md"))
+            .unwrap()
+            .set_modified(reference)
+            .unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java", mtime_from = "README.md" }
+            notes = "src/notes.txt"
+
+            [destination]
+            name = "test-{username}"
+            archive = "zip"
+
+            [destination.locations]
+            src = "."
+            notes = "."
+        "#;
+
+        build(toml_str, root).unwrap().execute().unwrap();
+
+        let mut zip = ZipArchive::new(File::open(root.join("test-user987.zip")).unwrap()).unwrap();
+        let expected = zip::DateTime::from_date_and_time(2020, 1, 2, 3, 4, 6).unwrap();
+        assert_eq!(
+            zip.by_name("test-user987/util/Util.java")
+                .unwrap()
+                .last_modified(),
+            Some(expected)
+        );
+        assert_ne!(
+            zip.by_name("test-user987/notes.txt")
+                .unwrap()
+                .last_modified(),
+            Some(expected)
+        );
+
+        build(&toml_str.replace("\"zip\"", "\"tar.gz\""), root)
+            .unwrap()
+            .execute()
+            .unwrap();
+
+        let file = File::open(root.join("test-user987.tar.gz")).unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        let mtimes: BTreeMap<String, u64> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                (name, entry.header().mtime().unwrap())
+            })
+            .collect();
+        assert_eq!(mtimes["test-user987/Main.java"], 1_577_934_246);
+        assert_ne!(mtimes["test-user987/notes.txt"], 1_577_934_246);
+    }
+
+    /// Test that errors report the files they are about, along with their code.
+    #[test]
+    fn error_codes_and_files() {
+        let err = FileMapError::DestinationConflict {
+            path: PathBuf::from("dest/a.rs"),
+            sources: vec![PathBuf::from("a/a.rs"), PathBuf::from("b/a.rs")],
+        };
+        assert_eq!(err.code(), "E015");
+        assert_eq!(
+            err.files(),
+            vec![
+                Path::new("dest/a.rs"),
+                Path::new("a/a.rs"),
+                Path::new("b/a.rs")
+            ]
+        );
+
+        let err = FileMapError::MissingDest {
+            key: "src".to_string(),
+        };
+        assert_eq!(err.code(), "E004");
+        assert!(err.files().is_empty());
+    }
+
+    /// Test that `group_by_dest_dir` puts each pair under the folder its destination is in.
+    #[test]
+    fn group_by_dest_dir() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.*" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let dest_dir = root.join("test-user987");
+        let groups = file_map.group_by_dest_dir();
+
+        assert_eq!(
+            groups.keys().collect::<Vec<_>>(),
+            vec![
+                &dest_dir,
+                &dest_dir.join("code"),
+                &dest_dir.join("code/util")
+            ]
+        );
+        assert_eq!(
+            groups[&dest_dir.join("code")],
+            vec![
+                (root.join("src/Main.java"), dest_dir.join("code/Main.java")),
+                (root.join("src/notes.txt"), dest_dir.join("code/notes.txt")),
+            ]
+        );
+        assert_eq!(groups[&dest_dir].len(), 1);
+    }
+
+    /// Test that `verify_integrity` passes after executing, and fails if a copy is changed afterwards.
+    #[test]
+    fn verify_integrity() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "code"
+            readme = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        file_map.execute().unwrap();
+        assert!(file_map.verify_integrity().is_ok());
+
+        let copy = root.join("test-user987/code/Main.java");
+        fs::write(&copy, "class Main { }").unwrap();
+
+        match file_map.verify_integrity() {
+            Err(FileMapError::IntegrityFailure { source, dest }) => {
+                assert_eq!(source, root.join("src/Main.java"));
+                assert_eq!(dest, copy);
+            }
+            other => panic!("expected IntegrityFailure, got {:?}", other),
+        }
+    }
+
+    /// Test that the Makefile copies every file, creating each folder once, and archives and cleans up the destination.
+    #[test]
+    fn to_makefile() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = "tar.gz"
+
+            [destination.locations]
+            src = "code"
+            readme = { file = "docs/it's $HOME.md" }
+        "#,
+            root,
+        )
+        .unwrap();
+
+        assert_eq!(
+            file_map.to_makefile(),
+            "# Generated by bathpack. Run `make pack` from this folder.
+
+.PHONY: pack clean
+
+pack:
+\tmkdir -p 'test-user987/docs'
+\tcp 'README.md' 'test-user987/docs/it'\\''s $$HOME.md'
+\tmkdir -p 'test-user987/code'
+\tcp 'src/Main.java' 'test-user987/code/Main.java'
+\tmkdir -p 'test-user987/code/util'
+\tcp 'src/util/Util.java' 'test-user987/code/util/Util.java'
+\ttar -czf 'test-user987.tar.gz' -C '.' 'test-user987'
+
+clean:
+\trm -rf 'test-user987' 'test-user987.tar.gz'
+"
+        );
+    }
+
+    /// Test that compression level 0 stores ZIP entries uncompressed, and that higher levels compress them.
+    #[test]
+    fn compression_level() {
+        let dir = project();
+        let root = dir.path();
+        fs::write(root.join("README.md"), "bathpack ".repeat(100)).unwrap();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = "zip"
+            compression_level = 0
+
+            [destination.locations]
+            readme = "."
+        "#;
+
+        let compression = |toml_str: &str| {
+            build(toml_str, root).unwrap().execute().unwrap();
+            let mut zip =
+                ZipArchive::new(File::open(root.join("test-user987.zip")).unwrap()).unwrap();
+            let entry = zip.by_name("test-user987/README.md").unwrap();
+            (entry.compression(), entry.compressed_size())
+        };
+
+        assert_eq!(compression(toml_str), (CompressionMethod::Stored, 900));
+
+        let (method, size) = compression(&toml_str.replace("= 0", "= 9"));
+        assert_eq!(method, CompressionMethod::Deflated);
+        assert!(size < 900);
+    }
+
+    /// Test that the archive comment is substituted and stored in the ZIP archive, and that one given to the builder
+    /// takes precedence over the config.
+    #[test]
+    fn archive_comment() {
+        let dir = project();
+        let root = dir.path();
+
+        let config = Config::parse(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = "zip"
+            archive_comment = "Submitted by {username}"
+
+            [destination.locations]
+            readme = "."
+        "#,
+        )
+        .unwrap();
+
+        let comment = |builder: FileMapBuilder| {
+            builder.build().unwrap().execute().unwrap();
+            let zip = ZipArchive::new(File::open(root.join("test-user987.zip")).unwrap()).unwrap();
+            String::from_utf8(zip.comment().to_vec()).unwrap()
+        };
+
+        let builder = FileMapBuilder::from(config, root.to_path_buf());
+        assert_eq!(comment(builder.clone()), "Submitted by user987");
+        assert_eq!(
+            comment(builder.with_archive_comment("{username}: final")),
+            "user987: final"
+        );
+    }
+
+    /// Test that a manifest is written into the destination folder and included in the archive, using the algorithm
+    /// from the options in preference to the one from the config.
+    #[test]
+    fn execute_manifest() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+            manifest = true
+
+            [destination.locations]
+            readme = "docs"
+        "#,
+            root,
+        )
+        .unwrap();
+
+        file_map.execute().unwrap();
+
+        let manifest = fs::read_to_string(root.join("test-user987/MANIFEST.sha256")).unwrap();
+        let expected = format!(
+            "# bathpack manifest (sha256)\n{}  docs/README.md\n",
+            ChecksumAlgorithm::Sha256
+                .hash_file(root.join("README.md"))
+                .unwrap()
+        );
+        assert_eq!(manifest, expected);
+
+        let entries = read_archive(&root.join("test-user987.zip"));
+        assert_eq!(entries["test-user987/MANIFEST.sha256"], expected);
+
+        let options = ExecuteOptions {
+            checksum_algorithm: Some(ChecksumAlgorithm::Blake3),
+            ..ExecuteOptions::default()
+        };
+        file_map.execute_with(&options).unwrap();
+
+        let manifest = fs::read_to_string(root.join("test-user987/MANIFEST.blake3")).unwrap();
+        assert!(manifest.starts_with("# bathpack manifest (blake3)\n"));
+    }
+
+    /// Test that the copy log records every copy in order, is included in the archive, and is only written when asked
+    /// for.
+    #[test]
+    fn execute_copy_log() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+            main = "src/Main.java"
+
+            [destination]
+            name = "test-{username}"
+            archive = true
+
+            [destination.locations]
+            readme = "docs"
+            main = "src"
+        "#,
+            root,
+        )
+        .unwrap();
+
+        file_map.execute().unwrap();
+        assert!(!root.join("test-user987").join(COPY_LOG_FILE).exists());
+
+        let options = ExecuteOptions {
+            copy_log: true,
+            ..ExecuteOptions::default()
+        };
+        file_map.execute_with(&options).unwrap();
+
+        let log = fs::read_to_string(root.join("test-user987").join(COPY_LOG_FILE)).unwrap();
+        let records: Vec<CopyRecord> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                CopyRecord {
+                    from: "src/Main.java".into(),
+                    to: "src/Main.java".into(),
+                    bytes: 13,
+                    sha256: ChecksumAlgorithm::Sha256
+                        .hash_file(root.join("src/Main.java"))
+                        .unwrap(),
+                },
+                CopyRecord {
+                    from: "README.md".into(),
+                    to: "docs/README.md".into(),
+                    bytes: 9,
+                    sha256: ChecksumAlgorithm::Sha256
+                        .hash_file(root.join("README.md"))
+                        .unwrap(),
+                },
+            ]
+        );
+
+        let entries = read_archive(&root.join("test-user987.zip"));
+        assert_eq!(entries["test-user987/bathpack.log"], log);
+    }
+
+    /// Test that the `post_copy` hook runs in the destination folder, that a failing hook is reported, and that
+    /// `skip_hooks` stops it from running.
+    #[test]
+    fn execute_post_copy() {
+        let dir = project();
+        let root = dir.path();
+
+        let toml_str = r#"
+            username = "user987"
+
+            [sources]
+            readme = "README.md"
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+            post_copy = "ls > files.txt"
+
+            [destination.locations]
+            readme = "."
+        "#;
+
+        let file_map = build(toml_str, root).unwrap();
+        file_map.execute().unwrap();
+
+        let files = root.join("test-user987/files.txt");
+        assert!(fs::read_to_string(&files).unwrap().contains("README.md"));
+
+        let file_map = build(&toml_str.replace("ls > files.txt", "exit 3"), root).unwrap();
+
+        match file_map.execute() {
+            Err(FileMapError::HookFailed { exit_code, command }) => {
+                assert_eq!(exit_code, 3);
+                assert_eq!(command, "exit 3");
+            }
+            other => panic!("expected HookFailed, got {:?}", other),
+        }
+
+        let options = ExecuteOptions {
+            skip_hooks: true,
+            ..ExecuteOptions::default()
+        };
+        file_map.execute_with(&options).unwrap();
+    }
+
+    /// Test that a simulated failure stops copying at the given file, after the earlier files were journaled, so that
+    /// they can be rolled back.
+    #[cfg(any(debug_assertions, feature = "testing"))]
+    #[test]
+    fn simulate_failure() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = false
+
+            [destination.locations]
+            src = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        let options = ExecuteOptions {
+            simulate_failure: Some(2),
+            ..ExecuteOptions::default()
+        };
+        let mut journal = CopyJournal::default();
+
+        match file_map.execute_journaled(&options, &mut journal) {
+            Err(FileMapError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::PermissionDenied),
+            other => panic!("expected a simulated failure, got {:?}", other),
+        }
+        assert!(root.join("test-user987/Main.java").exists());
+        assert!(!root.join("test-user987/util/Util.java").exists());
+
+        file_map.rollback(&journal).unwrap();
+        assert!(!root.join("test-user987/Main.java").exists());
+    }
+
+    /// Test that `run_after` substitutes the map's details into the command and runs it in the root directory.
+    #[test]
+    fn run_after() {
+        let dir = project();
+        let root = dir.path();
+
+        let file_map = build(
+            r#"
+            username = "user987"
+
+            [sources]
+            src = { path = "src", pattern = "**/*.java" }
+
+            [destination]
+            name = "test-{username}"
+            archive = "tar.gz"
+
+            [destination.locations]
+            src = "."
+        "#,
+            root,
+        )
+        .unwrap();
+
+        file_map
+            .run_after("echo {file_count} {dest_dir} {archive_path} > after.txt")
+            .unwrap();
+
+        let output = fs::read_to_string(root.join("after.txt")).unwrap();
+        assert_eq!(
+            output.trim(),
+            format!(
+                "2 {} {}",
+                root.join("test-user987").display(),
+                root.join("test-user987.tar.gz").display()
+            )
+        );
+
+        assert!(matches!(
+            file_map.run_after("echo {unknown}"),
+            Err(FileMapError::FormatError(_))
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+//
+//  lib.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Bathpack is a tool for automating the packaging of coursework files for submission at the University of Bath,
+//! specifically for the BSc/MComp Computer Science degree.
+//!
+//! Bathpack works by reading a configuration file in TOML format, called `bathpack.toml` by default, describing the
+//! locations of source files and destination locations, as well as details about the final folder/archive.
+//!
+//! Optionally, information about the destination can be specified separately, such as in another TOML file alongside
+//! `bathpack.toml` or inside/alongside Bathpack. This way, configurations for specific coursework submissions can be
+//! distributed to multiple users.
+
+extern crate blake3;
+extern crate chrono;
+extern crate flate2;
+extern crate glob;
+extern crate notify;
+extern crate regex;
+extern crate reqwest;
+#[cfg(feature = "update")]
+extern crate self_replace;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+extern crate strfmt;
+extern crate tar;
+extern crate toml;
+extern crate walkdir;
+extern crate zip;
+
+use std::path::{Path, PathBuf};
+
+pub mod checksum;
+pub mod config;
+pub mod file_map;
+pub mod logger;
+#[cfg(feature = "update")]
+pub mod update;
+pub mod verbosity;
+pub mod watch;
+
+/// Builds a [`PathBuf`][pathbuf] by joining each of `parts` onto `base` in turn with [`Path::join`][join].
+///
+/// [pathbuf]: https://doc.rust-lang.org/std/path/struct.PathBuf.html
+/// [join]: https://doc.rust-lang.org/std/path/struct.Path.html#method.join
+pub(crate) fn join_paths<P, I>(base: P, parts: I) -> PathBuf
+where
+    P: AsRef<Path>,
+    I: IntoIterator,
+    I::Item: AsRef<Path>,
+{
+    parts
+        .into_iter()
+        .fold(base.as_ref().to_path_buf(), |acc, part| acc.join(part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that parts are joined in order, and that an absolute part replaces everything before it, as with
+    /// `Path::join`.
+    #[test]
+    fn join_paths() {
+        assert_eq!(
+            super::join_paths("root", ["src", "main.rs"]),
+            PathBuf::from("root/src/main.rs")
+        );
+        assert_eq!(
+            super::join_paths("root", Vec::<&str>::new()),
+            PathBuf::from("root")
+        );
+        assert_eq!(
+            super::join_paths("root", ["src", "/tmp", "out"]),
+            PathBuf::from("/tmp/out")
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//
+//  logger.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Structured logging of pack operations to a [JSON Lines][jsonl] file, for auditing.
+//!
+//! Each line is a JSON object with a `ts` timestamp in UTC, an `event` name, and the fields of that event, e.g.
+//!
+//! ```text
+//! {"ts":"2024-03-01T09:00:00Z","event":"copy","src":"...","dst":"...","bytes":1234,"duration_ms":5}
+//! ```
+//!
+//! [jsonl]: https://jsonlines.org
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// An event recorded by a [`Logger`][logger].
+///
+/// [logger]: ./struct.Logger.html
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum LogEvent {
+    /// Packaging has started, and `files` files will be copied into `dest`.
+    Start { dest: PathBuf, files: usize },
+    /// A file has been copied.
+    Copy {
+        src: PathBuf,
+        dst: PathBuf,
+        bytes: u64,
+        duration_ms: u64,
+    },
+    /// Packaging has finished after copying `files` files.
+    End { files: usize, duration_ms: u64 },
+    /// Packaging has failed with `error`, and the files written so far have been rolled back.
+    Failed { error: String, duration_ms: u64 },
+}
+
+/// A single line of the log.
+#[derive(Serialize)]
+struct Entry<'a> {
+    ts: String,
+    #[serde(flatten)]
+    event: &'a LogEvent,
+}
+
+/// Writes [`LogEvent`][logevent]s to a file, one JSON object per line.
+///
+/// A `Logger` is a cheap handle that can be cloned and shared. The log is flushed whenever a handle is dropped, and
+/// the file is closed once the last handle is dropped.
+///
+/// [logevent]: ./enum.LogEvent.html
+#[derive(Clone)]
+pub struct Logger {
+    writer: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Logger {
+    /// Create a `Logger` that writes to the file at `path`, replacing it if it exists.
+    pub fn create<P>(path: P) -> io::Result<Logger>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::create(path)?;
+
+        Ok(Logger {
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+        })
+    }
+
+    /// Write `event` to the log, timestamped with the current time.
+    pub fn log_event(&self, event: LogEvent) -> io::Result<()> {
+        let entry = Entry {
+            ts: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            event: &event,
+        };
+        let line = serde_json::to_string(&entry)?;
+
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        writeln!(writer, "{}", line)
+    }
+}
+
+impl Drop for Logger {
+    fn drop(&mut self) {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = writer.flush();
+    }
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Logger").finish_non_exhaustive()
+    }
+}
+
+/// Two `Logger`s are equal if they are handles to the same log.
+impl PartialEq for Logger {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.writer, &other.writer)
+    }
+}
+
+impl Eq for Logger {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that each event is written as one JSON object per line, with the timestamp and event name first.
+    #[test]
+    fn log_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pack.log");
+
+        let logger = Logger::create(&path).unwrap();
+        logger
+            .log_event(LogEvent::Start {
+                dest: PathBuf::from("dest"),
+                files: 1,
+            })
+            .unwrap();
+        logger
+            .log_event(LogEvent::Copy {
+                src: PathBuf::from("a.rs"),
+                dst: PathBuf::from("dest/a.rs"),
+                bytes: 1234,
+                duration_ms: 5,
+            })
+            .unwrap();
+        drop(logger);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        assert!(lines[1].starts_with(r#"{"ts":""#));
+        assert!(lines[1].ends_with(
+            r#"Z","event":"copy","src":"a.rs","dst":"dest/a.rs","bytes":1234,"duration_ms":5}"#
+        ));
+
+        let start: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(start["event"], "start");
+        assert_eq!(start["files"], 1);
+    }
+}
@@ -15,24 +15,1052 @@
 //  limitations under the License.
 //
 
-//! Bathpack is a tool for automating the packaging of coursework files for submission at the University of Bath,
-//! specifically for the BSc/MComp Computer Science degree.
-//!
-//! Bathpack works by reading a configuration file in TOML format, called `bathpack.toml` by default, describing the
-//! locations of source files and destination locations, as well as details about the final folder/archive.
-//!
-//! Optionally, information about the destination can be specified separately, such as in another TOML file alongside
-//! `bathpack.toml` or inside/alongside Bathpack. This way, configurations for specific coursework submissions can be
-//! distributed to multiple users.
-
-extern crate serde;
+//! The `bathpack` command-line tool, which packages the files described by the nearest `bathpack.toml`.
+
+extern crate bathpack;
+extern crate chrono;
+extern crate clap;
+extern crate ctrlc;
+extern crate serde_json;
 extern crate toml;
 
-mod config;
+use bathpack::checksum::{ChecksumAlgorithm, Manifest, ManifestVerifier, VerifyStatus};
+use bathpack::config::validate::{Severity, Validator};
+use bathpack::config::{try_read_config, Config, CONFIG_FILE_NAME};
+use bathpack::file_map::{
+    is_plain_relative, CopyJournal, ExecuteOptions, FileMap, FileMapBuilder, FileMapError, Result,
+};
+use bathpack::logger::Logger;
+use bathpack::verbosity::Verbosity;
+use bathpack::watch::ChangeWatcher;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, exit};
+use std::time::SystemTime;
+
+/// Where the file map from the last successful run is recorded, relative to the root directory, so that dry runs can
+/// show what has changed since.
+const LAST_RUN_FILE: &str = ".bathpack/last-run.toml";
+
+/// Package coursework files for submission.
+#[derive(Debug, Parser)]
+#[command(version, about, arg_required_else_help = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Print every file operation as it happens, with timing information.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    /// Print nothing except errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Only look for bathpack.toml in the current directory, rather than also searching its parents.
+    #[arg(long, global = true)]
+    no_discover: bool,
+    /// Use this assignment name instead of the one in bathpack.toml, e.g. for the `{assignment}` template variable.
+    #[arg(long, global = true, value_name = "NAME")]
+    assignment: Option<String>,
+    /// Set the template variable KEY to VALUE, as if it were in `[vars]`. Can be given more than once.
+    #[arg(long, global = true, value_name = "KEY=VALUE")]
+    set_var: Vec<String>,
+}
+
+/// Subcommands of `bathpack`. With no subcommand, help is printed.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Copy the files to their destinations and create the archive.
+    Pack(Box<PackArgs>),
+    /// Check that the configuration is valid and that every source file exists, without copying anything.
+    Check,
+    /// Check the configuration for mistakes and for settings that aren't recommended for a submission.
+    Lint,
+    /// Check that the configuration is valid, without looking at any of the files it refers to.
+    Validate,
+    /// List the files matched by each source, without working out their destinations.
+    List {
+        /// Only list the files matched by the source with this key.
+        source_key: Option<String>,
+    },
+    /// Create a bathpack.toml in the current directory to start from.
+    Init,
+    /// Package the files, and then package them again whenever any of the source folders change.
+    Watch {
+        /// Delete the destination folder when stopped with Ctrl-C.
+        #[arg(long)]
+        clean_on_exit: bool,
+        /// Instead of packaging the files, run this shell command in the project root whenever any of the source
+        /// folders change. The path of a changed file is passed in `BATHPACK_CHANGED_FILE`.
+        #[arg(long, value_name = "COMMAND", conflicts_with = "clean_on_exit")]
+        on_change_exec: Option<String>,
+    },
+    /// Delete the destination folder and archive. Without --force, only lists what would be deleted.
+    Clean {
+        /// Actually delete the files.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the configuration bathpack is using, after merging in included files, `BATHPACK_*` environment variables
+    /// and command-line overrides.
+    ShowConfig {
+        /// How to print the configuration.
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Resolved)]
+        format: ConfigFormat,
+    },
+    /// Replace this bathpack binary with the latest release.
+    #[cfg(feature = "update")]
+    SelfUpdate {
+        /// Install the latest release even if it is older than this version.
+        #[arg(long)]
+        allow_downgrade: bool,
+    },
+    /// Re-hash the files listed in a manifest written by `pack --manifest`, reporting each one as OK, CORRUPTED or
+    /// MISSING. Exits with an error unless every file is OK.
+    VerifyChecksums {
+        /// The manifest to check, e.g. `submission/MANIFEST.sha256`. Files are looked for in the folder it is in.
+        manifest: PathBuf,
+        /// How to print the results.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+    },
+    /// Print the version, along with the git commit and time it was built from, for bug reports.
+    Version,
+    /// Print a script that sets up tab completion for bathpack in the given shell.
+    Completions {
+        /// The shell to print the completion script for.
+        #[arg(value_enum)]
+        shell: CompletionShell,
+    },
+}
+
+/// Shells that `bathpack completions` can print a completion script for.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl From<CompletionShell> for Shell {
+    fn from(shell: CompletionShell) -> Self {
+        match shell {
+            CompletionShell::Bash => Shell::Bash,
+            CompletionShell::Zsh => Shell::Zsh,
+            CompletionShell::Fish => Shell::Fish,
+        }
+    }
+}
+
+/// Formats for `bathpack verify-checksums`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ReportFormat {
+    /// One line per file, followed by a summary.
+    Text,
+    /// A JSON object with the result for each file and the number of files with each result.
+    Json,
+}
+
+/// Formats for `bathpack show-config`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConfigFormat {
+    /// TOML, exactly as parsed.
+    Raw,
+    /// TOML, with variables substituted into the destination name and archive path.
+    Resolved,
+    /// JSON, with variables substituted like `resolved`.
+    Json,
+}
+
+/// Arguments to `bathpack pack`.
+#[derive(Debug, Args)]
+struct PackArgs {
+    /// Add files to the existing archive instead of recreating it.
+    #[arg(long)]
+    append: bool,
+    /// Show what would be packaged, and what has changed since the last run, without copying anything.
+    #[arg(long)]
+    dry_run: bool,
+    /// Write a manifest of checksums into the destination folder, even if `destination.manifest` is not set.
+    #[arg(long)]
+    manifest: bool,
+    /// The hash algorithm for the manifest: sha256, sha512 or blake3. Overrides `destination.checksum_algorithm`.
+    #[arg(long, value_name = "ALGO")]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Write a JSON Lines log of every file copied to this path.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Don't run any hooks configured to run around copying, e.g. when debugging a failing pack.
+    #[arg(long)]
+    skip_hooks: bool,
+    /// Don't write `bathpack.log`, the log of every file copied, into the destination folder.
+    #[arg(long)]
+    no_log: bool,
+    /// Don't check that every source file exists before copying, e.g. in CI when an earlier step creates them.
+    #[arg(long)]
+    no_verify_existence: bool,
+    /// Put every file under this extra folder inside the destination folder, e.g. `submission/`.
+    #[arg(long, value_name = "PREFIX", value_parser = parse_prefix)]
+    prefix: Option<PathBuf>,
+    /// Remove these folders from the start of every destination inside the destination folder, e.g.
+    /// `submission/ab1234`. Destinations that don't start with them are left alone. Done before --prefix.
+    #[arg(long, value_name = "PREFIX")]
+    strip_prefix: Option<PathBuf>,
+    /// Write the archive to this path, relative to the project root. Overrides `destination.archive_path`.
+    #[arg(long, value_name = "PATH")]
+    archive_path: Option<PathBuf>,
+    /// Refuse to package source files adding up to more than this many bytes. Overrides
+    /// `destination.max_size_bytes`.
+    #[arg(long, value_name = "BYTES")]
+    max_size: Option<u64>,
+    /// Store this comment in the ZIP archive, with template variables such as `{username}` substituted. Overrides
+    /// `destination.archive_comment`.
+    #[arg(long, value_name = "TEXT")]
+    archive_comment: Option<String>,
+    /// Write the archive straight from the source files, without creating the destination folder.
+    #[arg(long, conflicts_with = "dry_run")]
+    archive_only: bool,
+    /// Leave out the source with this key, along with its destination location. Can be given more than once.
+    #[arg(long, value_name = "KEY")]
+    except: Vec<String>,
+    /// Only package source files with these extensions, e.g. `--only-extensions rs toml`. Case-insensitive.
+    #[arg(long, value_name = "EXT", num_args = 1..)]
+    only_extensions: Vec<String>,
+    /// Only package files from folder and glob sources that were modified after this ISO 8601 date or time, e.g.
+    /// `2024-03-01` (midnight, local time) or `2024-03-01T09:00:00Z`.
+    #[arg(long, value_name = "DATE", value_parser = parse_since)]
+    since: Option<SystemTime>,
+    /// Check that every copied file has the same SHA-256 checksum as its source once packing has finished.
+    #[arg(long, conflicts_with = "archive_only")]
+    verify: bool,
+    /// Print the number of source files and their total size once packing has finished.
+    #[arg(long)]
+    stats: bool,
+    /// Run this shell command in the project root once packing has finished. `{archive_path}`, `{dest_dir}` and
+    /// `{file_count}` are replaced with details of the run.
+    #[arg(long, value_name = "COMMAND")]
+    after: Option<String>,
+    /// Print only the number of files that would be packaged, without copying anything.
+    #[arg(long, conflicts_with_all = ["dry_run", "archive_only"])]
+    count_only: bool,
+    /// With --count-only, don't print a newline after the number.
+    #[arg(long, requires = "count_only")]
+    no_newline: bool,
+    /// Fail in place of copying the Nth file, to test how partial failures are handled. Only in debug builds, or with
+    /// the `testing` feature.
+    #[cfg(any(debug_assertions, feature = "testing"))]
+    #[arg(long, value_name = "N", hide = true)]
+    simulate_failure: Option<usize>,
+    /// Print errors to stderr as lines of JSON, with an error code, message and list of files, instead of as text.
+    #[arg(long)]
+    json_errors: bool,
+}
+
+/// The configuration file written by `bathpack init`.
+const INIT_TEMPLATE: &str = r#"username = "abc123"
+
+[sources]
+# A folder, and a glob pattern for the files to take from it.
+code = { path = "src", pattern = "**/*.java" }
+# A single file.
+report = "report.pdf"
+
+[destination]
+# The name of the folder to copy the files into. `{username}` is replaced with the username above.
+name = "coursework-{username}"
+# Whether to archive the folder, and how: "zip", "tar.gz", or false for no archive.
+archive = "zip"
 
-use config::{read_config, Config};
+[destination.locations]
+# Where each source goes, relative to the destination folder.
+code = "src"
+report = "."
+"#;
 
-/// Reads in a configuration file.
+/// Reads in a configuration file and runs the given subcommand on it.
 fn main() {
-    let config = read_config();
+    let cli = Cli::parse();
+
+    Verbosity::set(if cli.quiet {
+        Verbosity::QUIET
+    } else if cli.verbose {
+        Verbosity::VERBOSE
+    } else {
+        Verbosity::NORMAL
+    });
+
+    match cli.command {
+        Command::Init => {
+            if let Err(e) = init() {
+                eprintln!("Could not create {}: {}", CONFIG_FILE_NAME, e);
+                exit(1);
+            }
+
+            return;
+        }
+        Command::VerifyChecksums {
+            ref manifest,
+            format,
+        } => {
+            if !verify_checksums(manifest, format) {
+                exit(1);
+            }
+
+            return;
+        }
+        Command::Completions { shell } => {
+            // Generate into a buffer first, since the generator panics if it can't write, e.g. when piped into
+            // `head`.
+            let mut script = Vec::new();
+            clap_complete::generate(
+                Shell::from(shell),
+                &mut Cli::command(),
+                "bathpack",
+                &mut script,
+            );
+
+            match std::io::stdout().write_all(&script) {
+                Err(ref e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                    eprintln!("Could not print completions: {}", e);
+                    exit(1);
+                }
+                _ => return,
+            }
+        }
+        #[cfg(feature = "update")]
+        Command::SelfUpdate { allow_downgrade } => {
+            self_update(allow_downgrade);
+            return;
+        }
+        Command::Version => {
+            version();
+            return;
+        }
+        _ => {}
+    }
+
+    // `pack --json-errors` applies to problems with the config too, so it has to be known before reading it.
+    let json_errors = matches!(cli.command, Command::Pack(ref args) if args.json_errors);
+
+    let (mut config, root_dir) = match cli_config(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            print_error(&e, json_errors);
+            exit(1);
+        }
+    };
+
+    print_warnings(config.warnings());
+
+    // Only commands that package or list the files fill in missing locations. The others show or check the config
+    // as it was written.
+    if matches!(
+        cli.command,
+        Command::Pack(_) | Command::List { .. } | Command::Watch { .. }
+    ) {
+        let (filled, warnings) = config.auto_fill_locations();
+
+        for warning in warnings {
+            eprintln!("[WARN] {}", warning);
+        }
+
+        config = filled;
+    }
+
+    Verbosity::VERBOSE.print(format_args!(
+        "Using {}",
+        root_dir.join(CONFIG_FILE_NAME).display()
+    ));
+
+    match cli.command {
+        Command::Check => match check(config, root_dir) {
+            Ok(()) => Verbosity::NORMAL.print(format_args!(
+                "Check passed: bathpack.toml is valid and all source files exist."
+            )),
+            Err(e) => {
+                eprintln!("Check failed: {}", e);
+                exit(1);
+            }
+        },
+        Command::Lint => {
+            if !lint(&config, &root_dir) {
+                exit(1);
+            }
+        }
+        Command::Validate => {
+            if !validate(&config) {
+                exit(1);
+            }
+        }
+        Command::List { source_key } => {
+            if let Err(e) = list(config, root_dir, source_key.as_deref()) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Command::Pack(args) => pack(config, root_dir, &args),
+        Command::Watch {
+            clean_on_exit,
+            on_change_exec,
+        } => {
+            if let Err(e) = watch(config, root_dir, clean_on_exit, on_change_exec.as_deref()) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Command::Clean { force } => {
+            if let Err(e) = clean(config, root_dir, force) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Command::ShowConfig { format } => {
+            if let Err(e) = show_config(&config, format) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Command::Init
+        | Command::Completions { .. }
+        | Command::VerifyChecksums { .. }
+        | Command::Version => {
+            unreachable!("handled before the config is read")
+        }
+        #[cfg(feature = "update")]
+        Command::SelfUpdate { .. } => unreachable!("handled before the config is read"),
+    }
+}
+
+/// Packages the files described by `config`, printing an error and exiting on failure. With `--json-errors`, the error
+/// is printed as a line of JSON.
+fn pack(config: Config, root_dir: PathBuf, args: &PackArgs) {
+    if let Err(e) = run(config, root_dir, args) {
+        print_error(&e, args.json_errors);
+        exit(1);
+    }
+}
+
+/// Reads the config, with the overrides from `--assignment` and `--set-var` applied.
+fn cli_config(cli: &Cli) -> Result<(Config, PathBuf)> {
+    let (mut config, root_dir) = try_read_config(!cli.no_discover)?;
+
+    if let Some(ref assignment) = cli.assignment {
+        config = config.with_assignment_name(assignment);
+    }
+
+    for var in &cli.set_var {
+        let result = match var.split_once('=') {
+            Some((name, value)) => config.set_var(name, value).map_err(|e| e.to_string()),
+            None => Err("expected KEY=VALUE".to_string()),
+        };
+
+        config = result.map_err(|reason| FileMapError::InvalidSetVar {
+            arg: var.clone(),
+            reason,
+        })?;
+    }
+
+    Ok((config, root_dir))
+}
+
+/// Prints each of `warnings`, unless `--quiet` was given.
+fn print_warnings<W>(warnings: &[W])
+where
+    W: fmt::Display,
+{
+    if Verbosity::NORMAL.enabled() {
+        for warning in warnings {
+            eprintln!("[WARN] {}", warning);
+        }
+    }
+}
+
+/// Prints `error`, as a JSON object if `json` is set.
+fn print_error(error: &FileMapError, json: bool) {
+    if json {
+        eprintln!("{}", json_error(error));
+    } else {
+        eprintln!("{}", error);
+    }
+}
+
+/// `error` as a JSON object with its code, message and the files it is about.
+fn json_error(error: &FileMapError) -> serde_json::Value {
+    let files: Vec<String> = error
+        .files()
+        .iter()
+        .map(|file| file.to_string_lossy().into_owned())
+        .collect();
+
+    serde_json::json!({
+        "error_code": error.code(),
+        "message": error.to_string(),
+        "files": files,
+    })
+}
+
+/// Parses the argument to `--prefix`, which must be a relative path without `..`, so that it stays inside the
+/// destination folder.
+fn parse_prefix(prefix: &str) -> std::result::Result<PathBuf, String> {
+    let path = PathBuf::from(prefix);
+
+    if is_plain_relative(&path) {
+        Ok(path)
+    } else {
+        Err(format!(
+            "`{}` must be a relative path without `..`, e.g. submission/",
+            prefix
+        ))
+    }
+}
+
+/// Parses the argument to `--since`: an RFC 3339 time, a date and time without a time zone, or just a date. Times
+/// without a time zone are taken to be local, and a date on its own means midnight at the start of that day.
+fn parse_since(date: &str) -> std::result::Result<SystemTime, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Ok(time.into());
+    }
+
+    let naive = NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d").map(|day| day.and_time(NaiveTime::MIN))
+        })
+        .map_err(|_| format!("`{}` is not an ISO 8601 date, e.g. 2024-03-01", date))?;
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(SystemTime::from)
+        .ok_or_else(|| format!("`{}` does not exist in the local time zone", date))
+}
+
+/// Builds a file map from `config`, rooted at `root_dir`, and copies each file to its destination. For a dry run, the
+/// differences from the last run are printed instead.
+fn run(config: Config, root_dir: PathBuf, args: &PackArgs) -> Result<()> {
+    let mut builder = FileMapBuilder::from(config, root_dir.clone());
+
+    if args.no_verify_existence {
+        eprintln!("[WARN] Not checking that source files exist (--no-verify-existence)");
+        builder = builder.with_verify_existence(false);
+    }
+
+    if let Some(max_size) = args.max_size {
+        builder = builder.with_max_size(max_size);
+    }
+
+    if let Some(ref comment) = args.archive_comment {
+        builder = builder.with_archive_comment(comment);
+    }
+
+    if let Some(ref log_file) = args.log_file {
+        builder = builder.with_logger(Logger::create(log_file)?);
+    }
+
+    if let Some(since) = args.since {
+        builder = builder.with_modified_since(since);
+    }
+
+    if !args.except.is_empty() {
+        let keys: Vec<&str> = args.except.iter().map(String::as_str).collect();
+        builder = builder.with_exclude_keys(&keys);
+    }
+
+    let mut file_map = builder.build()?;
+    print_warnings(file_map.warnings());
+
+    if !args.only_extensions.is_empty() {
+        file_map = file_map.filter(|src, _| {
+            src.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+                args.only_extensions
+                    .iter()
+                    .any(|only| only.trim_start_matches('.').eq_ignore_ascii_case(ext))
+            })
+        });
+    }
+
+    if args.count_only {
+        if args.no_newline {
+            print!("{}", file_map.source_count());
+        } else {
+            println!("{}", file_map.source_count());
+        }
+
+        return Ok(());
+    }
+
+    if let Some(ref prefix) = args.strip_prefix {
+        file_map = file_map.with_stripped_prefix(prefix);
+    }
+
+    if let Some(ref prefix) = args.prefix {
+        file_map = file_map.with_prefix(prefix)?;
+    }
+
+    if let Some(ref archive_path) = args.archive_path {
+        file_map = file_map.with_archive_path(archive_path);
+    }
+
+    Verbosity::NORMAL.print(format_args!("{}", file_map));
+
+    let last_run_path = root_dir.join(LAST_RUN_FILE);
+
+    if args.dry_run {
+        match read_last_run(&last_run_path) {
+            Some(last_run) => println!("Changes since the last run:\n{}", last_run.diff(&file_map)),
+            None => println!("No previous run to compare against."),
+        }
+
+        return Ok(());
+    }
+
+    if args.skip_hooks {
+        eprintln!("[WARN] Hooks disabled via --skip-hooks");
+    }
+
+    let options = ExecuteOptions {
+        append: args.append,
+        manifest: args.manifest,
+        checksum_algorithm: args.checksum_algorithm,
+        skip_hooks: args.skip_hooks,
+        copy_log: !args.no_log,
+        #[cfg(any(debug_assertions, feature = "testing"))]
+        simulate_failure: args.simulate_failure,
+    };
+
+    if args.archive_only {
+        print_warnings(&file_map.execute_into_archive(&options)?);
+    } else {
+        let mut journal = CopyJournal::default();
+
+        if let Err(e) = file_map.execute_journaled(&options, &mut journal) {
+            if !journal.is_empty() {
+                // The backups aren't needed once they have been restored.
+                let rolled_back = file_map
+                    .rollback(&journal)
+                    .and_then(|()| journal.discard().map_err(FileMapError::from));
+
+                match rolled_back {
+                    Ok(()) => eprintln!("Rolled back the files written before the error."),
+                    Err(rollback_error) if args.json_errors => {
+                        eprintln!("{}", json_error(&rollback_error))
+                    }
+                    Err(rollback_error) => eprintln!("Could not roll back: {}", rollback_error),
+                }
+            }
+
+            return Err(e);
+        }
+
+        journal.discard()?;
+
+        if args.verify {
+            file_map.verify_integrity()?;
+            Verbosity::NORMAL.print(format_args!("Verified {} files", file_map.len()));
+        }
+    }
+
+    if args.stats {
+        println!(
+            "{} source files, {} bytes, in {} folders",
+            file_map.source_count(),
+            file_map.total_size()?,
+            file_map.pair_count_by_dest_dir().len()
+        );
+    }
+
+    if let Err(e) = write_last_run(&last_run_path, &file_map) {
+        eprintln!(
+            "Could not record this run in {}: {}",
+            last_run_path.display(),
+            e
+        );
+    }
+
+    if let Some(ref command) = args.after {
+        file_map.run_after(command)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the file map recorded by the last successful run, if there is one.
+fn read_last_run(path: &Path) -> Option<FileMap> {
+    let contents = fs::read_to_string(path).ok()?;
+    toml::from_str(&contents).ok()
+}
+
+/// Records `file_map` as the file map of the last successful run.
+fn write_last_run(
+    path: &Path,
+    file_map: &FileMap,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, toml::to_string(file_map)?)?;
+
+    Ok(())
+}
+
+/// Warns about likely mistakes in `config`, then builds a file map from it, rooted at `root_dir`, without copying
+/// anything.
+fn check(config: Config, root_dir: PathBuf) -> Result<()> {
+    for warning in Validator::new(&config).validate_with_fs(&root_dir) {
+        eprintln!("[WARN] {}", warning);
+    }
+
+    let file_map = FileMapBuilder::from(config, root_dir).build()?;
+    print_warnings(file_map.warnings());
+
+    Ok(())
+}
+
+/// Prints every issue that linting `config` finds, returning whether none of them were errors.
+fn lint(config: &Config, root_dir: &Path) -> bool {
+    let issues = Validator::new(config).lint(root_dir);
+
+    if issues.is_empty() {
+        Verbosity::NORMAL.print(format_args!("No issues found."));
+    }
+
+    for issue in &issues {
+        eprintln!("[{}] {}", issue.severity(), issue);
+    }
+
+    issues
+        .iter()
+        .all(|issue| issue.severity() != Severity::Error)
+}
+
+/// Prints every error in `config` found without looking at the filesystem, returning whether there were none. Parse
+/// errors are reported when the config is read, before this is reached.
+fn validate(config: &Config) -> bool {
+    match Validator::new(config).report_all() {
+        Ok(()) => {
+            Verbosity::NORMAL.print(format_args!("Config is valid"));
+            true
+        }
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+
+            false
+        }
+    }
+}
+
+/// Prints a tree of the sources in `config` and the files each one matches, relative to `root_dir`. If `source_key` is
+/// given, only that source is listed.
+fn list(config: Config, root_dir: PathBuf, source_key: Option<&str>) -> Result<()> {
+    if let Some(key) = source_key {
+        if config.source_by_key(key).is_none() {
+            eprintln!("No source with the key `{}`", key);
+            exit(1);
+        }
+    }
+
+    if let Some(assignment_name) = config.assignment_name() {
+        println!("Assignment: {}\n", assignment_name);
+    }
+
+    let (matched, warnings) = FileMapBuilder::from(config, root_dir.clone()).matched_files()?;
+    print_warnings(&warnings);
+
+    for (key, files) in &matched {
+        if source_key.is_some_and(|source_key| source_key != key) {
+            continue;
+        }
+
+        println!("{}", key);
+
+        for (i, file) in files.iter().enumerate() {
+            let branch = if i + 1 == files.len() {
+                "└──"
+            } else {
+                "├──"
+            };
+            let relative = file.strip_prefix(&root_dir).unwrap_or(file);
+            println!("{} {}", branch, relative.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces this binary with the latest release, printing an error and exiting on failure.
+#[cfg(feature = "update")]
+fn self_update(allow_downgrade: bool) {
+    use bathpack::update::{self, UpdateOutcome};
+
+    match update::update(allow_downgrade) {
+        Ok(UpdateOutcome::UpToDate { version }) => Verbosity::NORMAL.print(format_args!(
+            "bathpack {} is already the latest version",
+            version
+        )),
+        Ok(UpdateOutcome::Updated { from, to }) => {
+            Verbosity::NORMAL.print(format_args!("Updated bathpack from {} to {}", from, to))
+        }
+        Err(e) => {
+            eprintln!("Could not update: {}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Checks the files listed in the manifest at `path` against their checksums, printing the results in `format`. Returns
+/// whether every file matched.
+fn verify_checksums(path: &Path, format: ReportFormat) -> bool {
+    let manifest = match Manifest::read(path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            return false;
+        }
+    };
+
+    let dest_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let results = ManifestVerifier::new(manifest, dest_dir).verify();
+    let count = |status| {
+        results
+            .iter()
+            .filter(|result| result.status == status)
+            .count()
+    };
+    let (ok, corrupted, missing) = (
+        count(VerifyStatus::Ok),
+        count(VerifyStatus::Corrupted),
+        count(VerifyStatus::Missing),
+    );
+
+    match format {
+        ReportFormat::Text => {
+            for result in &results {
+                println!("{:<9} {}", result.status, result.path);
+            }
+
+            Verbosity::NORMAL.print(format_args!(
+                "{} OK, {} corrupted, {} missing",
+                ok, corrupted, missing
+            ));
+        }
+        ReportFormat::Json => println!(
+            "{:#}",
+            serde_json::json!({
+                "results": results,
+                "ok": ok,
+                "corrupted": corrupted,
+                "missing": missing,
+            })
+        ),
+    }
+
+    ok == results.len()
+}
+
+/// Prints the version, and the git commit and time recorded by the build script.
+fn version() {
+    let built = env!("BATHPACK_BUILD_TIMESTAMP")
+        .parse()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map_or_else(
+            || "unknown".to_string(),
+            |built| built.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        );
+
+    println!("bathpack {}", env!("CARGO_PKG_VERSION"));
+    println!("commit: {}", env!("BATHPACK_GIT_COMMIT"));
+    println!("built:  {}", built);
+}
+
+/// Writes a starting configuration file to the current directory, refusing to replace one that already exists.
+fn init() -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(CONFIG_FILE_NAME)?;
+    file.write_all(INIT_TEMPLATE.as_bytes())?;
+
+    Verbosity::NORMAL.print(format_args!(
+        "Created {}. Edit it to describe your coursework, then run `bathpack pack`.",
+        CONFIG_FILE_NAME
+    ));
+
+    Ok(())
+}
+
+/// Prints `config` in the given format.
+fn show_config(
+    config: &Config,
+    format: ConfigFormat,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let output = match format {
+        ConfigFormat::Raw => config.to_toml()?,
+        ConfigFormat::Resolved => config.resolve()?.to_toml()?,
+        ConfigFormat::Json => serde_json::to_string_pretty(&config.resolve()?)?,
+    };
+
+    println!("{}", output.trim_end());
+
+    Ok(())
+}
+
+/// Deletes the destination folder and any archives that packaging with `config` would have created, or, unless `force`
+/// is set, lists what would be deleted.
+fn clean(
+    config: Config,
+    root_dir: PathBuf,
+    force: bool,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let existing: Vec<PathBuf> = FileMapBuilder::from(config, root_dir)
+        .clean_paths()?
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect();
+
+    if existing.is_empty() {
+        Verbosity::NORMAL.print(format_args!("Nothing to clean."));
+        return Ok(());
+    }
+
+    for path in &existing {
+        if !force {
+            Verbosity::NORMAL.print(format_args!("Would remove {}", path.display()));
+        } else if path.is_dir() {
+            fs::remove_dir_all(path)?;
+            Verbosity::NORMAL.print(format_args!("Removed {}", path.display()));
+        } else {
+            fs::remove_file(path)?;
+            Verbosity::NORMAL.print(format_args!("Removed {}", path.display()));
+        }
+    }
+
+    if !force {
+        Verbosity::NORMAL.print(format_args!("Run with --force to remove them."));
+    }
+
+    Ok(())
+}
+
+/// Packages the files described by `config`, and then packages them again after every change to the source folders,
+/// until stopped with Ctrl-C. With `on_change_exec`, that command is run after every change instead.
+fn watch(
+    config: Config,
+    root_dir: PathBuf,
+    clean_on_exit: bool,
+    on_change_exec: Option<&str>,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut ignore = vec![root_dir.join(".bathpack")];
+    let mut dest_dir = None;
+
+    if on_change_exec.is_some() {
+        // The command may well package the files itself, so changes to the output are ignored all the same.
+        ignore.extend(
+            FileMapBuilder::from(config.clone(), root_dir.clone())
+                .output_paths()
+                .unwrap_or_default(),
+        );
+    } else if let Some(file_map) = watch_run(&config, &root_dir) {
+        // Run once first, to find out where the output goes so that changes to it can be ignored.
+        ignore.push(file_map.dest_dir().to_path_buf());
+        ignore.push(file_map.archive_path());
+        dest_dir = Some(file_map.dest_dir().to_path_buf());
+    }
+
+    let dirs: Vec<PathBuf> = config
+        .source_dirs()
+        .iter()
+        .map(|dir| root_dir.join(dir))
+        .collect();
+    let watcher = ChangeWatcher::new(&dirs, ignore)?;
+
+    let stop_handle = watcher.stop_handle();
+    ctrlc::set_handler(move || stop_handle.stop())?;
+
+    Verbosity::NORMAL.print(format_args!(
+        "Watching {} folders for changes. Press Ctrl-C to stop.",
+        dirs.len()
+    ));
+
+    while let Some(changed) = watcher.wait() {
+        Verbosity::VERBOSE.print(format_args!("{} paths changed", changed.len()));
+
+        if let Some(command) = on_change_exec {
+            watch_exec(command, &root_dir, &changed);
+        } else if let Some(file_map) = watch_run(&config, &root_dir) {
+            dest_dir = Some(file_map.dest_dir().to_path_buf());
+        }
+    }
+
+    if let (true, Some(dest_dir)) = (clean_on_exit, dest_dir) {
+        // Refuses to go on if the destination is the project folder itself, or outside it.
+        FileMapBuilder::from(config, root_dir).clean_paths()?;
+
+        if dest_dir.exists() {
+            fs::remove_dir_all(&dest_dir)?;
+            Verbosity::NORMAL.print(format_args!("Removed {}", dest_dir.display()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `command` with `sh -c` in `root_dir` for `bathpack watch --on-change-exec`, with the first of the `changed`
+/// paths in `BATHPACK_CHANGED_FILE`. The command's output goes straight to the terminal, and it failing is reported
+/// without stopping the watch.
+fn watch_exec(command: &str, root_dir: &Path, changed: &[PathBuf]) {
+    let timestamp = chrono::Local::now().format("%H:%M:%S");
+
+    let mut process = process::Command::new("sh");
+    process.arg("-c").arg(command).current_dir(root_dir);
+
+    if let Some(path) = changed.first() {
+        process.env("BATHPACK_CHANGED_FILE", path);
+    }
+
+    match process.status() {
+        Ok(status) if status.success() => {
+            Verbosity::NORMAL.print(format_args!("[{}] Ran `{}`", timestamp, command))
+        }
+        Ok(status) => match status.code() {
+            Some(code) => eprintln!("[{}] `{}` exited with code {}", timestamp, command, code),
+            None => eprintln!("[{}] `{}` was killed", timestamp, command),
+        },
+        Err(e) => eprintln!("[{}] Could not run `{}`: {}", timestamp, command, e),
+    }
+}
+
+/// Builds and executes a file map from `config` for `bathpack watch`, printing a timestamped summary or error. Returns
+/// the file map if it was built.
+fn watch_run(config: &Config, root_dir: &Path) -> Option<FileMap> {
+    let timestamp = chrono::Local::now().format("%H:%M:%S");
+
+    let file_map = match FileMapBuilder::from(config.clone(), root_dir.to_path_buf()).build() {
+        Ok(file_map) => file_map,
+        Err(e) => {
+            eprintln!("[{}] {}", timestamp, e);
+            return None;
+        }
+    };
+
+    print_warnings(file_map.warnings());
+
+    match file_map.execute() {
+        Ok(()) => Verbosity::NORMAL.print(format_args!(
+            "[{}] Packaged files into {}",
+            timestamp,
+            file_map.dest_dir().display()
+        )),
+        Err(e) => eprintln!("[{}] {}", timestamp, e),
+    }
+
+    Some(file_map)
 }
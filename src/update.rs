@@ -0,0 +1,318 @@
+//
+//  update.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Replacing the running bathpack binary with the latest release from GitHub.
+//!
+//! Each release has a binary for every supported platform, named as returned by [`asset_name`][asset_name], and a
+//! `<binary>.sha256` file containing its SHA-256 checksum. Only available with the `update` feature.
+//!
+//! [asset_name]: ./fn.asset_name.html
+
+use crate::checksum::to_hex;
+use crate::verbosity::Verbosity;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// The GitHub API endpoint describing the latest release.
+pub const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/bathoverflow/bathpack/releases/latest";
+
+/// The version of the running binary.
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A release on GitHub, as returned by the releases API.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Release {
+    /// The release's tag, e.g. `v0.2.0`.
+    pub tag_name: String,
+    /// The files attached to the release.
+    pub assets: Vec<Asset>,
+}
+
+/// A file attached to a [`Release`][release].
+///
+/// [release]: ./struct.Release.html
+#[derive(Clone, Debug, Deserialize)]
+pub struct Asset {
+    /// The file name.
+    pub name: String,
+    /// Where the file can be downloaded from.
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// The attached file called `name`, if there is one.
+    pub fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+}
+
+/// What [`update`][update] did.
+///
+/// [update]: ./fn.update.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UpdateOutcome {
+    /// The running binary is already the latest release.
+    UpToDate { version: String },
+    /// The running binary was replaced with the release `to`.
+    Updated { from: String, to: String },
+}
+
+/// The name of the release asset for the platform bathpack was built for, e.g. `bathpack-x86_64-linux`.
+pub fn asset_name() -> String {
+    let suffix = if cfg!(windows) { ".exe" } else { "" };
+    format!(
+        "bathpack-{}-{}{}",
+        env::consts::ARCH,
+        env::consts::OS,
+        suffix
+    )
+}
+
+/// Fetch the details of the latest release.
+pub fn latest_release() -> Result<Release> {
+    let body = client()?
+        .get(LATEST_RELEASE_URL)
+        .header("Accept", "application/vnd.github+json")
+        .send()?
+        .error_for_status()?
+        .text()?;
+
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// Replace the running binary with the latest release, if it is newer. If the latest release is older, e.g. because a
+/// broken release was withdrawn, it is only installed if `allow_downgrade` is set.
+pub fn update(allow_downgrade: bool) -> Result<UpdateOutcome> {
+    let release = latest_release()?;
+    let latest = release.tag_name.trim_start_matches('v').to_string();
+
+    let current_version =
+        parse_version(CURRENT_VERSION).ok_or_else(|| UpdateError::InvalidVersion {
+            version: CURRENT_VERSION.to_string(),
+        })?;
+    let latest_version = parse_version(&latest).ok_or_else(|| UpdateError::InvalidVersion {
+        version: release.tag_name.clone(),
+    })?;
+
+    if latest_version == current_version {
+        return Ok(UpdateOutcome::UpToDate { version: latest });
+    }
+
+    if latest_version < current_version && !allow_downgrade {
+        return Err(UpdateError::Downgrade {
+            current: CURRENT_VERSION.to_string(),
+            latest,
+        });
+    }
+
+    let name = asset_name();
+    let checksum_name = format!("{}.sha256", name);
+    let binary = release
+        .asset(&name)
+        .ok_or_else(|| UpdateError::MissingAsset { name: name.clone() })?;
+    let checksum = release
+        .asset(&checksum_name)
+        .ok_or(UpdateError::MissingAsset {
+            name: checksum_name,
+        })?;
+
+    let client = client()?;
+    let bytes = client
+        .get(&binary.browser_download_url)
+        .send()?
+        .error_for_status()?
+        .bytes()?;
+    let checksum_file = client
+        .get(&checksum.browser_download_url)
+        .send()?
+        .error_for_status()?
+        .text()?;
+
+    let expected = parse_checksum(&checksum_file).unwrap_or_default();
+    let actual = to_hex(&Sha256::digest(&bytes));
+
+    if !expected.eq_ignore_ascii_case(&actual) {
+        return Err(UpdateError::ChecksumMismatch { expected, actual });
+    }
+
+    Verbosity::VERBOSE.print(format_args!("Downloaded {} (sha256 {})", name, actual));
+
+    // Write the new binary next to the current one, so that it is on the same filesystem when it is moved into place.
+    let current_exe = env::current_exe()?;
+    let temp_path = current_exe.with_file_name(format!(".{}.new", name));
+    fs::write(&temp_path, &bytes)?;
+
+    let result = self_replace::self_replace(&temp_path);
+    fs::remove_file(&temp_path)?;
+    result?;
+
+    Ok(UpdateOutcome::Updated {
+        from: CURRENT_VERSION.to_string(),
+        to: latest,
+    })
+}
+
+/// An HTTP client that identifies itself to GitHub, which rejects requests without a `User-Agent`.
+fn client() -> Result<reqwest::blocking::Client> {
+    Ok(reqwest::blocking::Client::builder()
+        .user_agent(format!("bathpack/{}", CURRENT_VERSION))
+        .build()?)
+}
+
+/// Parse the `major.minor.patch` part of a version like `1.2.3` or `1.2.3-beta.1`. Pre-release and build suffixes are
+/// ignored.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|part| part.parse().ok());
+
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+
+    if parts.next().is_some() {
+        None
+    } else {
+        Some(version)
+    }
+}
+
+/// The checksum in the contents of a `.sha256` file, which is either the bare checksum or in the `sha256sum` format of
+/// a checksum followed by a file name.
+fn parse_checksum(contents: &str) -> Option<String> {
+    contents.split_whitespace().next().map(String::from)
+}
+
+/// Convenience alias for functions that return [`UpdateError`][error]s.
+///
+/// [error]: ./enum.UpdateError.html
+pub type Result<T> = std::result::Result<T, UpdateError>;
+
+/// Errors to do with updating bathpack.
+#[derive(Debug)]
+pub enum UpdateError {
+    /// Wraps a [`reqwest::Error`][reqwesterr] from talking to GitHub.
+    ///
+    /// [reqwesterr]: ../../reqwest/struct.Error.html
+    Http(reqwest::Error),
+    /// Wraps a [`std::io::Error`][ioerr].
+    ///
+    /// [ioerr]: https://doc.rust-lang.org/std/io/struct.Error.html
+    Io(io::Error),
+    /// The release details could not be parsed.
+    Json(serde_json::Error),
+    /// A version number, either of the running binary or of the release, could not be parsed.
+    InvalidVersion { version: String },
+    /// The latest release is older than the running binary, and downgrading wasn't allowed.
+    Downgrade { current: String, latest: String },
+    /// The latest release has no file with this name.
+    MissingAsset { name: String },
+    /// The downloaded binary's SHA-256 checksum did not match the one published with it.
+    ChecksumMismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UpdateError::Http(ref http_err) => write!(f, "Could not reach GitHub: {}", http_err),
+            UpdateError::Io(ref io_err) => write!(f, "{}", io_err),
+            UpdateError::Json(ref json_err) => write!(f, "Could not read the release details: {}", json_err),
+            UpdateError::InvalidVersion { ref version } => write!(f, "Invalid version `{}`", version),
+            UpdateError::Downgrade {
+                ref current,
+                ref latest,
+            } => write!(
+                f,
+                "The latest release ({}) is older than this version ({}); use --allow-downgrade to install it anyway",
+                latest, current
+            ),
+            UpdateError::MissingAsset { ref name } => {
+                write!(f, "The latest release has no `{}` for this platform", name)
+            }
+            UpdateError::ChecksumMismatch {
+                ref expected,
+                ref actual,
+            } => write!(
+                f,
+                "Checksum mismatch for the downloaded binary: expected {}, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            UpdateError::Http(ref http_err) => Some(http_err),
+            UpdateError::Io(ref io_err) => Some(io_err),
+            UpdateError::Json(ref json_err) => Some(json_err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for UpdateError {
+    fn from(http_error: reqwest::Error) -> Self {
+        UpdateError::Http(http_error)
+    }
+}
+
+impl From<io::Error> for UpdateError {
+    fn from(io_error: io::Error) -> Self {
+        UpdateError::Io(io_error)
+    }
+}
+
+impl From<serde_json::Error> for UpdateError {
+    fn from(json_error: serde_json::Error) -> Self {
+        UpdateError::Json(json_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that versions are parsed with any pre-release suffix ignored, and that malformed versions are rejected.
+    #[test]
+    fn parse_versions() {
+        assert_eq!(parse_version("0.1.0"), Some((0, 1, 0)));
+        assert_eq!(parse_version("1.12.3-beta.1"), Some((1, 12, 3)));
+        assert_eq!(parse_version("2.0.0+build5"), Some((2, 0, 0)));
+        assert!(parse_version("1.2.3") < parse_version("1.10.0"));
+
+        assert_eq!(parse_version("1.2"), None);
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert_eq!(parse_version("v1.2.3"), None);
+    }
+
+    /// Test that the checksum is read from both a bare checksum and `sha256sum` output.
+    #[test]
+    fn parse_checksums() {
+        assert_eq!(parse_checksum("abc123\n"), Some("abc123".to_string()));
+        assert_eq!(
+            parse_checksum("abc123  bathpack-x86_64-linux\n"),
+            Some("abc123".to_string())
+        );
+        assert_eq!(parse_checksum(""), None);
+    }
+}
@@ -0,0 +1,95 @@
+//
+//  verbosity.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! A global verbosity level controlling how much progress information is printed.
+//!
+//! All progress output goes through [`Verbosity::print`][print], so it can later be redirected to a logging framework
+//! without changing any of the places that produce it.
+//!
+//! [print]: ./struct.Verbosity.html#method.print
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The current global verbosity level.
+static LEVEL: AtomicU8 = AtomicU8::new(1);
+
+/// A verbosity level. Messages are printed if their level is at or below the global level.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Verbosity(u8);
+
+impl Verbosity {
+    /// Print nothing except errors.
+    pub const QUIET: Verbosity = Verbosity(0);
+    /// Print a summary of what is being done. This is the default.
+    pub const NORMAL: Verbosity = Verbosity(1);
+    /// Print every operation as it happens, along with timing information.
+    pub const VERBOSE: Verbosity = Verbosity(2);
+
+    /// Create a `Verbosity` from a numeric level, clamped to the highest level.
+    pub fn new(level: u8) -> Verbosity {
+        Verbosity(level.min(Verbosity::VERBOSE.0))
+    }
+
+    /// The numeric level of this `Verbosity`.
+    pub fn level(self) -> u8 {
+        self.0
+    }
+
+    /// The current global verbosity level.
+    pub fn get() -> Verbosity {
+        Verbosity(LEVEL.load(Ordering::Relaxed))
+    }
+
+    /// Set the global verbosity level.
+    pub fn set(verbosity: Verbosity) {
+        LEVEL.store(verbosity.0, Ordering::Relaxed);
+    }
+
+    /// Whether messages at this level should currently be printed.
+    pub fn enabled(self) -> bool {
+        self <= Verbosity::get()
+    }
+
+    /// Print a message at this level to stdout, if the global level allows it.
+    pub fn print(self, args: fmt::Arguments) {
+        if self.enabled() {
+            println!("{}", args);
+        }
+    }
+}
+
+impl Default for Verbosity {
+    fn default() -> Self {
+        Verbosity::NORMAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that numeric levels map onto the named levels, with anything above the highest level clamped to it.
+    #[test]
+    fn new() {
+        assert_eq!(Verbosity::new(0), Verbosity::QUIET);
+        assert_eq!(Verbosity::new(1), Verbosity::NORMAL);
+        assert_eq!(Verbosity::new(2), Verbosity::VERBOSE);
+        assert_eq!(Verbosity::new(7), Verbosity::VERBOSE);
+        assert!(Verbosity::QUIET < Verbosity::NORMAL && Verbosity::NORMAL < Verbosity::VERBOSE);
+    }
+}
@@ -0,0 +1,164 @@
+//
+//  watch.rs
+//  bathpack
+//
+//  Copyright (c) 2018 Søren Mortensen, Andrei Trandafir, Stavros Karantonis.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except
+//  in compliance with the License.  You may obtain a copy of the License at
+//
+//  http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software distributed under the
+//  License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either
+//  express or implied.  See the License for the specific language governing permissions and
+//  limitations under the License.
+//
+
+//! Watching source folders for changes, so that files can be packaged again whenever they change.
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// How long to wait after a change for more changes, so that a burst of changes (e.g. from saving several files at
+/// once) is reported together.
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watches a set of folders for changes to the files inside them.
+pub struct ChangeWatcher {
+    /// Kept so that the folders carry on being watched until the `ChangeWatcher` is dropped.
+    _watcher: RecommendedWatcher,
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+    ignore: Vec<PathBuf>,
+}
+
+/// A message to a [`ChangeWatcher`][watcher], either from the underlying watcher or from a
+/// [`StopHandle`][stophandle].
+///
+/// [watcher]: ./struct.ChangeWatcher.html
+/// [stophandle]: ./struct.StopHandle.html
+enum Message {
+    Changed(Vec<PathBuf>),
+    Stop,
+}
+
+impl ChangeWatcher {
+    /// Start watching each of `dirs`, including their subfolders. Changes to paths inside any of `ignore` are not
+    /// reported, so that e.g. the destination folder can be inside a watched folder without every run of bathpack
+    /// causing another one.
+    pub fn new(dirs: &[PathBuf], ignore: Vec<PathBuf>) -> notify::Result<ChangeWatcher> {
+        let (sender, receiver) = mpsc::channel();
+        let event_sender = sender.clone();
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if let Ok(event) = event {
+                if let EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) =
+                    event.kind
+                {
+                    let _ = event_sender.send(Message::Changed(event.paths));
+                }
+            }
+        })?;
+
+        for dir in dirs {
+            watcher.watch(dir, RecursiveMode::Recursive)?;
+        }
+
+        Ok(ChangeWatcher {
+            _watcher: watcher,
+            sender,
+            receiver,
+            ignore,
+        })
+    }
+
+    /// A handle that can be used to stop [`wait`][wait] from another thread, e.g. a Ctrl-C handler.
+    ///
+    /// [wait]: #method.wait
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.sender.clone())
+    }
+
+    /// Wait for something to change, then keep waiting until nothing has changed for [`DEBOUNCE`][debounce], and
+    /// return every path that changed in that time. Returns `None` once the watcher has been stopped.
+    ///
+    /// [debounce]: ./constant.DEBOUNCE.html
+    pub fn wait(&self) -> Option<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+
+        while changed.is_empty() {
+            match self.receiver.recv() {
+                Ok(Message::Changed(paths)) => self.add_changes(&mut changed, paths),
+                Ok(Message::Stop) | Err(_) => return None,
+            }
+        }
+
+        loop {
+            match self.receiver.recv_timeout(DEBOUNCE) {
+                Ok(Message::Changed(paths)) => self.add_changes(&mut changed, paths),
+                Ok(Message::Stop) => return None,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+
+        Some(changed)
+    }
+
+    /// Add each of `paths` that isn't ignored and isn't already in `changed` to `changed`.
+    fn add_changes(&self, changed: &mut Vec<PathBuf>, paths: Vec<PathBuf>) {
+        for path in paths {
+            let ignored = self.ignore.iter().any(|ignore| path.starts_with(ignore));
+
+            if !ignored && !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+    }
+}
+
+/// Stops a [`ChangeWatcher`][watcher] from another thread.
+///
+/// [watcher]: ./struct.ChangeWatcher.html
+#[derive(Clone)]
+pub struct StopHandle(Sender<Message>);
+
+impl StopHandle {
+    /// Make the watcher's current or next call to `wait` return `None`.
+    pub fn stop(&self) {
+        let _ = self.0.send(Message::Stop);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// Test that a change to a watched file is reported, that changes in ignored folders aren't, and that stopping
+    /// the watcher makes `wait` return `None`.
+    #[test]
+    fn wait_for_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("ignored")).unwrap();
+
+        let watcher =
+            ChangeWatcher::new(std::slice::from_ref(&root), vec![root.join("ignored")]).unwrap();
+
+        fs::write(root.join("ignored/a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+
+        let changed = watcher.wait().unwrap();
+        assert!(changed.contains(&root.join("b.txt")));
+        assert!(!changed.contains(&root.join("ignored/a.txt")));
+
+        watcher.stop_handle().stop();
+        assert_eq!(watcher.wait(), None);
+    }
+}